@@ -1,8 +1,9 @@
 use std::error::Error;
-use socks_lib;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
+    // RUST_LOG=debug cargo run --example tcp --features log
+    env_logger::init();
 
     let server: socks_lib::Server = socks_lib::Server::new(socks_lib::Config::new("127.0.0.1", 1083));
     server.handle().await?;
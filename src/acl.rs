@@ -0,0 +1,229 @@
+use std::io::{Error, ErrorKind};
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone)]
+pub enum Rule {
+    Cidr {
+        network: IpAddr,
+        prefix_len: u8,
+        action: Action,
+    },
+    Domain {
+        pattern: String,
+        action: Action,
+    },
+}
+
+impl Rule {
+    pub fn cidr(cidr: &str, action: Action) -> Result<Self, Error> {
+        let invalid_cidr = || Error::new(ErrorKind::InvalidInput, format!("invalid cidr {}", cidr));
+        let (network, prefix_len) = match cidr.split_once('/') {
+            Some((network, prefix_len)) => {
+                let network: IpAddr = network.parse().map_err(|_| invalid_cidr())?;
+                let prefix_len: u8 = prefix_len.parse().map_err(|_| invalid_cidr())?;
+                (network, prefix_len)
+            }
+            None => {
+                let network: IpAddr = cidr.parse().map_err(|_| invalid_cidr())?;
+                (network, if network.is_ipv4() { 32 } else { 128 })
+            }
+        };
+        let max_prefix_len = if network.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max_prefix_len {
+            return Err(invalid_cidr());
+        }
+        // IPv4-mapped networks are stored as IPv4, the form addresses are matched in.
+        let (network, prefix_len) = match network {
+            IpAddr::V6(v6) if prefix_len >= 96 && v6.to_ipv4_mapped().is_some() => (network.to_canonical(), prefix_len - 96),
+            _ => (network, prefix_len),
+        };
+        Ok(Rule::Cidr {
+            network,
+            prefix_len,
+            action,
+        })
+    }
+
+    pub fn domain<S: Into<String>>(pattern: S, action: Action) -> Self {
+        Rule::Domain {
+            pattern: pattern.into().to_ascii_lowercase(),
+            action,
+        }
+    }
+
+    fn matches(&self, domain: Option<&str>, ip: IpAddr) -> Option<Action> {
+        match self {
+            Rule::Cidr { network, prefix_len, action } => {
+                if cidr_contains(*network, *prefix_len, ip) {
+                    Some(*action)
+                } else {
+                    None
+                }
+            }
+            Rule::Domain { pattern, action } => match domain {
+                Some(domain) if glob_matches(pattern.as_bytes(), domain.to_ascii_lowercase().as_bytes()) => Some(*action),
+                _ => None,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessControl {
+    rules: Vec<Rule>,
+    default_action: Action,
+}
+
+impl AccessControl {
+    pub fn new(default_action: Action) -> Self {
+        AccessControl {
+            rules: Vec::new(),
+            default_action,
+        }
+    }
+
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// IPv4-mapped IPv6 addresses are matched as the IPv4 address they carry.
+    pub fn evaluate(&self, domain: Option<&str>, ip: IpAddr) -> Action {
+        let ip = ip.to_canonical();
+        self.rules.iter()
+            .find_map(|rule| rule.matches(domain, ip))
+            .unwrap_or(self.default_action)
+    }
+
+    /// Evaluate a name that will not be resolved locally, so only domain rules apply.
+    pub(crate) fn evaluate_domain(&self, domain: &str) -> Action {
+        self.rules.iter()
+            .filter(|rule| matches!(rule, Rule::Domain { .. }))
+            .find_map(|rule| rule.matches(Some(domain), IpAddr::from([0u8, 0u8, 0u8, 0u8])))
+            .unwrap_or(self.default_action)
+    }
+}
+
+fn cidr_contains(network: IpAddr, prefix_len: u8, ip: IpAddr) -> bool {
+    match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+            u32::from(network) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+            u128::from(network) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Match `domain` against a pattern where `*` stands for any run of bytes. On a mismatch only the
+/// last star's run is extended, which is enough since any earlier star could absorb the same
+/// bytes; this keeps the match linear in practice instead of exponential in the number of stars.
+fn glob_matches(pattern: &[u8], domain: &[u8]) -> bool {
+    let (mut p, mut d) = (0, 0);
+    // Where the last star was seen and where the domain stood when its run was last extended.
+    let mut last_star: Option<(usize, usize)> = None;
+    while d < domain.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                last_star = Some((p, d));
+                p += 1;
+            }
+            Some(byte) if *byte == domain[d] => {
+                p += 1;
+                d += 1;
+            }
+            _ => match last_star {
+                Some((star, run_end)) => {
+                    last_star = Some((star, run_end + 1));
+                    p = star + 1;
+                    d = run_end + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|byte| *byte == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let access_control = AccessControl::new(Action::Deny)
+            .rule(Rule::domain("*.example.com", Action::Allow))
+            .rule(Rule::cidr("10.0.0.0/8", Action::Deny).unwrap())
+            .rule(Rule::cidr("10.1.0.0/16", Action::Allow).unwrap());
+        assert_eq!(access_control.evaluate(Some("www.Example.com"), ip("10.1.2.3")), Action::Allow);
+        assert_eq!(access_control.evaluate(None, ip("10.1.2.3")), Action::Deny);
+        assert_eq!(access_control.evaluate(None, ip("192.0.2.1")), Action::Deny);
+        assert_eq!(AccessControl::new(Action::Allow).evaluate(None, ip("192.0.2.1")), Action::Allow);
+    }
+
+    #[test]
+    fn cidr_rules_match_by_prefix() {
+        let access_control = AccessControl::new(Action::Allow)
+            .rule(Rule::cidr("192.168.0.0/16", Action::Deny).unwrap())
+            .rule(Rule::cidr("2001:db8::/32", Action::Deny).unwrap())
+            .rule(Rule::cidr("0.0.0.0/0", Action::Deny).unwrap());
+        assert_eq!(access_control.evaluate(None, ip("192.168.200.1")), Action::Deny);
+        assert_eq!(access_control.evaluate(None, ip("2001:db8::1")), Action::Deny);
+        assert_eq!(access_control.evaluate(None, ip("2001:db9::1")), Action::Allow);
+        assert_eq!(access_control.evaluate(None, ip("8.8.8.8")), Action::Deny);
+        assert!(Rule::cidr("10.0.0.0/33", Action::Deny).is_err());
+        assert!(Rule::cidr("not-an-ip/8", Action::Deny).is_err());
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_ipv4_rules() {
+        let access_control = AccessControl::new(Action::Allow).rule(Rule::cidr("127.0.0.0/8", Action::Deny).unwrap());
+        assert_eq!(access_control.evaluate(None, ip("::ffff:127.0.0.1")), Action::Deny);
+
+        let access_control = AccessControl::new(Action::Allow).rule(Rule::cidr("::ffff:10.0.0.0/104", Action::Deny).unwrap());
+        assert_eq!(access_control.evaluate(None, ip("10.2.3.4")), Action::Deny);
+        assert_eq!(access_control.evaluate(None, ip("::ffff:10.2.3.4")), Action::Deny);
+        assert_eq!(access_control.evaluate(None, ip("11.0.0.1")), Action::Allow);
+    }
+
+    #[test]
+    fn unresolved_domains_only_see_domain_rules() {
+        let access_control = AccessControl::new(Action::Allow)
+            .rule(Rule::cidr("0.0.0.0/0", Action::Deny).unwrap())
+            .rule(Rule::domain("blocked.test", Action::Deny));
+        assert_eq!(access_control.evaluate_domain("allowed.test"), Action::Allow);
+        assert_eq!(access_control.evaluate_domain("blocked.test"), Action::Deny);
+    }
+
+    #[test]
+    fn globs_match_any_run_of_bytes() {
+        assert!(glob_matches(b"*", b""));
+        assert!(glob_matches(b"*.example.com", b"www.example.com"));
+        assert!(!glob_matches(b"*.example.com", b"example.com"));
+        assert!(glob_matches(b"a*b*c", b"aXbYbZc"));
+        assert!(!glob_matches(b"a*b*c", b"aXbYbZ"));
+        assert!(glob_matches(b"**a", b"ba"));
+        assert!(!glob_matches(b"ab", b"a"));
+    }
+
+    #[test]
+    fn adversarial_globs_finish_quickly() {
+        let domain = "a".repeat(250);
+        let started = std::time::Instant::now();
+        assert!(!glob_matches(b"*a*a*a*a*a*a*a*a*b", domain.as_bytes()));
+        assert!(started.elapsed() < std::time::Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,45 @@
+use crate::BoxFuture;
+
+pub trait Authenticator: Send + Sync {
+    fn authenticate<'a>(&'a self, username: &'a [u8], password: &'a [u8]) -> BoxFuture<'a, bool>;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::Config;
+
+    /// Accepts one login and records every attempt it is asked about.
+    #[derive(Default)]
+    struct Recording {
+        attempts: Mutex<Vec<(Vec<u8>, Vec<u8>)>>,
+    }
+
+    impl Authenticator for Recording {
+        fn authenticate<'a>(&'a self, username: &'a [u8], password: &'a [u8]) -> BoxFuture<'a, bool> {
+            self.attempts.lock().unwrap().push((username.to_vec(), password.to_vec()));
+            Box::pin(async move { username == b"dynamic" && password == b"secret" })
+        }
+    }
+
+    #[tokio::test]
+    async fn authenticator_backs_up_static_credentials() {
+        let authenticator = Arc::new(Recording::default());
+        let config = Config::builder().auth("static", "secret").authenticator(authenticator.clone()).build();
+        assert!(config.authenticate(b"static", b"secret").await);
+        assert!(authenticator.attempts.lock().unwrap().is_empty());
+        assert!(config.authenticate(b"dynamic", b"secret").await);
+        assert!(!config.authenticate(b"static", b"wrong").await);
+        assert_eq!(authenticator.attempts.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn authenticator_sees_non_utf8_usernames() {
+        let authenticator = Arc::new(Recording::default());
+        let config = Config::builder().authenticator(authenticator.clone()).build();
+        assert!(!config.authenticate(&[0xff, 0xfe], b"secret").await);
+        assert_eq!(*authenticator.attempts.lock().unwrap(), [(vec![0xff, 0xfe], b"secret".to_vec())]);
+    }
+}
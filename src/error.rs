@@ -0,0 +1,106 @@
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+/// Protocol failures, carried inside the `io::Error`s this crate returns so callers can tell them
+/// apart; see [`SocksError::from_io`].
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use socks_lib::SocksError;
+///
+/// let mut frame: &[u8] = &[4, 1, 0];
+/// let err = socks_lib::parse_greeting(&mut frame).await.unwrap_err();
+/// assert!(matches!(SocksError::from_io(&err), Some(SocksError::UnsupportedVersion(4))));
+/// # }
+/// ```
+#[derive(Debug)]
+pub enum SocksError {
+    UnsupportedVersion(u8),
+    NoAcceptableMethods(Vec<u8>),
+    AuthFailed,
+    /// Dialing the target, or the parent proxy in front of it, failed.
+    Upstream(Error),
+    ProtocolViolation(String),
+}
+
+impl SocksError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            SocksError::UnsupportedVersion(_) | SocksError::NoAcceptableMethods(_) => ErrorKind::InvalidInput,
+            SocksError::AuthFailed => ErrorKind::PermissionDenied,
+            SocksError::Upstream(err) => err.kind(),
+            SocksError::ProtocolViolation(_) => ErrorKind::InvalidData,
+        }
+    }
+
+    /// The `SocksError` an `io::Error` returned by this crate was built from, if any.
+    pub fn from_io(err: &Error) -> Option<&SocksError> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for SocksError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SocksError::UnsupportedVersion(ver) => write!(f, "invalid socks version {}", ver),
+            SocksError::NoAcceptableMethods(methods) => write!(f, "no acceptable methods in {:?}", methods),
+            SocksError::AuthFailed => write!(f, "invalid username or password"),
+            SocksError::Upstream(err) => write!(f, "{}", err),
+            SocksError::ProtocolViolation(violation) => write!(f, "{}", violation),
+        }
+    }
+}
+
+impl std::error::Error for SocksError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SocksError::Upstream(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<SocksError> for Error {
+    fn from(err: SocksError) -> Self {
+        Error::new(err.kind(), err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kinds_follow_the_variant() {
+        assert_eq!(SocksError::UnsupportedVersion(4).kind(), ErrorKind::InvalidInput);
+        assert_eq!(SocksError::NoAcceptableMethods(vec![1]).kind(), ErrorKind::InvalidInput);
+        assert_eq!(SocksError::AuthFailed.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(SocksError::Upstream(Error::from(ErrorKind::ConnectionRefused)).kind(), ErrorKind::ConnectionRefused);
+        assert_eq!(SocksError::ProtocolViolation("bad".to_string()).kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn survives_the_round_trip_through_io_error() {
+        let err = Error::from(SocksError::UnsupportedVersion(4));
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "invalid socks version 4");
+        assert!(matches!(SocksError::from_io(&err), Some(SocksError::UnsupportedVersion(4))));
+    }
+
+    #[test]
+    fn other_io_errors_are_not_socks_errors() {
+        assert!(SocksError::from_io(&Error::from(ErrorKind::BrokenPipe)).is_none());
+        assert!(SocksError::from_io(&Error::other("plain message")).is_none());
+    }
+
+    #[test]
+    fn upstream_errors_keep_their_source() {
+        use std::error::Error as _;
+
+        let err = SocksError::Upstream(Error::new(ErrorKind::TimedOut, "connect to example.com:80 timed out"));
+        assert_eq!(err.to_string(), "connect to example.com:80 timed out");
+        assert_eq!(err.source().unwrap().to_string(), "connect to example.com:80 timed out");
+        assert!(SocksError::AuthFailed.source().is_none());
+    }
+}
@@ -0,0 +1,106 @@
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::Address;
+
+pub trait EventHandler: Send + Sync {
+    fn on_connect(&self, _client: SocketAddr, _target: &Address) {}
+
+    /// Called after a direct CONNECT with the address that was actually dialed for `target`.
+    fn on_resolve(&self, _client: SocketAddr, _target: &Address, _remote: SocketAddr) {}
+
+    fn on_close(&self, _client: SocketAddr, _bytes_up: u64, _bytes_down: u64) {}
+
+    fn on_error(&self, _client: SocketAddr, _err: &Error) {}
+
+    /// Called once a relay has finished, right after `on_close`.
+    fn on_summary(&self, _summary: &ConnectionSummary) {}
+}
+
+/// Why a relay ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// Both directions reached end of stream (or, for UDP, the control connection closed).
+    Eof,
+    IdleTimeout,
+    MaxLifetime,
+    Error(ErrorKind),
+    /// The relay's cancellation token fired, or the relay was dropped before finishing by
+    /// shutdown or a killed connection.
+    Cancelled,
+}
+
+/// The totals of one finished relay.
+#[derive(Debug, Clone)]
+pub struct ConnectionSummary {
+    pub client: SocketAddr,
+    pub target: Address,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub duration: Duration,
+    pub close_reason: CloseReason,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopEventHandler;
+
+impl EventHandler for NoopEventHandler {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicU64;
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    use super::*;
+    use crate::{Config, RelayClosed, Target};
+
+    #[derive(Default)]
+    struct Recording {
+        closes: Mutex<Vec<(u64, u64)>>,
+        summaries: Mutex<Vec<ConnectionSummary>>,
+    }
+
+    impl EventHandler for Recording {
+        fn on_close(&self, _client: SocketAddr, bytes_up: u64, bytes_down: u64) {
+            self.closes.lock().unwrap().push((bytes_up, bytes_down));
+        }
+
+        fn on_summary(&self, summary: &ConnectionSummary) {
+            self.summaries.lock().unwrap().push(summary.clone());
+        }
+    }
+
+    fn close_relay(events: &Arc<Recording>, close_reason: Option<CloseReason>) -> ConnectionSummary {
+        let config = Config::builder().event_handler(events.clone()).build();
+        let target = Address::new(Target::Domain("example.com".to_string()), 80);
+        let (bytes_up, bytes_down) = (AtomicU64::new(3), AtomicU64::new(7));
+        drop(RelayClosed {
+            config: &config,
+            client_addr: SocketAddr::from(([127, 0, 0, 1], 1080)),
+            dst_addr: &target,
+            started: Instant::now(),
+            bytes_up: &bytes_up,
+            bytes_down: &bytes_down,
+            close_reason,
+        });
+        events.summaries.lock().unwrap().pop().unwrap()
+    }
+
+    #[test]
+    fn closed_relays_report_close_then_summary() {
+        let events = Arc::new(Recording::default());
+        let summary = close_relay(&events, Some(CloseReason::IdleTimeout));
+        assert_eq!(*events.closes.lock().unwrap(), [(3, 7)]);
+        assert_eq!((summary.bytes_up, summary.bytes_down), (3, 7));
+        assert_eq!(summary.target.port(), 80);
+        assert_eq!(summary.close_reason, CloseReason::IdleTimeout);
+    }
+
+    #[test]
+    fn relays_dropped_without_a_reason_were_cancelled() {
+        let events = Arc::new(Recording::default());
+        assert_eq!(close_relay(&events, None).close_reason, CloseReason::Cancelled);
+    }
+}
@@ -0,0 +1,148 @@
+use std::io::Error;
+
+use crate::SocksError;
+
+/// SOCKS5 lets domain names run 1..=255 bytes; this narrows them to RFC 1035's 253, plus a
+/// trailing root label, past which no name resolves. Longer ones are refused as invalid.
+const MAX_HOSTNAME_LEN: usize = 253;
+const MAX_LABEL_LEN: usize = 63;
+
+/// Check `domain` against hostname syntax, converting internationalized labels to their
+/// punycode `xn--` form. Underscores are accepted since they are common in service names.
+pub(crate) fn normalize(domain: &str) -> Result<String, Error> {
+    let invalid_hostname = |reason: &str| SocksError::ProtocolViolation(format!("invalid hostname {:?}, {}", domain, reason));
+    let (name, root) = match domain.strip_suffix('.') {
+        Some(name) => (name, "."),
+        None => (domain, ""),
+    };
+    let mut labels = Vec::new();
+    for label in name.split('.') {
+        let label = if label.is_ascii() {
+            label.to_string()
+        } else {
+            let label = label.to_lowercase();
+            if label.chars().any(|ch| ch.is_control() || ch.is_whitespace()) {
+                return Err(invalid_hostname("illegal character").into());
+            }
+            format!("xn--{}", punycode(&label).ok_or_else(|| invalid_hostname("label cannot be encoded"))?)
+        };
+        if label.is_empty() || label.len() > MAX_LABEL_LEN {
+            return Err(invalid_hostname("label length out of range").into());
+        }
+        if !label.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_') {
+            return Err(invalid_hostname("illegal character").into());
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err(invalid_hostname("label starts or ends with a hyphen").into());
+        }
+        labels.push(label);
+    }
+    let hostname = labels.join(".");
+    if hostname.len() > MAX_HOSTNAME_LEN {
+        return Err(invalid_hostname("too long").into());
+    }
+    Ok(hostname + root)
+}
+
+/// RFC 3492 encoding of a label's code points, without the `xn--` prefix.
+fn punycode(label: &str) -> Option<String> {
+    const BASE: u32 = 36;
+    const T_MIN: u32 = 1;
+    const T_MAX: u32 = 26;
+
+    let code_points: Vec<u32> = label.chars().map(u32::from).collect();
+    let mut output: String = label.chars().filter(char::is_ascii).collect();
+    let basic_len = output.len() as u32;
+    if basic_len != 0 {
+        output.push('-');
+    }
+    let (mut n, mut delta, mut bias, mut handled) = (0x80u32, 0u32, 72u32, basic_len);
+    while (handled as usize) < code_points.len() {
+        let next = code_points.iter().copied().filter(|code_point| *code_point >= n).min()?;
+        delta = delta.checked_add((next - n).checked_mul(handled + 1)?)?;
+        n = next;
+        for &code_point in &code_points {
+            if code_point < n {
+                delta = delta.checked_add(1)?;
+            }
+            if code_point == n {
+                let mut q = delta;
+                let mut k = BASE;
+                loop {
+                    let t = k.saturating_sub(bias).clamp(T_MIN, T_MAX);
+                    if q < t {
+                        break;
+                    }
+                    output.push(punycode_digit(t + (q - t) % (BASE - t)));
+                    q = (q - t) / (BASE - t);
+                    k += BASE;
+                }
+                output.push(punycode_digit(q));
+                bias = punycode_adapt(delta, handled + 1, handled == basic_len);
+                delta = 0;
+                handled += 1;
+            }
+        }
+        delta = delta.checked_add(1)?;
+        n = n.checked_add(1)?;
+    }
+    Some(output)
+}
+
+fn punycode_adapt(delta: u32, num_points: u32, first: bool) -> u32 {
+    let mut delta = if first { delta / 700 } else { delta / 2 };
+    delta += delta / num_points;
+    let mut k = 0;
+    while delta > 35 * 26 / 2 {
+        delta /= 35;
+        k += 36;
+    }
+    k + 36 * delta / (delta + 38)
+}
+
+fn punycode_digit(digit: u32) -> char {
+    match digit {
+        0..=25 => (b'a' + digit as u8) as char,
+        _ => (b'0' + (digit - 26) as u8) as char,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_plain_hostnames() {
+        assert_eq!(normalize("example.com").unwrap(), "example.com");
+        assert_eq!(normalize("Example.COM.").unwrap(), "Example.COM.");
+        assert_eq!(normalize("_sip._tcp.example.com").unwrap(), "_sip._tcp.example.com");
+        assert_eq!(normalize("a-b.c0").unwrap(), "a-b.c0");
+    }
+
+    #[test]
+    fn encodes_internationalized_labels() {
+        assert_eq!(normalize("bücher.example").unwrap(), "xn--bcher-kva.example");
+        assert_eq!(normalize("BÜCHER.example").unwrap(), "xn--bcher-kva.example");
+        assert_eq!(normalize("münchen.de").unwrap(), "xn--mnchen-3ya.de");
+        assert_eq!(normalize("☃.net").unwrap(), "xn--n3h.net");
+        assert_eq!(normalize("例え.テスト").unwrap(), "xn--r8jz45g.xn--zckzah");
+    }
+
+    #[test]
+    fn rejects_malformed_hostnames() {
+        for domain in ["", "a..b", ".a", "a\0b", "a b", "a/b", "-a.b", "a-.b", "ü\u{7}.b"] {
+            let err = normalize(domain).unwrap_err();
+            assert!(matches!(SocksError::from_io(&err), Some(SocksError::ProtocolViolation(_))), "{:?} accepted", domain);
+        }
+    }
+
+    #[test]
+    fn enforces_length_limits() {
+        assert!(normalize(&"a".repeat(MAX_LABEL_LEN)).is_ok());
+        assert!(normalize(&"a".repeat(MAX_LABEL_LEN + 1)).is_err());
+        let longest = [&"a".repeat(63)[..], &"b".repeat(63), &"c".repeat(63), &"d".repeat(61)].join(".");
+        assert_eq!(longest.len(), MAX_HOSTNAME_LEN);
+        assert!(normalize(&longest).is_ok());
+        assert!(normalize(&format!("{}e", longest)).is_err());
+    }
+}
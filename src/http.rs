@@ -0,0 +1,197 @@
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+use crate::listener::Listener;
+#[cfg(feature = "cancellation")]
+use crate::CancellationToken;
+use crate::{bind_server, bound_server, check_domain_len, handle_connect_tcp, handle_connected, handle_relay, parse_domain, with_handshake_timeout, Address, BoundServer, Config, ConnectionInfo, ConnectionToken, Protocol, ServerState, ServerStats, ShutdownStats, Target};
+
+const MAX_HEADER_LEN: usize = 8192;
+
+pub struct HttpConnectServer {
+    config: Arc<Config>,
+    state: Arc<ServerState>,
+}
+
+impl HttpConnectServer {
+    pub fn new(config: Config) -> Self {
+        HttpConnectServer {
+            state: Arc::new(ServerState::new(&config)),
+            config: Arc::new(config),
+        }
+    }
+
+    pub async fn handle(&self) -> Result<(), Error> {
+        self.handle_with_shutdown(std::future::pending(), None).await?;
+        Ok(())
+    }
+
+    pub async fn handle_with_shutdown<F: Future<Output = ()>>(&self, shutdown: F, drain_timeout: Option<Duration>) -> Result<ShutdownStats, Error> {
+        self.bind().await?.run_with_shutdown(shutdown, drain_timeout).await
+    }
+
+    #[cfg(feature = "cancellation")]
+    pub async fn handle_with_token(&self, token: CancellationToken) -> Result<ShutdownStats, Error> {
+        self.bind().await?.run_with_token(token).await
+    }
+
+    pub async fn bind(&self) -> Result<BoundServer, Error> {
+        bind_server(&self.config, &self.state, Protocol::HttpConnect).await
+    }
+
+    pub fn bind_listener(&self, server_socket: TcpListener) -> Result<BoundServer, Error> {
+        bound_server(&self.config, &self.state, Protocol::HttpConnect, vec![Listener::Tcp(server_socket)])
+    }
+
+    #[cfg(unix)]
+    pub fn bind_unix<P: AsRef<Path>>(&self, path: P) -> Result<BoundServer, Error> {
+        bound_server(&self.config, &self.state, Protocol::HttpConnect, vec![Listener::Unix(UnixListener::bind(path)?)])
+    }
+
+    pub fn stats(&self) -> ServerStats {
+        self.state.counters.snapshot()
+    }
+
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.state.counters.connections()
+    }
+
+    pub fn kill(&self, id: u64) -> bool {
+        self.state.counters.kill(id)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn handle_connection<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(config: Arc<Config>, state: Arc<ServerState>, client_addr: SocketAddr, _server_addr: SocketAddr, accepted_at: Instant, token: &ConnectionToken, mut client_reader: R, mut client_writer: W) -> Result<(), Error> {
+    let (header, early_data) = match with_handshake_timeout(&config, accepted_at, token, read_header(&mut client_reader)).await {
+        Ok(read) => read,
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+            debug!(config, "{} disconnected before sending a request", client_addr);
+            return Ok(());
+        }
+        Err(err) if err.kind() == ErrorKind::TimedOut => return Err(err),
+        Err(err) => {
+            write_status(&mut client_writer, "400 Bad Request").await?;
+            return Err(err);
+        }
+    };
+    let dst_addr = match parse_request(&header).and_then(|dst_addr| check_domain_len(&config, &dst_addr).map(|()| dst_addr)) {
+        Ok(dst_addr) => dst_addr,
+        Err(err) => {
+            let status = match err.kind() {
+                ErrorKind::Unsupported => "405 Method Not Allowed",
+                _ => "400 Bad Request",
+            };
+            write_status(&mut client_writer, status).await?;
+            return Err(err);
+        }
+    };
+    if config.auth_required(client_addr.ip()) {
+        write_status(&mut client_writer, "403 Forbidden").await?;
+        return Err(Error::new(ErrorKind::PermissionDenied, "http connect is not allowed when authentication is configured"));
+    }
+
+    let (remote_reader, mut remote_writer) = match handle_connect_tcp(&config, &state, client_addr, &dst_addr).await {
+        Ok(remote) => remote,
+        Err(err) => {
+            warn!(config, "{} connect to {} failed: {}", client_addr, dst_addr, err);
+            write_status(&mut client_writer, status_line(&err)).await?;
+            return Err(err);
+        }
+    };
+    client_writer.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await?;
+    handle_connected(&config, client_addr, &dst_addr, remote_writer.peer_addr()?);
+
+    if !early_data.is_empty() {
+        remote_writer.write_all(&early_data).await?;
+    }
+    handle_relay(&config, &state, client_addr, accepted_at, token, &dst_addr, client_reader, client_writer, remote_reader, remote_writer).await
+}
+
+async fn read_header<R: AsyncRead + Unpin>(client_reader: &mut R) -> Result<(String, Vec<u8>), Error> {
+    let mut buffer = Vec::new();
+    let mut reader_buffer = [0u8; 1024];
+    loop {
+        if let Some(header_end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            let early_data = buffer.split_off(header_end + 4);
+            let header = String::from_utf8(buffer).map_err(|_| Error::new(ErrorKind::InvalidData, "invalid non-utf8 request header"))?;
+            return Ok((header, early_data));
+        }
+        if buffer.len() > MAX_HEADER_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "request header too long"));
+        }
+        let read_len = client_reader.read(&mut reader_buffer).await?;
+        if read_len == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed before request header"));
+        }
+        buffer.extend_from_slice(&reader_buffer[..read_len]);
+    }
+}
+
+fn parse_request(header: &str) -> Result<Address, Error> {
+    let request_line = header.lines().next().unwrap_or_default();
+    let invalid_request = || Error::new(ErrorKind::InvalidData, format!("invalid request line {:?}", request_line));
+    let mut parts = request_line.split(' ');
+    let (method, authority, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(authority), Some(version), None) => (method, authority, version),
+        _ => return Err(invalid_request()),
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(invalid_request());
+    }
+    if method != "CONNECT" {
+        return Err(Error::new(ErrorKind::Unsupported, format!("unsupported method {}", method)));
+    }
+    let (host, port) = authority.rsplit_once(':').ok_or_else(invalid_request)?;
+    let port: u16 = port.parse().map_err(|_| invalid_request())?;
+    let target = if let Some(host) = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')) {
+        Target::Ipv6(host.parse::<Ipv6Addr>().map_err(|_| invalid_request())?)
+    } else if let Ok(ip) = host.parse::<Ipv4Addr>() {
+        Target::Ipv4(ip)
+    } else {
+        Target::Domain(parse_domain(host.as_bytes().to_vec())?)
+    };
+    Ok(Address::new(target, port))
+}
+
+fn status_line(err: &Error) -> &'static str {
+    match err.kind() {
+        ErrorKind::PermissionDenied => "403 Forbidden",
+        ErrorKind::TimedOut => "504 Gateway Timeout",
+        _ => "502 Bad Gateway",
+    }
+}
+
+async fn write_status<W: AsyncWrite + Unpin>(client_writer: &mut W, status: &str) -> Result<(), Error> {
+    client_writer.write_all(format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).as_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_connect_authorities() {
+        assert_eq!(parse_request("CONNECT 127.0.0.1:443 HTTP/1.1\r\n").unwrap(), Address::new(Target::Ipv4(Ipv4Addr::LOCALHOST), 443));
+        assert_eq!(parse_request("CONNECT [::1]:443 HTTP/1.0\r\n").unwrap(), Address::new(Target::Ipv6(Ipv6Addr::LOCALHOST), 443));
+        assert_eq!(parse_request("CONNECT Example.com:80 HTTP/1.1\r\n").unwrap(), Address::new(Target::Domain("Example.com".to_string()), 80));
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        assert_eq!(parse_request("GET / HTTP/1.1\r\n").unwrap_err().kind(), ErrorKind::Unsupported);
+        assert!(parse_request("CONNECT example.com HTTP/1.1\r\n").is_err());
+        assert!(parse_request("CONNECT example.com:80 HTTP/2\r\n").is_err());
+        assert!(parse_request("CONNECT example.com:80\r\n").is_err());
+        assert!(parse_request("CONNECT [::1:80 HTTP/1.1\r\n").is_err());
+    }
+}
@@ -0,0 +1,116 @@
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::listener::{ClientStream, Listener};
+use crate::{accept_any, handle_closed, handle_stream, ActiveConnection, Config, ConnectionToken, Protocol, ServerState};
+
+/// Accepts connections for the caller to handle, in place of the built-in spawn loop.
+pub struct Incoming {
+    pub(crate) config: Arc<Config>,
+    pub(crate) state: Arc<ServerState>,
+    pub(crate) server_sockets: Vec<Listener>,
+    pub(crate) next_listener: usize,
+    pub(crate) protocol: Protocol,
+    #[cfg(feature = "tls")]
+    pub(crate) tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
+}
+
+impl Incoming {
+    /// Wait for the next connection on any listener. Client sockets are configured as in `run`,
+    /// but connection limits are left to the caller.
+    pub async fn accept(&mut self) -> Result<IncomingConnection, Error> {
+        let (client_stream, client_addr) = accept_any(&self.server_sockets, &mut self.next_listener).await?;
+        debug!(self.config, "{} accepted", client_addr);
+        if let Err(err) = client_stream.configure(&self.config) {
+            warn!(self.config, "{} failed to configure socket: {}", client_addr, err);
+        }
+        Ok(IncomingConnection {
+            config: self.config.clone(),
+            state: self.state.clone(),
+            protocol: self.protocol,
+            client_addr,
+            accepted_at: Instant::now(),
+            client_stream,
+            #[cfg(feature = "tls")]
+            tls_acceptor: self.tls_acceptor.clone(),
+        })
+    }
+}
+
+/// An accepted connection before any handshake, readable and writable as a raw stream (TLS is
+/// not yet terminated) or handed back to the server with `serve`.
+pub struct IncomingConnection {
+    config: Arc<Config>,
+    state: Arc<ServerState>,
+    protocol: Protocol,
+    client_addr: SocketAddr,
+    accepted_at: Instant,
+    client_stream: ClientStream,
+    #[cfg(feature = "tls")]
+    tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
+}
+
+impl IncomingConnection {
+    pub fn client_addr(&self) -> SocketAddr {
+        self.client_addr
+    }
+
+    /// Run the handshake and relay as the server would, counting the connection in its stats
+    /// and `max_per_client`.
+    pub async fn serve(self) -> Result<(), Error> {
+        let _active = ActiveConnection::new(self.config.clone(), self.state.clone(), self.client_addr.ip())
+            .ok_or_else(|| Error::new(ErrorKind::ConnectionRefused, format!("{} rejected, per-client connection limit reached", self.client_addr)))?;
+        let result = handle_stream(self.config.clone(), self.state, self.protocol, self.client_addr, self.accepted_at, ConnectionToken::default(), self.client_stream, #[cfg(feature = "tls")] self.tls_acceptor).await;
+        handle_closed(&self.config, self.client_addr, &result);
+        result
+    }
+}
+
+impl AsyncRead for IncomingConnection {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.get_mut().client_stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for IncomingConnection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
+        Pin::new(&mut self.get_mut().client_stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.get_mut().client_stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.get_mut().client_stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::Server;
+
+    #[tokio::test]
+    async fn accepted_sockets_are_configured() {
+        let server = Server::new(Config::builder().local_addr("127.0.0.1").local_port(0).nodelay(true).build()).bind().await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let mut incoming = server.incoming();
+
+        let client = TcpStream::connect(server_addr).await.unwrap();
+        let connection = incoming.accept().await.unwrap();
+        assert_eq!(connection.client_addr(), client.local_addr().unwrap());
+        match &connection.client_stream {
+            ClientStream::Tcp(stream) => assert!(stream.nodelay().unwrap()),
+            #[cfg(unix)]
+            ClientStream::Unix(_) => unreachable!("bound a tcp listener"),
+        }
+    }
+}
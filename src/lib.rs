@@ -1,15 +1,118 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::Poll;
 use std::io::{Error, ErrorKind};
-use tokio;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream, UdpSocket};
+#[cfg(unix)]
+use std::path::Path;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use tokio::net::UnixListener;
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::task::JoinHandle;
+use tokio::runtime::Handle;
+use tokio::sync::Semaphore;
+use tokio::task::{AbortHandle, JoinError, JoinHandle, JoinSet};
+
+use listener::{ClientStream, Listener};
+
+/// Emit to the `log` facade when that feature is on, and to the configured `log_fn` if any.
+macro_rules! log_event {
+    ($config:expr, $level:ident, $($arg:tt)*) => {{
+        #[cfg(feature = "log")]
+        log::log!(log::Level::$level, $($arg)*);
+        if let Some(log_fn) = &$config.log_fn {
+            log_fn($crate::LogLevel::$level, &format!($($arg)*));
+        }
+    }};
+}
+
+macro_rules! debug {
+    ($config:expr, $($arg:tt)*) => { log_event!($config, Debug, $($arg)*) };
+}
+
+macro_rules! info {
+    ($config:expr, $($arg:tt)*) => { log_event!($config, Info, $($arg)*) };
+}
+
+macro_rules! warn {
+    ($config:expr, $($arg:tt)*) => { log_event!($config, Warn, $($arg)*) };
+}
+
+mod acl;
+mod auth;
+mod error;
+mod event;
+mod hostname;
+mod http;
+mod incoming;
+mod listener;
+mod metrics;
+mod parse;
+mod pool;
+mod proxy_protocol;
+mod rate;
+mod reply;
+mod resolver;
+mod rewrite;
+#[cfg(feature = "testutil")]
+pub mod testutil;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "udp")]
+mod udp;
+mod upstream;
+
+pub use acl::{AccessControl, Action, Rule};
+pub use auth::Authenticator;
+pub use error::SocksError;
+pub use event::{CloseReason, ConnectionSummary, EventHandler, NoopEventHandler};
+pub use http::HttpConnectServer;
+pub use incoming::{Incoming, IncomingConnection};
+pub use metrics::{Metrics, NoopMetrics};
+pub use parse::{parse_address, parse_greeting, parse_reply, parse_request, Command, Request};
+pub use reply::Reply;
+pub use resolver::{Resolver, SystemResolver};
+pub use rewrite::Rewriter;
+#[cfg(feature = "tls")]
+pub use tokio_rustls::rustls;
+#[cfg(feature = "cancellation")]
+pub use tokio_util::sync::CancellationToken;
+
+use pool::ConnectionPool;
+use rate::RateLimiter;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Callback receiving each log line, see [`ConfigBuilder::log_fn`].
+pub type LogFn = dyn Fn(LogLevel, &str) + Send + Sync;
 
 type PortType = u16;
 
 type Byte = u8;
 
 const VERSION: Byte = 5;
+const VERSION_4: Byte = 4;
+
+const REP_V4_VERSION: Byte = 0;
+const REP_V4_GRANTED: ReplyType = 90;
+const REP_V4_REJECTED: ReplyType = 91;
+
+type MethodType = Byte;
+const METHOD_NO_AUTH: MethodType = 0;
+const METHOD_GSSAPI: MethodType = 1;
+const METHOD_USERNAME_PASSWORD: MethodType = 2;
+const METHOD_NO_ACCEPTABLE: MethodType = 0xFF;
+
+const AUTH_VERSION: Byte = 1;
+const AUTH_SUCCEEDED: Byte = 0;
+const AUTH_FAILED: Byte = 1;
 
 type AddressType = Byte;
 const ATYP_IPV4: AddressType = 1;
@@ -18,20 +121,283 @@ const ATYP_IPV6: AddressType = 4;
 
 type CmdType = Byte;
 const CMD_CONNECT: CmdType = 1;
+const CMD_BIND: CmdType = 2;
 const CMD_ASSOCIATE: CmdType = 3;
 
+type ReplyType = Byte;
+const REP_SUCCEEDED: ReplyType = 0;
+const REP_GENERAL_FAILURE: ReplyType = 1;
+const REP_NOT_ALLOWED: ReplyType = 2;
+const REP_NETWORK_UNREACHABLE: ReplyType = 3;
+const REP_HOST_UNREACHABLE: ReplyType = 4;
+const REP_CONNECTION_REFUSED: ReplyType = 5;
+const REP_COMMAND_NOT_SUPPORTED: ReplyType = 7;
+const REP_ADDRESS_TYPE_NOT_SUPPORTED: ReplyType = 8;
+
 const READER_BUFFER_LEN: usize = 256;
+const HANDSHAKE_BUFFER_LEN: usize = 512;
+const DEFAULT_RELAY_BUFFER_LEN: usize = 8192;
+const DEFAULT_LISTEN_BACKLOG: u32 = 1024;
+
+const DEFAULT_LOCAL_ADDR: &str = "127.0.0.1";
+const DEFAULT_LOCAL_PORT: PortType = 1080;
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+const DEFAULT_BIND_TIMEOUT: Duration = Duration::from_secs(60);
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_millis(10);
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(5);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+const UNSPECIFIED_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
 
-#[derive(Debug)]
 pub struct Config {
     local_addr: String,
     local_port: PortType,
+    credentials: HashMap<String, String>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    require_auth: bool,
+    no_auth_sources: Option<AccessControl>,
+    method_preference: Vec<MethodType>,
+    connect_timeout: Option<Duration>,
+    connect_retries: u32,
+    retry_backoff: Duration,
+    connection_pool: Option<(usize, Duration)>,
+    idle_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
+    io_timeout: Option<Duration>,
+    bind_timeout: Option<Duration>,
+    handshake_timeout: Option<Duration>,
+    max_connections: Option<usize>,
+    max_per_client: Option<usize>,
+    limit_policy: LimitPolicy,
+    resolver: Arc<dyn Resolver>,
+    dns_family: IpFamily,
+    max_domain_len: u8,
+    access_control: Option<AccessControl>,
+    block_private_addresses: bool,
+    rewriter: Option<Arc<dyn Rewriter>>,
+    event_handler: Arc<dyn EventHandler>,
+    metrics: Arc<dyn Metrics>,
+    log_fn: Option<Arc<LogFn>>,
+    relay_buffer_size: usize,
+    coalesce_window: Option<Duration>,
+    listen_addrs: Vec<SocketAddr>,
+    listen_backlog: u32,
+    tcp_keepalive: Option<Duration>,
+    nodelay: bool,
+    outbound_bind: Option<IpAddr>,
+    udp_bind_addr: Option<IpAddr>,
+    ipv6_scope_id: Option<u32>,
+    ipv6_only: Option<bool>,
+    rate_limit: Option<u64>,
+    rate_limit_mode: RateLimitMode,
+    global_rate_limit: Option<u64>,
+    upstream_proxy: Option<SocketAddr>,
+    upstream_credentials: Option<(String, String)>,
+    proxy_protocol: Option<ProxyProtocol>,
+    accept_proxy_protocol: bool,
+    proxy_protocol_trusted: Option<AccessControl>,
+    #[cfg(feature = "tls")]
+    tls_identity: Option<(Vec<rustls::pki_types::CertificateDer<'static>>, rustls::pki_types::PrivateKeyDer<'static>)>,
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("local_addr", &self.local_addr)
+            .field("local_port", &self.local_port)
+            .field("listen_addrs", &self.listen_addrs)
+            .field("listen_backlog", &self.listen_backlog)
+            .field("usernames", &self.credentials.keys().collect::<Vec<_>>())
+            .field("require_auth", &self.require_auth)
+            .field("no_auth_sources", &self.no_auth_sources)
+            .field("method_preference", &self.method_preference)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("connect_retries", &self.connect_retries)
+            .field("retry_backoff", &self.retry_backoff)
+            .field("connection_pool", &self.connection_pool)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("max_lifetime", &self.max_lifetime)
+            .field("io_timeout", &self.io_timeout)
+            .field("bind_timeout", &self.bind_timeout)
+            .field("handshake_timeout", &self.handshake_timeout)
+            .field("max_connections", &self.max_connections)
+            .field("max_per_client", &self.max_per_client)
+            .field("limit_policy", &self.limit_policy)
+            .field("dns_family", &self.dns_family)
+            .field("max_domain_len", &self.max_domain_len)
+            .field("access_control", &self.access_control)
+            .field("block_private_addresses", &self.block_private_addresses)
+            .field("relay_buffer_size", &self.relay_buffer_size)
+            .field("coalesce_window", &self.coalesce_window)
+            .field("tcp_keepalive", &self.tcp_keepalive)
+            .field("nodelay", &self.nodelay)
+            .field("outbound_bind", &self.outbound_bind)
+            .field("udp_bind_addr", &self.udp_bind_addr)
+            .field("ipv6_scope_id", &self.ipv6_scope_id)
+            .field("ipv6_only", &self.ipv6_only)
+            .field("rate_limit", &self.rate_limit)
+            .field("rate_limit_mode", &self.rate_limit_mode)
+            .field("global_rate_limit", &self.global_rate_limit)
+            .field("upstream_proxy", &self.upstream_proxy)
+            .field("upstream_username", &self.upstream_credentials.as_ref().map(|(username, _)| username))
+            .field("proxy_protocol", &self.proxy_protocol)
+            .field("accept_proxy_protocol", &self.accept_proxy_protocol)
+            .field("proxy_protocol_trusted", &self.proxy_protocol_trusted)
+            .finish_non_exhaustive()
+    }
+}
+
+/// What the connections of one server share at runtime, built from its `Config` when the server
+/// is created so the configuration itself holds no state.
+#[derive(Debug)]
+pub(crate) struct ServerState {
+    counters: Counters,
+    global_rate_limiter: Option<RateLimiter>,
+    connection_pool: Option<ConnectionPool>,
+}
+
+impl ServerState {
+    pub(crate) fn new(config: &Config) -> Self {
+        ServerState {
+            counters: Counters::default(),
+            global_rate_limiter: config.global_rate_limit.map(RateLimiter::new),
+            connection_pool: config.connection_pool.map(|(max_size, idle_timeout)| ConnectionPool::new(max_size, idle_timeout)),
+        }
+    }
 }
 
-struct Address {
-    addr: String,
+#[derive(Debug, Default)]
+struct Counters {
+    active_connections: AtomicUsize,
+    total_connections: AtomicU64,
+    total_bytes: AtomicU64,
+    client_connections: Mutex<HashMap<IpAddr, usize>>,
+    connections: Mutex<HashMap<u64, (ConnectionInfo, AbortHandle)>>,
+}
+
+impl Counters {
+    fn snapshot(&self) -> ServerStats {
+        ServerStats {
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            total_connections: self.total_connections.load(Ordering::Relaxed),
+            total_bytes: self.total_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    fn connections(&self) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<ConnectionInfo> = self.connections.lock().unwrap().values().map(|(info, _)| *info).collect();
+        connections.sort_by_key(|info| info.id);
+        connections
+    }
+
+    fn kill(&self, id: u64) -> bool {
+        match self.connections.lock().unwrap().get(&id) {
+            Some((_, abort_handle)) => {
+                abort_handle.abort();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitPolicy {
+    Reject,
+    Wait,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitMode {
+    Independent,
+    Shared,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Warn,
+    Info,
+    Debug,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpFamily {
+    V4Only,
+    V6Only,
+    Both,
+}
+
+impl IpFamily {
+    fn allows(self, ip: IpAddr) -> bool {
+        match self {
+            IpFamily::V4Only => ip.is_ipv4(),
+            IpFamily::V6Only => ip.is_ipv6(),
+            IpFamily::Both => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyProtocol {
+    V1,
+    V2,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Target {
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+    Domain(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Address {
+    target: Target,
     port: PortType,
-    atyp: AddressType,
+}
+
+impl Address {
+    pub fn new(target: Target, port: u16) -> Self {
+        Address {
+            target,
+            port,
+        }
+    }
+
+    pub fn target(&self) -> &Target {
+        &self.target
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn host(&self) -> String {
+        match &self.target {
+            Target::Ipv4(ip) => ip.to_string(),
+            Target::Ipv6(ip) => format!("[{}]", ip),
+            Target::Domain(domain) => domain.clone(),
+        }
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Self {
+        let target = match addr.ip() {
+            IpAddr::V4(ip) => Target::Ipv4(ip),
+            IpAddr::V6(ip) => Target::Ipv6(ip),
+        };
+        Address::new(target, addr.port())
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.host(), self.port)
+    }
 }
 
 impl Config {
@@ -39,153 +405,2042 @@ impl Config {
         Config {
             local_addr: local_addr.into(),
             local_port,
+            credentials: HashMap::new(),
+            authenticator: None,
+            require_auth: false,
+            no_auth_sources: None,
+            method_preference: Vec::new(),
+            connect_timeout: Some(DEFAULT_CONNECT_TIMEOUT),
+            connect_retries: 0,
+            retry_backoff: DEFAULT_RETRY_BACKOFF,
+            connection_pool: None,
+            idle_timeout: None,
+            max_lifetime: None,
+            io_timeout: None,
+            bind_timeout: Some(DEFAULT_BIND_TIMEOUT),
+            handshake_timeout: None,
+            max_connections: None,
+            max_per_client: None,
+            limit_policy: LimitPolicy::Wait,
+            resolver: Arc::new(SystemResolver),
+            dns_family: IpFamily::Both,
+            max_domain_len: u8::MAX,
+            access_control: None,
+            block_private_addresses: false,
+            rewriter: None,
+            event_handler: Arc::new(NoopEventHandler),
+            metrics: Arc::new(NoopMetrics),
+            log_fn: None,
+            relay_buffer_size: DEFAULT_RELAY_BUFFER_LEN,
+            coalesce_window: None,
+            listen_addrs: Vec::new(),
+            listen_backlog: DEFAULT_LISTEN_BACKLOG,
+            tcp_keepalive: None,
+            nodelay: false,
+            outbound_bind: None,
+            udp_bind_addr: None,
+            ipv6_scope_id: None,
+            ipv6_only: None,
+            rate_limit: None,
+            rate_limit_mode: RateLimitMode::Independent,
+            global_rate_limit: None,
+            upstream_proxy: None,
+            upstream_credentials: None,
+            proxy_protocol: None,
+            accept_proxy_protocol: false,
+            proxy_protocol_trusted: None,
+            #[cfg(feature = "tls")]
+            tls_identity: None,
+        }
+    }
+
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// let config = socks_lib::Config::builder()
+    ///     .local_addr("127.0.0.1")
+    ///     .local_port(1080)
+    ///     .connect_timeout(Some(Duration::from_secs(5)))
+    ///     .idle_timeout(Some(Duration::from_secs(300)))
+    ///     .max_connections(Some(1024))
+    ///     .auth("user", "password")
+    ///     .build();
+    /// let server = socks_lib::Server::new(config);
+    /// ```
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder {
+            config: Config::new(DEFAULT_LOCAL_ADDR, DEFAULT_LOCAL_PORT),
+        }
+    }
+
+    fn methods(&self, client_ip: IpAddr) -> Vec<MethodType> {
+        let methods = match (self.auth_configured(), self.no_auth_allowed(client_ip)) {
+            (false, true) => vec![METHOD_NO_AUTH],
+            (false, false) => vec![],
+            (true, true) => vec![METHOD_USERNAME_PASSWORD, METHOD_NO_AUTH],
+            (true, false) => vec![METHOD_USERNAME_PASSWORD],
+        };
+        if self.method_preference.is_empty() {
+            return methods;
+        }
+        self.method_preference.iter().copied().filter(|method| methods.contains(method)).collect()
+    }
+
+    fn auth_configured(&self) -> bool {
+        !self.credentials.is_empty() || self.authenticator.is_some()
+    }
+
+    fn no_auth_allowed(&self, client_ip: IpAddr) -> bool {
+        match &self.no_auth_sources {
+            Some(no_auth_sources) => no_auth_sources.evaluate(None, client_ip) == Action::Allow,
+            None => !self.require_auth,
+        }
+    }
+
+    /// Whether a PROXY header from `peer_ip` is read and believed.
+    fn trusts_proxy_header(&self, peer_ip: IpAddr) -> bool {
+        self.accept_proxy_protocol && self.proxy_protocol_trusted.as_ref().is_some_and(|trusted| trusted.evaluate(None, peer_ip) == Action::Allow)
+    }
+
+    /// Whether a client at `client_ip` must authenticate on protocols without no-auth fallback.
+    fn auth_required(&self, client_ip: IpAddr) -> bool {
+        match &self.no_auth_sources {
+            Some(_) => !self.no_auth_allowed(client_ip),
+            None => self.require_auth || self.auth_configured(),
+        }
+    }
+
+    async fn authenticate(&self, username: &[u8], password: &[u8]) -> bool {
+        let matches_credentials = match std::str::from_utf8(username) {
+            Ok(username) => self.credentials.get(username).map(|p| p.as_bytes()) == Some(password),
+            Err(_) => false,
+        };
+        match &self.authenticator {
+            Some(authenticator) if !matches_credentials => authenticator.authenticate(username, password).await,
+            _ => matches_credentials,
         }
     }
 }
 
-pub struct Server {
+#[derive(Debug)]
+pub struct ConfigBuilder {
     config: Config,
 }
 
+impl ConfigBuilder {
+    pub fn local_addr<S: Into<String>>(mut self, local_addr: S) -> Self {
+        self.config.local_addr = local_addr.into();
+        self
+    }
+
+    pub fn local_port(mut self, local_port: u16) -> Self {
+        self.config.local_port = local_port;
+        self
+    }
+
+    /// Listen on each of these addresses instead of `local_addr`/`local_port`.
+    pub fn listen_addrs<I: IntoIterator<Item = SocketAddr>>(mut self, listen_addrs: I) -> Self {
+        self.config.listen_addrs = listen_addrs.into_iter().collect();
+        self
+    }
+
+    /// Pending-connection queue length for the listeners, 1024 by default. The OS may cap it:
+    /// Linux silently clamps it to `net.core.somaxconn` and macOS to `kern.ipc.somaxconn`.
+    /// Listeners passed to `bind_listener` keep their own backlog.
+    pub fn listen_backlog(mut self, listen_backlog: u32) -> Self {
+        self.config.listen_backlog = listen_backlog;
+        self
+    }
+
+    pub fn auth<S: Into<String>>(mut self, username: S, password: S) -> Self {
+        self.config.credentials.insert(username.into(), password.into());
+        self
+    }
+
+    /// Check username/password logins that don't match an `auth` entry against this backend.
+    pub fn authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.config.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Reject clients that don't authenticate, even if no-auth could be negotiated.
+    pub fn require_auth(mut self, require_auth: bool) -> Self {
+        self.config.require_auth = require_auth;
+        self
+    }
+
+    /// Decide per client address whether no-auth may be negotiated: sources the ruleset allows
+    /// may skip authentication, all others must authenticate. Takes precedence over `require_auth`.
+    pub fn no_auth_sources(mut self, no_auth_sources: AccessControl) -> Self {
+        self.config.no_auth_sources = Some(no_auth_sources);
+        self
+    }
+
+    /// Order in which enabled methods are matched against the client's offer; enabled methods
+    /// missing from a non-empty preference are never selected.
+    pub fn method_preference<I: IntoIterator<Item = u8>>(mut self, method_preference: I) -> Self {
+        self.config.method_preference = method_preference.into_iter().collect();
+        self
+    }
+
+    pub fn connect_timeout(mut self, connect_timeout: Option<Duration>) -> Self {
+        self.config.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// Retry a CONNECT this many more times after a refused, reset, unreachable or timed out attempt.
+    pub fn connect_retries(mut self, connect_retries: u32) -> Self {
+        self.config.connect_retries = connect_retries;
+        self
+    }
+
+    /// Pause between connect retries.
+    pub fn retry_backoff(mut self, retry_backoff: Duration) -> Self {
+        self.config.retry_backoff = retry_backoff;
+        self
+    }
+
+    /// Keep up to `max_size` spare connections per target, handed to the next CONNECT to it if one
+    /// arrives within `idle_timeout`. A target gets its first spare once two CONNECTs to it came
+    /// within `idle_timeout`, and a replacement whenever a spare is taken. Spares are never shared
+    /// between tunnels, but targets see connections opened before any client asked for them, and
+    /// may close idle ones first; only opt in for targets that tolerate that.
+    pub fn connection_pool(mut self, max_size: usize, idle_timeout: Duration) -> Self {
+        self.config.connection_pool = Some((max_size, idle_timeout));
+        self
+    }
+
+    pub fn idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.config.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Close a connection this long after it was accepted, however active its relay is. Unlike other
+    /// closes, this one does not wait for the peers to drain, so data still in flight may be reset.
+    pub fn max_lifetime(mut self, max_lifetime: Option<Duration>) -> Self {
+        self.config.max_lifetime = max_lifetime;
+        self
+    }
+
+    /// Close a relay when any single read or write in either direction blocks this long.
+    pub fn io_timeout(mut self, io_timeout: Option<Duration>) -> Self {
+        self.config.io_timeout = io_timeout;
+        self
+    }
+
+    pub fn bind_timeout(mut self, bind_timeout: Option<Duration>) -> Self {
+        self.config.bind_timeout = bind_timeout;
+        self
+    }
+
+    /// Bound the time from accept until the request has been read. One deadline covers every phase:
+    /// the PROXY header, the TLS handshake and the SOCKS or HTTP negotiation.
+    pub fn handshake_timeout(mut self, handshake_timeout: Option<Duration>) -> Self {
+        self.config.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    pub fn max_connections(mut self, max_connections: Option<usize>) -> Self {
+        self.config.max_connections = max_connections;
+        self
+    }
+
+    /// Reject connections from a client IP that already has this many open.
+    pub fn max_per_client(mut self, max_per_client: Option<usize>) -> Self {
+        self.config.max_per_client = max_per_client;
+        self
+    }
+
+    pub fn limit_policy(mut self, limit_policy: LimitPolicy) -> Self {
+        self.config.limit_policy = limit_policy;
+        self
+    }
+
+    pub fn resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.config.resolver = resolver;
+        self
+    }
+
+    /// Address family to keep from resolved domains, both by default. Domains with no address
+    /// of the family are unreachable; IP literal targets are not affected, and neither are
+    /// domains forwarded to an `upstream_proxy`, which resolves them itself.
+    pub fn dns_family(mut self, dns_family: IpFamily) -> Self {
+        self.config.dns_family = dns_family;
+        self
+    }
+
+    /// Longest domain target accepted, in bytes, 255 by default; longer names are refused as a
+    /// protocol error before any resolution.
+    pub fn max_domain_len(mut self, max_domain_len: u8) -> Self {
+        self.config.max_domain_len = max_domain_len;
+        self
+    }
+
+    pub fn access_control(mut self, access_control: AccessControl) -> Self {
+        self.config.access_control = Some(access_control);
+        self
+    }
+
+    /// Refuse targets in private, loopback, link-local and other special-use ranges, checked
+    /// after resolution so domains pointing at internal addresses are refused too. Domains
+    /// forwarded to an `upstream_proxy` are checked against what they resolve to locally, which
+    /// the parent's own resolution may not match.
+    pub fn block_private_addresses(mut self, block_private_addresses: bool) -> Self {
+        self.config.block_private_addresses = block_private_addresses;
+        self
+    }
+
+    pub fn rewriter(mut self, rewriter: Arc<dyn Rewriter>) -> Self {
+        self.config.rewriter = Some(rewriter);
+        self
+    }
+
+    pub fn event_handler(mut self, event_handler: Arc<dyn EventHandler>) -> Self {
+        self.config.event_handler = event_handler;
+        self
+    }
+
+    /// Sink for connection and byte counters, a no-op by default.
+    pub fn metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.config.metrics = metrics;
+        self
+    }
+
+    /// Receive every log line the server emits, with or without the `log` feature.
+    pub fn log_fn(mut self, log_fn: Arc<LogFn>) -> Self {
+        self.config.log_fn = Some(log_fn);
+        self
+    }
+
+    /// Size of the per-direction buffer used while relaying, 8 KiB by default.
+    /// Larger buffers mean fewer syscalls on bulk transfers at the cost of
+    /// two allocations of this size for every open relay.
+    pub fn relay_buffer_size(mut self, relay_buffer_size: usize) -> Self {
+        self.config.relay_buffer_size = relay_buffer_size.max(1);
+        self
+    }
+
+    /// Keep reading for up to this long after data arrives, so a burst of small chunks is
+    /// relayed in one write of up to `relay_buffer_size` bytes. Off by default.
+    pub fn coalesce_window(mut self, coalesce_window: Option<Duration>) -> Self {
+        self.config.coalesce_window = coalesce_window;
+        self
+    }
+
+    /// Enable SO_KEEPALIVE with this idle time on client and upstream sockets.
+    pub fn tcp_keepalive(mut self, tcp_keepalive: Option<Duration>) -> Self {
+        self.config.tcp_keepalive = tcp_keepalive;
+        self
+    }
+
+    /// Set TCP_NODELAY on client and upstream sockets, off by default.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.config.nodelay = nodelay;
+        self
+    }
+
+    /// Source address for outbound connections; targets of the other address family are unreachable.
+    pub fn outbound_bind(mut self, outbound_bind: Option<IpAddr>) -> Self {
+        self.config.outbound_bind = outbound_bind;
+        self
+    }
+
+    /// Address for UDP ASSOCIATE relay sockets, instead of the interface the client connected to.
+    /// Has no effect without the `udp` feature.
+    pub fn udp_bind_addr(mut self, udp_bind_addr: Option<IpAddr>) -> Self {
+        self.config.udp_bind_addr = udp_bind_addr;
+        self
+    }
+
+    /// Interface index used to reach link-local IPv6 targets, which cannot be dialed without one.
+    pub fn ipv6_scope_id(mut self, ipv6_scope_id: Option<u32>) -> Self {
+        self.config.ipv6_scope_id = ipv6_scope_id;
+        self
+    }
+
+    /// Set IPV6_V6ONLY on IPv6 listeners: `true` refuses IPv4-mapped clients, `false` accepts
+    /// them too. The platform default applies when unset.
+    pub fn ipv6_only(mut self, ipv6_only: Option<bool>) -> Self {
+        self.config.ipv6_only = ipv6_only;
+        self
+    }
+
+    /// Cap each connection's throughput in bytes per second.
+    pub fn rate_limit(mut self, rate_limit: Option<u64>) -> Self {
+        self.config.rate_limit = rate_limit;
+        self
+    }
+
+    /// Whether the two relay directions get their own `rate_limit` budget or split one.
+    pub fn rate_limit_mode(mut self, rate_limit_mode: RateLimitMode) -> Self {
+        self.config.rate_limit_mode = rate_limit_mode;
+        self
+    }
+
+    /// Cap the combined throughput of every connection on the server in bytes per second.
+    pub fn global_rate_limit(mut self, global_rate_limit: Option<u64>) -> Self {
+        self.config.global_rate_limit = global_rate_limit;
+        self
+    }
+
+    /// Forward CONNECT requests through this parent SOCKS5 proxy instead of dialing targets directly.
+    /// Domain targets are passed on unresolved, so `dns_family` does not apply to them and
+    /// `block_private_addresses` can only check them against a local resolution.
+    pub fn upstream_proxy(mut self, upstream_proxy: Option<SocketAddr>) -> Self {
+        self.config.upstream_proxy = upstream_proxy;
+        self
+    }
+
+    /// Authenticate to the `upstream_proxy` with RFC 1929 username/password. If the parent rejects
+    /// the credentials, the client's CONNECT fails with a "connection not allowed" reply.
+    pub fn upstream_auth<S: Into<String>>(mut self, username: S, password: S) -> Self {
+        self.config.upstream_credentials = Some((username.into(), password.into()));
+        self
+    }
+
+    /// Announce the client's address to CONNECT targets with a PROXY protocol header before relaying.
+    /// Servers refuse to bind with both this and `upstream_proxy`, since the target's address is
+    /// then only known to the parent.
+    pub fn proxy_protocol(mut self, proxy_protocol: Option<ProxyProtocol>) -> Self {
+        self.config.proxy_protocol = proxy_protocol;
+        self
+    }
+
+    /// Expect connections from `proxy_protocol_trusted` peers to open with a PROXY protocol header
+    /// and treat the source address it carries as the client's; their connections without a valid
+    /// header are dropped.
+    pub fn accept_proxy_protocol(mut self, accept_proxy_protocol: bool) -> Self {
+        self.config.accept_proxy_protocol = accept_proxy_protocol;
+        self
+    }
+
+    /// Peers, typically load balancers, whose PROXY headers are believed. Other peers are served as
+    /// direct clients under their own address, so a header they send fails the handshake. Without
+    /// this, no peer is trusted.
+    pub fn proxy_protocol_trusted(mut self, proxy_protocol_trusted: AccessControl) -> Self {
+        self.config.proxy_protocol_trusted = Some(proxy_protocol_trusted);
+        self
+    }
+
+    /// Terminate TLS on accepted connections with this certificate chain and key before the handshake.
+    #[cfg(feature = "tls")]
+    pub fn tls_identity(mut self, cert_chain: Vec<rustls::pki_types::CertificateDer<'static>>, key: rustls::pki_types::PrivateKeyDer<'static>) -> Self {
+        self.config.tls_identity = Some((cert_chain, key));
+        self
+    }
+
+    pub fn build(self) -> Config {
+        self.config
+    }
+}
+
+pub struct Server {
+    config: Arc<Config>,
+    state: Arc<ServerState>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownStats {
+    pub served: usize,
+    pub aborted: usize,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ServerStats {
+    pub active_connections: usize,
+    pub total_connections: u64,
+    pub total_bytes: u64,
+}
+
+/// A connection currently being served, as listed by [`Server::connections`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub client_addr: SocketAddr,
+}
+
+/// Run a SOCKS server with the default configuration on `addr` until it fails.
+///
+/// ```no_run
+/// # #[tokio::main]
+/// # async fn main() -> std::io::Result<()> {
+/// socks_lib::serve("127.0.0.1:1080").await
+/// # }
+/// ```
+pub async fn serve<A: ToSocketAddrs>(addr: A) -> Result<(), Error> {
+    let server_socket = TcpListener::bind(addr).await?;
+    Server::new(Config::builder().build()).bind_listener(server_socket)?.run().await
+}
+
 impl Server {
     pub fn new(config: Config) -> Self {
         Server {
-            config
+            state: Arc::new(ServerState::new(&config)),
+            config: Arc::new(config),
         }
     }
 
     pub async fn handle(&self) -> Result<(), Error> {
-        let server_socket: TcpListener = TcpListener::bind((self.config.local_addr.as_str(), self.config.local_port)).await?;
-        while let Ok((client_stream, _client_addr)) = server_socket.accept().await {
-            tokio::spawn(async {
-                let (client_reader, client_writer) = client_stream.into_split();
-                let mut read_task: JoinHandle<Result<(), Error>> = tokio::spawn(async {
-                    if let Err(err) = handle_connection(client_reader, client_writer).await {
-                        return Err(err);
-                    }
-                    Ok(())
-                });
-                if tokio::try_join!(&mut read_task).is_err() {
-                    eprintln!("err");
-                }
-            });
-        }
-        Ok::<(), Error>(())
+        self.handle_with_shutdown(std::future::pending(), None).await?;
+        Ok(())
     }
-}
 
-async fn handle_connection(mut client_reader: OwnedReadHalf, mut client_writer: OwnedWriteHalf) -> Result<(), Error> {
-    let mut reader_buffer: [u8; READER_BUFFER_LEN] = [0u8; READER_BUFFER_LEN];
+    pub async fn handle_with_shutdown<F: Future<Output = ()>>(&self, shutdown: F, drain_timeout: Option<Duration>) -> Result<ShutdownStats, Error> {
+        self.bind().await?.run_with_shutdown(shutdown, drain_timeout).await
+    }
 
-    let ver = client_reader.read_u8().await?;
-    if VERSION != ver {
-        return Err(Error::new(ErrorKind::InvalidInput, format!("invalid socks version {}", ver)));
+    /// Serve until `token` is cancelled; see [`BoundServer::run_with_token`].
+    #[cfg(feature = "cancellation")]
+    pub async fn handle_with_token(&self, token: CancellationToken) -> Result<ShutdownStats, Error> {
+        self.bind().await?.run_with_token(token).await
     }
-    let n_method = client_reader.read_u8().await?;
-    let method_len = client_reader.read(&mut reader_buffer[..n_method as usize]).await?;
-    if n_method as usize != method_len {
-        return Err(Error::new(ErrorKind::InvalidInput, format!("invalid methods length {}", method_len)));
+
+    pub async fn bind(&self) -> Result<BoundServer, Error> {
+        bind_server(&self.config, &self.state, Protocol::Socks).await
     }
-    client_writer.write_all(&[5u8, 0u8]).await?;
 
-    let ver = client_reader.read_u8().await?;
-    if VERSION != ver {
-        return Err(Error::new(ErrorKind::InvalidInput, format!("invalid socks version {}", ver)));
+    /// Bind the configured addresses and hand accepted connections to the caller; see [`Incoming`].
+    pub async fn incoming(&self) -> Result<Incoming, Error> {
+        Ok(self.bind().await?.incoming())
+    }
+
+    /// Like `handle`, serving connections one at a time without spawning; see [`BoundServer::run_sequential`].
+    pub async fn handle_sequential(&self) -> Result<(), Error> {
+        self.bind().await?.run_sequential().await
     }
-    let cmd = client_reader.read_u8().await?;
-    let _rsv = client_reader.read_u8().await?;
 
-    let dst_addr = handle_connection_addr(&mut client_reader, &mut reader_buffer).await?;
+    /// Serve on an already bound listener, such as an inherited socket, ignoring the configured addresses.
+    pub fn bind_listener(&self, server_socket: TcpListener) -> Result<BoundServer, Error> {
+        bound_server(&self.config, &self.state, Protocol::Socks, vec![Listener::Tcp(server_socket)])
+    }
 
-    handle_connection_down(cmd, dst_addr, client_reader, client_writer).await?;
+    /// Serve on a Unix domain socket at `path` instead of the configured addresses, failing if
+    /// `path` already exists. Unix clients have no address: they are reported as `0.0.0.0:0` and
+    /// share a single `max_per_client` budget. Only CONNECT is served; BIND and UDP ASSOCIATE would
+    /// open a port on the network and get "command not supported".
+    #[cfg(unix)]
+    pub fn bind_unix<P: AsRef<Path>>(&self, path: P) -> Result<BoundServer, Error> {
+        bound_server(&self.config, &self.state, Protocol::Socks, vec![Listener::Unix(UnixListener::bind(path)?)])
+    }
 
-    Ok(())
+    pub fn stats(&self) -> ServerStats {
+        self.state.counters.snapshot()
+    }
+
+    /// Connections currently being served, oldest first. Connections served by `handle_sequential` are not listed.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.state.counters.connections()
+    }
+
+    /// Abort the connection with this `id`, closing both its client and remote sockets.
+    /// Returns `false` if no such connection is open.
+    pub fn kill(&self, id: u64) -> bool {
+        self.state.counters.kill(id)
+    }
 }
 
-async fn handle_connection_addr(client_reader: &mut OwnedReadHalf, reader_buffer: &mut [u8; 256]) -> Result<Address, Error> {
-    let atyp = client_reader.read_u8().await?;
-    let mut dst_addr: String;
-    match atyp {
-        ATYP_IPV4 => {
-            let _ = client_reader.read(&mut reader_buffer[..4]).await?;
-            dst_addr = String::from_utf8_lossy(&reader_buffer[..4]).to_string();
-        }
-        ATYP_DOMAIN_NAME => {
-            let dst_addr_len: u8 = client_reader.read_u8().await?;
-            if dst_addr_len as usize > 8192 {
-                return Err(Error::new(ErrorKind::InvalidInput, format!("invalid dst_addr_len {}", dst_addr_len)));
-            }
-            let mut dst_addr_len_count = dst_addr_len as usize;
-            dst_addr = String::with_capacity(dst_addr_len_count);
-            while dst_addr_len_count != 0 {
-                if dst_addr_len_count > READER_BUFFER_LEN {
-                    let _ = client_reader.read(&mut reader_buffer[..]).await?;
-                    dst_addr.push_str(String::from_utf8_lossy(&reader_buffer[..]).as_ref());
-                    dst_addr_len_count -= READER_BUFFER_LEN;
-                } else {
-                    let _ = client_reader.read(&mut reader_buffer[..dst_addr_len_count]).await?;
-                    dst_addr.push_str(String::from_utf8_lossy(&reader_buffer[..dst_addr_len_count]).as_ref());
-                    dst_addr_len_count = 0;
-                }
-            }
-        }
-        ATYP_IPV6 => {
-            let _ = client_reader.read(&mut reader_buffer[..16]).await?;
-            dst_addr = String::from_utf8_lossy(&reader_buffer[..16]).to_string();
-        }
-        _ => {
-            return Err(Error::new(ErrorKind::InvalidInput, format!("invalid atyp value {}", atyp)));
-        }
+async fn bind_server(config: &Arc<Config>, state: &Arc<ServerState>, protocol: Protocol) -> Result<BoundServer, Error> {
+    let mut server_sockets = Vec::new();
+    if config.listen_addrs.is_empty() {
+        server_sockets.push(Listener::Tcp(bind_tcp(config, (config.local_addr.as_str(), config.local_port)).await?));
     }
-    let dst_port = client_reader.read_u16().await?;
-    Ok(Address {
-        addr: dst_addr,
-        port: dst_port,
-        atyp,
-    })
+    for listen_addr in &config.listen_addrs {
+        server_sockets.push(Listener::Tcp(bind_tcp(config, listen_addr).await?));
+    }
+    bound_server(config, state, protocol, server_sockets)
 }
 
-async fn handle_connection_down(cmd: u8, dst_addr: Address, mut client_reader: OwnedReadHalf, mut client_writer: OwnedWriteHalf) -> Result<(), Error> {
-    match cmd {
-        CMD_CONNECT => {
-            let (mut remote_reader, mut remote_writer) = handle_connect_tcp(dst_addr).await?;
-            client_writer.write_all(&[5u8, 0u8, 0u8, 1u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8]).await?;
+/// Bind the first address `addr` resolves to that can be listened on, like `TcpListener::bind`.
+async fn bind_tcp<A: ToSocketAddrs>(config: &Config, addr: A) -> Result<TcpListener, Error> {
+    let mut last_err = None;
+    for listen_addr in tokio::net::lookup_host(addr).await? {
+        match listen_tcp(config, listen_addr) {
+            Ok(server_socket) => return Ok(server_socket),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| Error::new(ErrorKind::InvalidInput, "could not resolve to any address")))
+}
 
-            let mut task_upstream: JoinHandle<Result<(),
-                Error>> = tokio::spawn(async move {
-                tokio::io::copy(&mut client_reader, &mut remote_writer).await?;
-                Ok(())
-            });
+fn listen_tcp(config: &Config, listen_addr: SocketAddr) -> Result<TcpListener, Error> {
+    let server_socket = match listen_addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    #[cfg(unix)]
+    server_socket.set_reuseaddr(true)?;
+    if let (Some(ipv6_only), SocketAddr::V6(_)) = (config.ipv6_only, listen_addr) {
+        SockRef::from(&server_socket).set_only_v6(ipv6_only)?;
+    }
+    server_socket.bind(listen_addr)?;
+    server_socket.listen(config.listen_backlog)
+}
 
-            let mut task_downstream: JoinHandle<Result<(),
-                Error>> = tokio::spawn(async move {
-                tokio::io::copy(&mut remote_reader, &mut client_writer).await?;
-                Ok(())
-            });
+fn bound_server(config: &Arc<Config>, state: &Arc<ServerState>, protocol: Protocol, server_sockets: Vec<Listener>) -> Result<BoundServer, Error> {
+    if config.proxy_protocol.is_some() && config.upstream_proxy.is_some() {
+        return Err(Error::new(ErrorKind::InvalidInput, "proxy_protocol cannot be combined with upstream_proxy"));
+    }
+    #[cfg(feature = "tls")]
+    let tls_acceptor = tls::acceptor(config)?;
+    Ok(BoundServer {
+        config: config.clone(),
+        state: state.clone(),
+        server_sockets,
+        protocol,
+        runtime: None,
+        #[cfg(feature = "tls")]
+        tls_acceptor,
+    })
+}
 
-            tokio::try_join!(&mut task_upstream, &mut task_downstream)?;
-        }
-        CMD_ASSOCIATE => {
-            let mut remote_socket = handle_connect_udp(dst_addr).await?;
-            // todo associate implement
-            return Err(Error::new(ErrorKind::InvalidInput, format!("unimplemented cmd value {}", cmd)));
+#[derive(Debug, Clone, Copy)]
+enum Protocol {
+    Socks,
+    HttpConnect,
+}
 
-        }
-        _ => {
-            return Err(Error::new(ErrorKind::InvalidInput, format!("invalid cmd value {}", cmd)));
+impl Protocol {
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_connection<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(self, config: Arc<Config>, state: Arc<ServerState>, client_addr: SocketAddr, server_addr: SocketAddr, accepted_at: Instant, token: &ConnectionToken, client_reader: R, client_writer: W) -> Result<(), Error> {
+        match self {
+            Protocol::Socks => handle_connection(config, state, client_addr, server_addr, accepted_at, token, client_reader, client_writer).await,
+            Protocol::HttpConnect => http::handle_connection(config, state, client_addr, server_addr, accepted_at, token, client_reader, client_writer).await,
         }
     }
-
-    Ok(())
 }
 
-async fn handle_connect_tcp(dst_addr: Address) -> Result<(OwnedReadHalf, OwnedWriteHalf), Error> {
-    let remote_stream = TcpStream::connect((dst_addr.addr.as_str(), dst_addr.port)).await?;
-    let (remote_reader, remote_writer) = remote_stream.into_split();
-    Ok((remote_reader, remote_writer))
+pub struct BoundServer {
+    config: Arc<Config>,
+    state: Arc<ServerState>,
+    server_sockets: Vec<Listener>,
+    protocol: Protocol,
+    runtime: Option<Handle>,
+    #[cfg(feature = "tls")]
+    tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
 }
 
-async fn handle_connect_udp(dst_addr: Address) -> Result<UdpSocket, Error> {
-    let remote_socket = UdpSocket::bind("0.0.0.0:0").await?;
-    Ok(remote_socket)
+impl BoundServer {
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.server_sockets[0].local_addr()
+    }
+
+    pub fn local_addrs(&self) -> Result<Vec<SocketAddr>, Error> {
+        self.server_sockets.iter().map(Listener::local_addr).collect()
+    }
+
+    pub fn stats(&self) -> ServerStats {
+        self.state.counters.snapshot()
+    }
+
+    /// Spawn connection tasks on this runtime instead of the one driving `run`.
+    pub fn runtime(mut self, runtime: Handle) -> Self {
+        self.runtime = Some(runtime);
+        self
+    }
+
+    pub async fn run(self) -> Result<(), Error> {
+        self.run_with_shutdown(std::future::pending(), None).await?;
+        Ok(())
+    }
+
+    /// Accept connections without serving them, for the caller to handle or `serve` itself.
+    pub fn incoming(self) -> Incoming {
+        Incoming {
+            config: self.config,
+            state: self.state,
+            server_sockets: self.server_sockets,
+            next_listener: 0,
+            protocol: self.protocol,
+            #[cfg(feature = "tls")]
+            tls_acceptor: self.tls_acceptor,
+        }
+    }
+
+    /// Serve one connection at a time on the current task instead of spawning a task per
+    /// connection; a panicking handler takes the server down with it.
+    pub async fn run_sequential(self) -> Result<(), Error> {
+        let mut next_listener = 0;
+        let mut accept_backoff = None;
+        loop {
+            if let Some(accept_backoff) = accept_backoff {
+                tokio::time::sleep(accept_backoff).await;
+            }
+            let (client_stream, client_addr) = match accept_any(&self.server_sockets, &mut next_listener).await {
+                Ok(accepted) => {
+                    accept_backoff = None;
+                    accepted
+                }
+                Err(err) if is_fatal_accept_error(&err) => {
+                    warn!(self.config, "accept failed: {}", err);
+                    return Ok(());
+                }
+                Err(err) if is_connection_accept_error(&err) => {
+                    debug!(self.config, "accept failed: {}", err);
+                    continue;
+                }
+                Err(err) => {
+                    let backoff = accept_backoff.map_or(MIN_ACCEPT_BACKOFF, |backoff| (backoff * 2).min(MAX_ACCEPT_BACKOFF));
+                    warn!(self.config, "accept failed, retrying in {:?}: {}", backoff, err);
+                    accept_backoff = Some(backoff);
+                    continue;
+                }
+            };
+            debug!(self.config, "{} accepted", client_addr);
+            let _active = match ActiveConnection::new(self.config.clone(), self.state.clone(), client_addr.ip()) {
+                Some(active) => active,
+                None => continue,
+            };
+            if let Err(err) = client_stream.configure(&self.config) {
+                warn!(self.config, "{} failed to configure socket: {}", client_addr, err);
+            }
+            let result = handle_stream(self.config.clone(), self.state.clone(), self.protocol, client_addr, Instant::now(), ConnectionToken::default(), client_stream, #[cfg(feature = "tls")] self.tls_acceptor.clone()).await;
+            handle_closed(&self.config, client_addr, &result);
+        }
+    }
+
+    /// Serve until `token` is cancelled, then stop accepting. Open relays see the cancellation
+    /// too and close both peers cleanly, as after a finished transfer, with `CloseReason::Cancelled`;
+    /// handshakes in progress stop at once. Connections still dialing or waiting for a BIND peer
+    /// are aborted after two seconds and count as aborted.
+    #[cfg(feature = "cancellation")]
+    pub async fn run_with_token(self, token: CancellationToken) -> Result<ShutdownStats, Error> {
+        let connection_token = ConnectionToken {
+            token: Some(token.clone()),
+        };
+        // Relays drain for up to `DRAIN_TIMEOUT` on close; give them that and a margin.
+        self.serve(token.cancelled_owned(), Some(DRAIN_TIMEOUT * 2), connection_token).await
+    }
+
+    pub async fn run_with_shutdown<F: Future<Output = ()>>(self, shutdown: F, drain_timeout: Option<Duration>) -> Result<ShutdownStats, Error> {
+        self.serve(shutdown, drain_timeout, ConnectionToken::default()).await
+    }
+
+    async fn serve<F: Future<Output = ()>>(self, shutdown: F, drain_timeout: Option<Duration>, token: ConnectionToken) -> Result<ShutdownStats, Error> {
+        let server_sockets = self.server_sockets;
+        let connection_permits = self.config.max_connections.map(|max_connections| Arc::new(Semaphore::new(max_connections)));
+        let mut connections: JoinSet<()> = JoinSet::new();
+        let mut stats = ShutdownStats::default();
+        let mut accept_backoff: Option<Duration> = None;
+        let mut next_listener = 0;
+        tokio::pin!(shutdown);
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => break,
+                Some(_) = connections.join_next(), if !connections.is_empty() => {
+                    stats.served += 1;
+                }
+                accepted = async {
+                    if let Some(accept_backoff) = accept_backoff {
+                        tokio::time::sleep(accept_backoff).await;
+                    }
+                    let permit = match (&connection_permits, self.config.limit_policy) {
+                        (Some(connection_permits), LimitPolicy::Wait) => connection_permits.clone().acquire_owned().await.ok(),
+                        _ => None,
+                    };
+                    accept_any(&server_sockets, &mut next_listener).await.map(|accepted| (accepted, permit))
+                } => {
+                    let ((client_stream, client_addr), permit) = match accepted {
+                        Ok(accepted) => {
+                            accept_backoff = None;
+                            accepted
+                        }
+                        Err(err) if is_fatal_accept_error(&err) => {
+                            warn!(self.config, "accept failed: {}", err);
+                            break;
+                        }
+                        Err(err) if is_connection_accept_error(&err) => {
+                            debug!(self.config, "accept failed: {}", err);
+                            continue;
+                        }
+                        Err(err) => {
+                            let backoff = accept_backoff.map_or(MIN_ACCEPT_BACKOFF, |backoff| (backoff * 2).min(MAX_ACCEPT_BACKOFF));
+                            warn!(self.config, "accept failed, retrying in {:?}: {}", backoff, err);
+                            accept_backoff = Some(backoff);
+                            continue;
+                        }
+                    };
+                    let permit = match (permit, &connection_permits) {
+                        (Some(permit), _) => Some(permit),
+                        (None, Some(connection_permits)) => match connection_permits.clone().try_acquire_owned() {
+                            Ok(permit) => Some(permit),
+                            Err(_) => {
+                                warn!(self.config, "{} rejected, connection limit reached", client_addr);
+                                continue;
+                            }
+                        },
+                        (None, None) => None,
+                    };
+                    let accepted_at = Instant::now();
+                    debug!(self.config, "{} accepted", client_addr);
+                    let config = self.config.clone();
+                    let state = self.state.clone();
+                    let protocol = self.protocol;
+                    let token = token.child();
+                    #[cfg(feature = "tls")]
+                    let tls_acceptor = self.tls_acceptor.clone();
+                    let active = match ActiveConnection::new(config.clone(), state.clone(), client_addr.ip()) {
+                        Some(active) => active,
+                        None => {
+                            warn!(config, "{} rejected, per-client connection limit reached", client_addr);
+                            continue;
+                        }
+                    };
+                    let connection = async move {
+                        let _permit = permit;
+                        if let Err(err) = client_stream.configure(&config) {
+                            warn!(config, "{} failed to configure socket: {}", client_addr, err);
+                        }
+                        let mut handler = AbortOnDrop(tokio::spawn(handle_stream(config.clone(), state, protocol, client_addr, accepted_at, token, client_stream, #[cfg(feature = "tls")] tls_acceptor)));
+                        active.track(client_addr, handler.0.abort_handle());
+                        let result = match (&mut handler.0).await {
+                            Ok(result) => result,
+                            Err(join_err) if join_err.is_cancelled() => Err(Error::new(ErrorKind::ConnectionAborted, "connection killed")),
+                            Err(join_err) => Err(Error::other(format!("connection handler panicked: {}", panic_message(join_err)))),
+                        };
+                        handle_closed(&config, client_addr, &result);
+                    };
+                    match &self.runtime {
+                        Some(runtime) => connections.spawn_on(connection, runtime),
+                        None => connections.spawn(connection),
+                    };
+                }
+            }
+        }
+        drop(server_sockets);
+
+        if let Some(drain_timeout) = drain_timeout {
+            let _ = tokio::time::timeout(drain_timeout, async {
+                while connections.join_next().await.is_some() {
+                    stats.served += 1;
+                }
+            }).await;
+        }
+        stats.aborted = connections.len();
+        connections.shutdown().await;
+        Ok(stats)
+    }
+}
+
+fn handle_closed(config: &Config, client_addr: SocketAddr, result: &Result<(), Error>) {
+    match result {
+        Ok(()) => debug!(config, "{} closed", client_addr),
+        Err(err) => {
+            warn!(config, "{} closed with error: {}", client_addr, err);
+            config.event_handler.on_error(client_addr, err);
+        }
+    }
+}
+
+fn is_fatal_accept_error(err: &Error) -> bool {
+    err.kind() == ErrorKind::InvalidInput
+}
+
+fn is_connection_accept_error(err: &Error) -> bool {
+    matches!(err.kind(), ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset | ErrorKind::Interrupted)
+}
+
+/// Accept from whichever listener is ready, scanning from the one after the listener that
+/// accepted last so a busy listener cannot starve the others.
+async fn accept_any(server_sockets: &[Listener], next_listener: &mut usize) -> Result<(ClientStream, SocketAddr), Error> {
+    std::future::poll_fn(|cx| {
+        for offset in 0..server_sockets.len() {
+            let index = (*next_listener + offset) % server_sockets.len();
+            if let Poll::Ready(accepted) = server_sockets[index].poll_accept(cx) {
+                *next_listener = index + 1;
+                return Poll::Ready(accepted);
+            }
+        }
+        Poll::Pending
+    }).await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_stream(config: Arc<Config>, state: Arc<ServerState>, protocol: Protocol, client_addr: SocketAddr, accepted_at: Instant, token: ConnectionToken, mut client_stream: ClientStream, #[cfg(feature = "tls")] tls_acceptor: Option<tokio_rustls::TlsAcceptor>) -> Result<(), Error> {
+    let server_addr = client_stream.local_addr()?;
+    let mut client_addr = client_addr;
+    if config.trusts_proxy_header(client_addr.ip()) {
+        if let Some(proxied_addr) = with_handshake_timeout(&config, accepted_at, &token, proxy_protocol::read_header(&mut client_stream)).await? {
+            debug!(config, "{} proxied for {}", client_addr, proxied_addr);
+            client_addr = proxied_addr;
+        }
+    } else if config.accept_proxy_protocol {
+        debug!(config, "{} is not a trusted proxy, serving it directly", client_addr);
+    }
+    #[cfg(feature = "tls")]
+    if let Some(tls_acceptor) = tls_acceptor {
+        let tls_stream = with_handshake_timeout(&config, accepted_at, &token, tls_acceptor.accept(client_stream)).await?;
+        let (client_reader, client_writer) = tokio::io::split(tls_stream);
+        return protocol.handle_connection(config, state, client_addr, server_addr, accepted_at, &token, client_reader, client_writer).await;
+    }
+    match client_stream {
+        ClientStream::Tcp(client_stream) => {
+            let (client_reader, client_writer) = client_stream.into_split();
+            protocol.handle_connection(config, state, client_addr, server_addr, accepted_at, &token, client_reader, client_writer).await
+        }
+        #[cfg(unix)]
+        ClientStream::Unix(client_stream) => {
+            let (client_reader, client_writer) = client_stream.into_split();
+            protocol.handle_connection(config, state, client_addr, server_addr, accepted_at, &token, client_reader, client_writer).await
+        }
+    }
+}
+
+/// Counts a connection as active until dropped, however its task ends, including by abort.
+struct ActiveConnection {
+    config: Arc<Config>,
+    state: Arc<ServerState>,
+    id: u64,
+    client_ip: IpAddr,
+}
+
+impl ActiveConnection {
+    /// `None` when `client_ip` is already at `max_per_client`.
+    fn new(config: Arc<Config>, state: Arc<ServerState>, client_ip: IpAddr) -> Option<Self> {
+        if let Some(max_per_client) = config.max_per_client {
+            let mut client_connections = state.counters.client_connections.lock().unwrap();
+            let connections = client_connections.get(&client_ip).copied().unwrap_or(0);
+            if connections >= max_per_client {
+                return None;
+            }
+            client_connections.insert(client_ip, connections + 1);
+        }
+        let id = state.counters.total_connections.fetch_add(1, Ordering::Relaxed) + 1;
+        state.counters.active_connections.fetch_add(1, Ordering::Relaxed);
+        config.metrics.connection_opened();
+        Some(ActiveConnection {
+            config,
+            state,
+            id,
+            client_ip,
+        })
+    }
+
+    /// List the connection in `Server::connections`, killable through `abort_handle`, until dropped.
+    fn track(&self, client_addr: SocketAddr, abort_handle: AbortHandle) {
+        let info = ConnectionInfo {
+            id: self.id,
+            client_addr,
+        };
+        self.state.counters.connections.lock().unwrap().insert(self.id, (info, abort_handle));
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.state.counters.active_connections.fetch_sub(1, Ordering::Relaxed);
+        self.config.metrics.connection_closed();
+        self.state.counters.connections.lock().unwrap().remove(&self.id);
+        if self.config.max_per_client.is_some() {
+            let mut client_connections = self.state.counters.client_connections.lock().unwrap();
+            if let Some(connections) = client_connections.get_mut(&self.client_ip) {
+                *connections -= 1;
+                if *connections == 0 {
+                    client_connections.remove(&self.client_ip);
+                }
+            }
+        }
+    }
+}
+
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+fn panic_message(join_err: JoinError) -> String {
+    match join_err.try_into_panic() {
+        Ok(panic) => match panic.downcast::<String>() {
+            Ok(message) => *message,
+            Err(panic) => match panic.downcast::<&str>() {
+                Ok(message) => message.to_string(),
+                Err(_) => "unknown panic".to_string(),
+            },
+        },
+        Err(join_err) => join_err.to_string(),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_connection<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(config: Arc<Config>, state: Arc<ServerState>, client_addr: SocketAddr, server_addr: SocketAddr, accepted_at: Instant, token: &ConnectionToken, client_reader: R, mut client_writer: W) -> Result<(), Error> {
+    // Clients may pipeline the greeting, authentication and request, and even the first payload
+    // bytes, into one segment: the buffer keeps whatever one phase read past its end for the next,
+    // and for the relay.
+    let mut client_reader = BufReader::with_capacity(HANDSHAKE_BUFFER_LEN, client_reader);
+    let handshake = async {
+        let ver = client_reader.read_u8().await?;
+        if VERSION_4 == ver {
+            let (cmd, dst_addr) = handle_request_v4(&mut client_reader).await?;
+            check_domain_len(&config, &dst_addr)?;
+            return Ok((ver, Command::try_from(cmd).map_err(|_| cmd), dst_addr));
+        }
+        if VERSION != ver {
+            return Err(SocksError::UnsupportedVersion(ver).into());
+        }
+        let methods = match parse::parse_methods(&mut client_reader).await {
+            Ok(methods) => methods,
+            Err(err) if matches!(SocksError::from_io(&err), Some(SocksError::ProtocolViolation(_))) => {
+                client_writer.write_all(&[VERSION, METHOD_NO_ACCEPTABLE]).await?;
+                client_writer.shutdown().await?;
+                return Err(err);
+            }
+            Err(err) => return Err(err),
+        };
+        let offered: HashSet<MethodType> = methods.iter().copied().collect();
+        let method = config.methods(client_addr.ip()).into_iter().find(|method| offered.contains(method));
+        debug!(config, "{} offered methods {:?}, selected {:?}", client_addr, methods, method);
+        match method {
+            Some(METHOD_USERNAME_PASSWORD) => {
+                client_writer.write_all(&[VERSION, METHOD_USERNAME_PASSWORD]).await?;
+                handle_connection_auth(&config, &mut client_reader, &mut client_writer).await?;
+            }
+            Some(method) => {
+                client_writer.write_all(&[VERSION, method]).await?;
+            }
+            None => {
+                if methods.contains(&METHOD_GSSAPI) {
+                    debug!(config, "{} offered gssapi, which is not supported", client_addr);
+                }
+                client_writer.write_all(&[VERSION, METHOD_NO_ACCEPTABLE]).await?;
+                client_writer.shutdown().await?;
+                return Err(SocksError::NoAcceptableMethods(methods).into());
+            }
+        }
+
+        match parse_request(&mut client_reader).await.and_then(|request| check_domain_len(&config, request.address()).map(|()| request)) {
+            Ok(request) => Ok((ver, request.command(), request.address().clone())),
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => Err(err),
+            Err(err) => {
+                let rep = match err.kind() {
+                    ErrorKind::Unsupported => REP_ADDRESS_TYPE_NOT_SUPPORTED,
+                    _ => REP_GENERAL_FAILURE,
+                };
+                write_reply(&mut client_writer, rep, &UNSPECIFIED_ADDR.into()).await?;
+                Err(err)
+            }
+        }
+    };
+    let (ver, command, dst_addr) = match with_handshake_timeout(&config, accepted_at, token, handshake).await {
+        Ok(request) => request,
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+            debug!(config, "{} disconnected during handshake", client_addr);
+            return Ok(());
+        }
+        Err(err) => return Err(err),
+    };
+
+    if VERSION_4 == ver {
+        debug!(config, "{} requested socks4 {:?} to {}", client_addr, command, dst_addr);
+        return handle_connection_v4(&config, &state, client_addr, accepted_at, token, command, dst_addr, client_reader, client_writer).await;
+    }
+    debug!(config, "{} requested {:?} to {}", client_addr, command, dst_addr);
+    let command = match command {
+        Ok(command) => command,
+        Err(cmd) => {
+            write_reply(&mut client_writer, REP_COMMAND_NOT_SUPPORTED, &UNSPECIFIED_ADDR.into()).await?;
+            return Err(Error::new(ErrorKind::Unsupported, format!("unsupported cmd value {}", cmd)));
+        }
+    };
+
+    handle_connection_down(&config, &state, client_addr, server_addr, accepted_at, token, command, dst_addr, client_reader, client_writer).await?;
+
+    Ok(())
+}
+
+/// Run one handshake phase against the deadline shared by all of them, counted from accept,
+/// giving up as soon as `token` is cancelled.
+async fn with_handshake_timeout<T, F: Future<Output = Result<T, Error>>>(config: &Config, accepted_at: Instant, token: &ConnectionToken, handshake: F) -> Result<T, Error> {
+    let handshake = async {
+        tokio::select! {
+            handshaken = handshake => handshaken,
+            _ = token.cancelled() => Err(Error::new(ErrorKind::ConnectionAborted, "handshake cancelled")),
+        }
+    };
+    match config.handshake_timeout {
+        Some(handshake_timeout) => tokio::time::timeout_at((accepted_at + handshake_timeout).into(), handshake).await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, format!("handshake timed out after {:?}", handshake_timeout)))?,
+        None => handshake.await,
+    }
+}
+
+async fn handle_request_v4<R: AsyncRead + Unpin>(client_reader: &mut R) -> Result<(CmdType, Address), Error> {
+    let cmd = client_reader.read_u8().await?;
+    let dst_port = client_reader.read_u16().await?;
+    let mut octets = [0u8; 4];
+    client_reader.read_exact(&mut octets).await?;
+    let _user_id = read_null_terminated(client_reader).await?;
+    let target = match octets {
+        [0, 0, 0, last] if last != 0 => Target::Domain(parse_domain(read_null_terminated(client_reader).await?)?),
+        _ => Target::Ipv4(Ipv4Addr::from(octets)),
+    };
+    Ok((cmd, Address {
+        target,
+        port: dst_port,
+    }))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_connection_v4<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(config: &Arc<Config>, state: &Arc<ServerState>, client_addr: SocketAddr, accepted_at: Instant, token: &ConnectionToken, command: Result<Command, CmdType>, dst_addr: Address, client_reader: R, mut client_writer: W) -> Result<(), Error> {
+    if config.auth_required(client_addr.ip()) {
+        write_reply_v4(&mut client_writer, REP_V4_REJECTED, None).await?;
+        return Err(Error::new(ErrorKind::PermissionDenied, "socks4 is not allowed when authentication is configured"));
+    }
+    if Ok(Command::Connect) != command {
+        write_reply_v4(&mut client_writer, REP_V4_REJECTED, None).await?;
+        return Err(SocksError::ProtocolViolation(format!("invalid socks4 command {:?}", command)).into());
+    }
+    let (remote_reader, remote_writer) = match handle_connect_tcp(config, state, client_addr, &dst_addr).await {
+        Ok(remote) => remote,
+        Err(err) => {
+            warn!(config, "{} connect to {} failed: {}", client_addr, dst_addr, err);
+            write_reply_v4(&mut client_writer, REP_V4_REJECTED, None).await?;
+            return Err(err);
+        }
+    };
+    write_reply_v4(&mut client_writer, REP_V4_GRANTED, Some(remote_writer.local_addr()?)).await?;
+    handle_connected(config, client_addr, &dst_addr, remote_writer.peer_addr()?);
+
+    handle_relay(config, state, client_addr, accepted_at, token, &dst_addr, client_reader, client_writer, remote_reader, remote_writer).await
+}
+
+async fn read_null_terminated<R: AsyncRead + Unpin>(client_reader: &mut R) -> Result<Vec<u8>, Error> {
+    let mut field = Vec::new();
+    loop {
+        let byte = client_reader.read_u8().await?;
+        if byte == 0 {
+            return Ok(field);
+        }
+        if field.len() == READER_BUFFER_LEN {
+            return Err(SocksError::ProtocolViolation(format!("socks4 field longer than {}", READER_BUFFER_LEN)).into());
+        }
+        field.push(byte);
+    }
+}
+
+async fn write_reply_v4<W: AsyncWrite + Unpin>(client_writer: &mut W, rep: ReplyType, bnd_addr: Option<SocketAddr>) -> Result<(), Error> {
+    let mut reply = vec![REP_V4_VERSION, rep];
+    match bnd_addr {
+        Some(SocketAddr::V4(bnd_addr)) => {
+            reply.extend_from_slice(&bnd_addr.port().to_be_bytes());
+            reply.extend_from_slice(&bnd_addr.ip().octets());
+        }
+        _ => reply.extend_from_slice(&[0u8; 6]),
+    }
+    client_writer.write_all(&reply).await
+}
+
+async fn handle_connection_auth<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(config: &Config, client_reader: &mut R, client_writer: &mut W) -> Result<(), Error> {
+    let ver = client_reader.read_u8().await?;
+    if AUTH_VERSION != ver {
+        return Err(SocksError::ProtocolViolation(format!("invalid auth version {}", ver)).into());
+    }
+    let ulen = client_reader.read_u8().await?;
+    let mut username = vec![0u8; ulen as usize];
+    client_reader.read_exact(&mut username).await?;
+    let plen = client_reader.read_u8().await?;
+    let mut password = vec![0u8; plen as usize];
+    client_reader.read_exact(&mut password).await?;
+
+    if !config.authenticate(&username, &password).await {
+        client_writer.write_all(&[AUTH_VERSION, AUTH_FAILED]).await?;
+        return Err(SocksError::AuthFailed.into());
+    }
+    client_writer.write_all(&[AUTH_VERSION, AUTH_SUCCEEDED]).await?;
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_connection_down<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(config: &Arc<Config>, state: &Arc<ServerState>, client_addr: SocketAddr, server_addr: SocketAddr, accepted_at: Instant, token: &ConnectionToken, command: Command, dst_addr: Address, client_reader: R, mut client_writer: W) -> Result<(), Error> {
+    // Unix listeners report the unspecified address, so binding "the server's interface" would
+    // listen on every interface, and no datagram ever comes from the client's address.
+    if Command::Connect != command && UNSPECIFIED_ADDR == server_addr {
+        write_reply(&mut client_writer, REP_COMMAND_NOT_SUPPORTED, &UNSPECIFIED_ADDR.into()).await?;
+        return Err(Error::new(ErrorKind::Unsupported, format!("{:?} is not supported on unix sockets", command)));
+    }
+    match command {
+        Command::Connect => {
+            let (remote_reader, remote_writer) = match handle_connect_tcp(config, state, client_addr, &dst_addr).await {
+                Ok(remote) => remote,
+                Err(err) => {
+                    warn!(config, "{} connect to {} failed: {}", client_addr, dst_addr, err);
+                    write_reply(&mut client_writer, reply_code_for(&err), &UNSPECIFIED_ADDR.into()).await?;
+                    return Err(err);
+                }
+            };
+            // Bail before relaying if the client is gone; the remote halves drop with this frame.
+            if let Err(err) = write_reply(&mut client_writer, REP_SUCCEEDED, &remote_writer.local_addr()?.into()).await {
+                debug!(config, "{} disconnected before the reply for {}", client_addr, dst_addr);
+                return Err(err);
+            }
+            handle_connected(config, client_addr, &dst_addr, remote_writer.peer_addr()?);
+
+            handle_relay(config, state, client_addr, accepted_at, token, &dst_addr, client_reader, client_writer, remote_reader, remote_writer).await?;
+        }
+        Command::Bind => {
+            // DST.ADDR names the peer the client expects, which like any destination must pass the
+            // ruleset; zeros leave it open to any peer the ruleset allows.
+            let expected_ips = match expected_bind_peers(config, &dst_addr).await {
+                Ok(expected_ips) => expected_ips,
+                Err(err) => {
+                    warn!(config, "{} bind for {} failed: {}", client_addr, dst_addr, err);
+                    write_reply(&mut client_writer, reply_code_for(&err), &UNSPECIFIED_ADDR.into()).await?;
+                    return Err(err);
+                }
+            };
+            let bind_socket = TcpListener::bind(SocketAddr::new(server_addr.ip(), 0)).await?;
+            let bind_addr = bind_socket.local_addr()?;
+            write_reply(&mut client_writer, REP_SUCCEEDED, &bind_addr.into()).await?;
+            info!(config, "{} bound {}", client_addr, bind_addr);
+
+            let (remote_stream, remote_addr) = match handle_bind_accept(config, &bind_socket, expected_ips.as_deref()).await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    warn!(config, "{} bind on {} failed: {}", client_addr, bind_addr, err);
+                    write_reply(&mut client_writer, reply_code_for(&err), &UNSPECIFIED_ADDR.into()).await?;
+                    return Err(err);
+                }
+            };
+            drop(bind_socket);
+            write_reply(&mut client_writer, REP_SUCCEEDED, &remote_addr.into()).await?;
+            info!(config, "{} accepted {} on {}", client_addr, remote_addr, bind_addr);
+            config.event_handler.on_connect(client_addr, &dst_addr);
+
+            configure_socket(config, &remote_stream)?;
+            let (remote_reader, remote_writer) = remote_stream.into_split();
+            handle_relay(config, state, client_addr, accepted_at, token, &dst_addr, client_reader, client_writer, remote_reader, remote_writer).await?;
+        }
+        #[cfg(feature = "udp")]
+        Command::UdpAssociate => {
+            let relay_socket = udp::handle_connect_udp(config, server_addr, &dst_addr).await?;
+            let mut relay_addr = relay_socket.local_addr()?;
+            if relay_addr.ip().is_unspecified() {
+                relay_addr.set_ip(server_addr.ip().to_canonical());
+            }
+            write_reply(&mut client_writer, REP_SUCCEEDED, &relay_addr.into()).await?;
+            info!(config, "{} associated udp relay {}", client_addr, relay_addr);
+            config.event_handler.on_connect(client_addr, &dst_addr);
+
+            udp::handle_udp_relay(config, state, client_addr, token, &dst_addr, relay_socket, client_reader).await?;
+        }
+        #[cfg(not(feature = "udp"))]
+        Command::UdpAssociate => {
+            write_reply(&mut client_writer, REP_COMMAND_NOT_SUPPORTED, &UNSPECIFIED_ADDR.into()).await?;
+            return Err(Error::new(ErrorKind::Unsupported, "udp associate is not supported in this build"));
+        }
+    }
+
+    Ok(())
+}
+
+fn handle_connected(config: &Config, client_addr: SocketAddr, dst_addr: &Address, remote_addr: SocketAddr) {
+    config.event_handler.on_connect(client_addr, dst_addr);
+    if config.upstream_proxy.is_some() {
+        info!(config, "{} connected to {} via {}", client_addr, dst_addr, remote_addr);
+        return;
+    }
+    info!(config, "{} connected to {} ({})", client_addr, dst_addr, remote_addr);
+    config.event_handler.on_resolve(client_addr, dst_addr, remote_addr);
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_relay<R: AsyncRead + Unpin, W: AsyncWrite + Unpin, RR: AsyncRead + Unpin, RW: AsyncWrite + Unpin>(config: &Config, state: &ServerState, client_addr: SocketAddr, accepted_at: Instant, token: &ConnectionToken, dst_addr: &Address, mut client_reader: R, mut client_writer: W, mut remote_reader: RR, mut remote_writer: RW) -> Result<(), Error> {
+    let last_activity = Mutex::new(Instant::now());
+    let bytes_up = AtomicU64::new(0);
+    let bytes_down = AtomicU64::new(0);
+    let mut closed = RelayClosed {
+        config,
+        client_addr,
+        dst_addr,
+        started: Instant::now(),
+        bytes_up: &bytes_up,
+        bytes_down: &bytes_down,
+        close_reason: None,
+    };
+    let rate_limiter_up = config.rate_limit.map(RateLimiter::new);
+    let rate_limiter_down = match config.rate_limit_mode {
+        RateLimitMode::Independent => config.rate_limit.map(RateLimiter::new),
+        RateLimitMode::Shared => None,
+    };
+    let rate_limiter_down = rate_limiter_down.as_ref().or(rate_limiter_up.as_ref());
+    let rate_limiters_up: Vec<&RateLimiter> = rate_limiter_up.iter().chain(&state.global_rate_limiter).collect();
+    let rate_limiters_down: Vec<&RateLimiter> = rate_limiter_down.into_iter().chain(&state.global_rate_limiter).collect();
+    let relayed_up = [&bytes_up, &state.counters.total_bytes];
+    let relayed_down = [&bytes_down, &state.counters.total_bytes];
+
+    // A direction reaching EOF leaves the other running to completion, so it never truncates it;
+    // a direction failing cancels the other, which could otherwise wait forever on a peer that is
+    // still open, and both sockets close with this frame.
+    let relayed = async {
+        tokio::try_join!(
+            relay(config, &mut client_reader, &mut remote_writer, &last_activity, &relayed_up, |metrics, bytes| metrics.bytes_up(bytes), &rate_limiters_up),
+            relay(config, &mut remote_reader, &mut client_writer, &last_activity, &relayed_down, |metrics, bytes| metrics.bytes_down(bytes), &rate_limiters_down),
+        ).map(|_| ())
+    };
+
+    let idle = async {
+        match config.idle_timeout {
+            Some(idle_timeout) => {
+                wait_idle(idle_timeout, &last_activity).await;
+                Error::new(ErrorKind::TimedOut, format!("relay idle for {:?}", idle_timeout))
+            }
+            None => std::future::pending().await,
+        }
+    };
+    let expired = async {
+        match config.max_lifetime {
+            Some(max_lifetime) => {
+                tokio::time::sleep_until((accepted_at + max_lifetime).into()).await;
+                Error::new(ErrorKind::TimedOut, format!("relay exceeded max lifetime of {:?}", max_lifetime))
+            }
+            None => std::future::pending().await,
+        }
+    };
+
+    let (result, close_reason) = tokio::select! {
+        relayed = relayed => {
+            let close_reason = match &relayed {
+                Ok(()) => CloseReason::Eof,
+                Err(err) => CloseReason::Error(err.kind()),
+            };
+            (relayed, close_reason)
+        }
+        err = idle => (Err(err), CloseReason::IdleTimeout),
+        err = expired => (Err(err), CloseReason::MaxLifetime),
+        _ = token.cancelled() => (Ok(()), CloseReason::Cancelled),
+    };
+    closed.close_reason = Some(close_reason);
+    if CloseReason::MaxLifetime == close_reason {
+        // The lifetime is a hard cap, so send FIN without waiting on the peers to finish.
+        let _ = tokio::time::timeout(SHUTDOWN_TIMEOUT, async { tokio::join!(client_writer.shutdown(), remote_writer.shutdown()) }).await;
+    } else {
+        close_gracefully(&mut client_reader, &mut client_writer, &mut remote_reader, &mut remote_writer).await;
+    }
+    result
+}
+
+/// Send FIN to both peers and read off whatever they still send before the sockets are dropped,
+/// since closing a socket with unread data resets the connection and can truncate what the peer
+/// has yet to read. Gives up after `DRAIN_TIMEOUT`.
+async fn close_gracefully<R: AsyncRead + Unpin, W: AsyncWrite + Unpin, RR: AsyncRead + Unpin, RW: AsyncWrite + Unpin>(client_reader: &mut R, client_writer: &mut W, remote_reader: &mut RR, remote_writer: &mut RW) {
+    let close = async {
+        let _ = tokio::join!(client_writer.shutdown(), remote_writer.shutdown());
+        let (mut client_sink, mut remote_sink) = (tokio::io::sink(), tokio::io::sink());
+        let _ = tokio::join!(tokio::io::copy(client_reader, &mut client_sink), tokio::io::copy(remote_reader, &mut remote_sink));
+    };
+    let _ = tokio::time::timeout(DRAIN_TIMEOUT, close).await;
+}
+
+/// Lets `run_with_token` end a connection cooperatively: handshakes stop and relays close like a
+/// finished transfer instead of being aborted. Never fires without the `cancellation` feature.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ConnectionToken {
+    #[cfg(feature = "cancellation")]
+    token: Option<CancellationToken>,
+}
+
+impl ConnectionToken {
+    fn child(&self) -> Self {
+        ConnectionToken {
+            #[cfg(feature = "cancellation")]
+            token: self.token.as_ref().map(CancellationToken::child_token),
+        }
+    }
+
+    pub(crate) async fn cancelled(&self) {
+        #[cfg(feature = "cancellation")]
+        if let Some(token) = &self.token {
+            return token.cancelled().await;
+        }
+        std::future::pending().await
+    }
+}
+
+/// Reports a relay's totals through `on_close` and `on_summary` when dropped, even if the relay
+/// panicked or was aborted; a relay that never recorded why it ended counts as cancelled.
+struct RelayClosed<'a> {
+    config: &'a Config,
+    client_addr: SocketAddr,
+    dst_addr: &'a Address,
+    started: Instant,
+    bytes_up: &'a AtomicU64,
+    bytes_down: &'a AtomicU64,
+    close_reason: Option<CloseReason>,
+}
+
+impl Drop for RelayClosed<'_> {
+    fn drop(&mut self) {
+        let (bytes_up, bytes_down) = (self.bytes_up.load(Ordering::Relaxed), self.bytes_down.load(Ordering::Relaxed));
+        info!(self.config, "{} relayed {} bytes up, {} bytes down", self.client_addr, bytes_up, bytes_down);
+        self.config.event_handler.on_close(self.client_addr, bytes_up, bytes_down);
+        self.config.event_handler.on_summary(&ConnectionSummary {
+            client: self.client_addr,
+            target: self.dst_addr.clone(),
+            bytes_up,
+            bytes_down,
+            duration: self.started.elapsed(),
+            close_reason: self.close_reason.take().unwrap_or(CloseReason::Cancelled),
+        });
+    }
+}
+
+async fn relay<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(config: &Config, reader: &mut R, writer: &mut W, last_activity: &Mutex<Instant>, relayed: &[&AtomicU64], record: fn(&dyn Metrics, u64), rate_limiters: &[&RateLimiter]) -> Result<(), Error> {
+    let mut relay_buffer = vec![0u8; config.relay_buffer_size];
+    loop {
+        let relay_len = with_io_timeout(config, "read", read_coalesced(reader, &mut relay_buffer, config.coalesce_window)).await?;
+        if relay_len == 0 {
+            // Propagate the half-close and leave the other direction running until it sees EOF too.
+            return match writer.shutdown().await {
+                Err(err) if err.kind() != ErrorKind::NotConnected => Err(err),
+                _ => Ok(()),
+            };
+        }
+        for rate_limiter in rate_limiters {
+            rate_limiter.acquire(relay_len).await;
+        }
+        with_io_timeout(config, "write", writer.write_all(&relay_buffer[..relay_len])).await?;
+        for relayed in relayed {
+            relayed.fetch_add(relay_len as u64, Ordering::Relaxed);
+        }
+        record(config.metrics.as_ref(), relay_len as u64);
+        *last_activity.lock().unwrap() = Instant::now();
+    }
+}
+
+/// Read into `relay_buffer`, then top it up with whatever else arrives within `coalesce_window`.
+/// An EOF inside the window ends the batch early and is seen again by the next read.
+async fn read_coalesced<R: AsyncRead + Unpin>(reader: &mut R, relay_buffer: &mut [u8], coalesce_window: Option<Duration>) -> Result<usize, Error> {
+    let mut relay_len = reader.read(relay_buffer).await?;
+    let coalesce_window = match coalesce_window {
+        Some(coalesce_window) if relay_len != 0 => coalesce_window,
+        _ => return Ok(relay_len),
+    };
+    let deadline = tokio::time::Instant::now() + coalesce_window;
+    while relay_len < relay_buffer.len() {
+        match tokio::time::timeout_at(deadline, reader.read(&mut relay_buffer[relay_len..])).await {
+            Ok(Ok(0)) | Err(_) => break,
+            Ok(Ok(read_len)) => relay_len += read_len,
+            Ok(Err(err)) => return Err(err),
+        }
+    }
+    Ok(relay_len)
+}
+
+async fn with_io_timeout<T, F: Future<Output = Result<T, Error>>>(config: &Config, op: &str, io: F) -> Result<T, Error> {
+    match config.io_timeout {
+        Some(io_timeout) => tokio::time::timeout(io_timeout, io).await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, format!("relay {} blocked for {:?}", op, io_timeout)))?,
+        None => io.await,
+    }
+}
+
+async fn wait_idle(idle_timeout: Duration, last_activity: &Mutex<Instant>) {
+    loop {
+        let deadline = *last_activity.lock().unwrap() + idle_timeout;
+        if Instant::now() >= deadline {
+            return;
+        }
+        tokio::time::sleep_until(deadline.into()).await;
+    }
+}
+
+async fn handle_connect_tcp(config: &Arc<Config>, state: &Arc<ServerState>, client_addr: SocketAddr, dst_addr: &Address) -> Result<(OwnedReadHalf, OwnedWriteHalf), Error> {
+    let mut dst_addr = dst_addr.clone();
+    if let Some(rewriter) = &config.rewriter {
+        rewriter.rewrite(&mut dst_addr)?;
+    }
+    let pooled = ConnectionPool::take(config, state, &dst_addr);
+    let mut remote_stream = match pooled {
+        Some(remote_stream) => {
+            debug!(config, "{} using a spare connection to {}", client_addr, dst_addr);
+            remote_stream
+        }
+        None => dial(config, &dst_addr).await.inspect_err(|err| config.metrics.connect_failed(err.kind()))?,
+    };
+    configure_socket(config, &remote_stream)?;
+    if let Some(proxy_protocol) = config.proxy_protocol {
+        remote_stream.write_all(&proxy_protocol::encode_header(proxy_protocol, client_addr, remote_stream.peer_addr()?)).await?;
+    }
+    let (remote_reader, remote_writer) = remote_stream.into_split();
+    Ok((remote_reader, remote_writer))
+}
+
+/// Connect to an already rewritten `dst_addr`, retrying as configured.
+async fn dial(config: &Config, dst_addr: &Address) -> Result<TcpStream, Error> {
+    let connect = || async {
+        if let Some(upstream_proxy) = config.upstream_proxy {
+            return upstream::connect(config, upstream_proxy, dst_addr).await;
+        }
+        let remote_addrs = lookup_addresses(config, dst_addr).await?;
+        connect_happy_eyeballs(config, remote_addrs).await
+            .map_err(|err| match err {
+                Some(err) => SocksError::Upstream(err).into(),
+                None => Error::new(ErrorKind::HostUnreachable, format!("no addresses found for {}", dst_addr)),
+            })
+    };
+    let mut retries = config.connect_retries;
+    loop {
+        let connected = match config.connect_timeout {
+            Some(connect_timeout) => tokio::time::timeout(connect_timeout, connect()).await
+                .unwrap_or_else(|_| Err(SocksError::Upstream(Error::new(ErrorKind::TimedOut, format!("connect to {} timed out", dst_addr))).into())),
+            None => connect().await,
+        };
+        match connected {
+            Ok(remote_stream) => return Ok(remote_stream),
+            Err(err) if retries != 0 && is_retryable(&err) => {
+                debug!(config, "connect to {} failed, {} retries left: {}", dst_addr, retries, err);
+                retries -= 1;
+                tokio::time::sleep(config.retry_backoff).await;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn is_retryable(err: &Error) -> bool {
+    matches!(err.kind(), ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable | ErrorKind::TimedOut)
+}
+
+async fn connect_happy_eyeballs(config: &Config, remote_addrs: Vec<SocketAddr>) -> Result<TcpStream, Option<Error>> {
+    let mut remote_addrs = interleave_families(remote_addrs).into_iter();
+    let mut attempts: Vec<BoxFuture<'_, Result<TcpStream, Error>>> = Vec::new();
+    let mut last_err = None;
+    loop {
+        if attempts.is_empty() {
+            match remote_addrs.next() {
+                Some(remote_addr) => attempts.push(Box::pin(connect_remote(config, remote_addr))),
+                None => return Err(last_err),
+            }
+        }
+        tokio::select! {
+            (attempted, index) = poll_first(&mut attempts) => {
+                drop(attempts.swap_remove(index));
+                match attempted {
+                    Ok(remote_stream) => return Ok(remote_stream),
+                    Err(err) => last_err = Some(err),
+                }
+                if let Some(remote_addr) = remote_addrs.next() {
+                    attempts.push(Box::pin(connect_remote(config, remote_addr)));
+                }
+            }
+            _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY), if remote_addrs.len() != 0 => {
+                if let Some(remote_addr) = remote_addrs.next() {
+                    attempts.push(Box::pin(connect_remote(config, remote_addr)));
+                }
+            }
+        }
+    }
+}
+
+async fn poll_first<T>(attempts: &mut [BoxFuture<'_, T>]) -> (T, usize) {
+    std::future::poll_fn(|cx| {
+        for (index, attempt) in attempts.iter_mut().enumerate() {
+            if let Poll::Ready(attempted) = attempt.as_mut().poll(cx) {
+                return Poll::Ready((attempted, index));
+            }
+        }
+        Poll::Pending
+    }).await
+}
+
+fn interleave_families(remote_addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let prefer_ipv6 = remote_addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let remote_addrs_len = remote_addrs.len();
+    let (preferred, other): (Vec<SocketAddr>, Vec<SocketAddr>) = remote_addrs.into_iter().partition(|remote_addr| remote_addr.is_ipv6() == prefer_ipv6);
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    let mut interleaved = Vec::with_capacity(remote_addrs_len);
+    while interleaved.len() != remote_addrs_len {
+        interleaved.extend(preferred.next());
+        interleaved.extend(other.next());
+    }
+    interleaved
+}
+
+async fn connect_remote(config: &Config, remote_addr: SocketAddr) -> Result<TcpStream, Error> {
+    let bind_ip = match config.outbound_bind {
+        Some(bind_ip) => bind_ip,
+        None => return TcpStream::connect(remote_addr).await,
+    };
+    let remote_socket = match (bind_ip, remote_addr) {
+        (IpAddr::V4(_), SocketAddr::V4(_)) => TcpSocket::new_v4()?,
+        (IpAddr::V6(_), SocketAddr::V6(_)) => TcpSocket::new_v6()?,
+        _ => return Err(Error::new(ErrorKind::AddrNotAvailable, format!("outbound bind address {} cannot reach {}", bind_ip, remote_addr))),
+    };
+    remote_socket.bind(SocketAddr::new(bind_ip, 0))?;
+    remote_socket.connect(remote_addr).await
+}
+
+fn configure_socket(config: &Config, stream: &TcpStream) -> Result<(), Error> {
+    if let Some(tcp_keepalive) = config.tcp_keepalive {
+        SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(tcp_keepalive))?;
+    }
+    if config.nodelay {
+        stream.set_nodelay(true)?;
+    }
+    Ok(())
+}
+
+/// The IPs a BIND peer may connect from, or `None` for any the ruleset allows.
+async fn expected_bind_peers(config: &Config, dst_addr: &Address) -> Result<Option<Vec<IpAddr>>, Error> {
+    match dst_addr.target {
+        Target::Ipv4(ip) if ip.is_unspecified() => Ok(None),
+        Target::Ipv6(ip) if ip.is_unspecified() => Ok(None),
+        _ => Ok(Some(lookup_addresses(config, dst_addr).await?.iter().map(|remote_addr| remote_addr.ip().to_canonical()).collect())),
+    }
+}
+
+/// Accept the first inbound connection from an expected peer, closing any others.
+async fn handle_bind_accept(config: &Config, bind_socket: &TcpListener, expected_ips: Option<&[IpAddr]>) -> Result<(TcpStream, SocketAddr), Error> {
+    let accept_expected = async {
+        loop {
+            let (remote_stream, remote_addr) = bind_socket.accept().await?;
+            let remote_ip = remote_addr.ip().to_canonical();
+            let expected = match expected_ips {
+                Some(expected_ips) => expected_ips.contains(&remote_ip),
+                None => config.access_control.as_ref().is_none_or(|access_control| access_control.evaluate(None, remote_ip) == Action::Allow),
+            };
+            if expected {
+                return Ok((remote_stream, remote_addr));
+            }
+            warn!(config, "refused unexpected bind peer {}", remote_addr);
+        }
+    };
+    match config.bind_timeout {
+        Some(bind_timeout) => tokio::time::timeout(bind_timeout, accept_expected).await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, format!("no inbound connection within {:?}", bind_timeout)))?,
+        None => accept_expected.await,
+    }
+}
+
+/// Refuse domain targets longer than `max_domain_len`, counted after IDN conversion.
+fn check_domain_len(config: &Config, dst_addr: &Address) -> Result<(), Error> {
+    match &dst_addr.target {
+        Target::Domain(domain) if domain.len() > config.max_domain_len as usize => {
+            Err(SocksError::ProtocolViolation(format!("domain of {} bytes exceeds the limit of {}", domain.len(), config.max_domain_len)).into())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn parse_domain(domain: Vec<u8>) -> Result<String, Error> {
+    if domain.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "invalid empty domain"));
+    }
+    match String::from_utf8(domain) {
+        Ok(domain) => hostname::normalize(&domain),
+        Err(err) => Err(Error::new(ErrorKind::InvalidInput, format!("invalid domain {:?}", err.as_bytes()))),
+    }
+}
+
+async fn lookup_addresses(config: &Config, dst_addr: &Address) -> Result<Vec<SocketAddr>, Error> {
+    let (domain, mut remote_addrs) = match &dst_addr.target {
+        Target::Ipv4(ip) => (None, vec![SocketAddr::from((*ip, dst_addr.port))]),
+        Target::Ipv6(ip) => (None, vec![SocketAddr::from((*ip, dst_addr.port))]),
+        Target::Domain(domain) => {
+            let mut resolved_addrs = config.resolver.resolve(domain, dst_addr.port).await?;
+            let resolved_len = resolved_addrs.len();
+            resolved_addrs.retain(|resolved_addr| config.dns_family.allows(resolved_addr.ip()));
+            if resolved_len != 0 && resolved_addrs.is_empty() {
+                return Err(Error::new(ErrorKind::HostUnreachable, format!("no addresses of the allowed family found for {}", dst_addr)));
+            }
+            (Some(domain.as_str()), resolved_addrs)
+        }
+    };
+    for remote_addr in &mut remote_addrs {
+        if let SocketAddr::V6(remote_addr) = remote_addr {
+            if remote_addr.ip().is_unicast_link_local() && remote_addr.scope_id() == 0 {
+                let scope_id = config.ipv6_scope_id.ok_or_else(|| Error::new(ErrorKind::AddrNotAvailable, format!("link-local target {} requires an ipv6 scope id", dst_addr)))?;
+                *remote_addr = SocketAddrV6::new(*remote_addr.ip(), remote_addr.port(), remote_addr.flowinfo(), scope_id);
+            }
+        }
+    }
+    if config.block_private_addresses {
+        let resolved_len = remote_addrs.len();
+        remote_addrs.retain(|remote_addr| !is_special_use(remote_addr.ip()));
+        if resolved_len != 0 && remote_addrs.is_empty() {
+            return Err(Error::new(ErrorKind::PermissionDenied, format!("connection to {} not allowed, private address", dst_addr)));
+        }
+    }
+    if let Some(access_control) = &config.access_control {
+        let resolved_len = remote_addrs.len();
+        remote_addrs.retain(|remote_addr| access_control.evaluate(domain, remote_addr.ip()) == Action::Allow);
+        if resolved_len != 0 && remote_addrs.is_empty() {
+            return Err(Error::new(ErrorKind::PermissionDenied, format!("connection to {} not allowed by ruleset", dst_addr)));
+        }
+    }
+    Ok(remote_addrs)
+}
+
+fn is_special_use(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || a == 0
+                || a >= 240
+                || (a == 100 && (b & 0xC0) == 64)
+                || (a == 192 && b == 0 && c == 0)
+                || (a == 198 && (b & 0xFE) == 18)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_special_use(IpAddr::V4(ip)),
+            None => ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || ip.is_unique_local() || ip.is_unicast_link_local(),
+        },
+    }
+}
+
+#[cfg(feature = "udp")]
+async fn lookup_address(config: &Config, dst_addr: &Address) -> Result<SocketAddr, Error> {
+    lookup_addresses(config, dst_addr).await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::HostUnreachable, format!("no addresses found for {}", dst_addr)))
+}
+
+#[cfg(feature = "udp")]
+fn encode_socket_addr(buffer: &mut Vec<u8>, addr: SocketAddr) {
+    match addr {
+        SocketAddr::V4(addr) => {
+            buffer.push(ATYP_IPV4);
+            buffer.extend_from_slice(&addr.ip().octets());
+        }
+        SocketAddr::V6(addr) => {
+            buffer.push(ATYP_IPV6);
+            buffer.extend_from_slice(&addr.ip().octets());
+        }
+    }
+    buffer.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+fn encode_address(buffer: &mut Vec<u8>, addr: &Address) -> Result<(), Error> {
+    match &addr.target {
+        Target::Ipv4(ip) => {
+            buffer.push(ATYP_IPV4);
+            buffer.extend_from_slice(&ip.octets());
+        }
+        Target::Ipv6(ip) => {
+            buffer.push(ATYP_IPV6);
+            buffer.extend_from_slice(&ip.octets());
+        }
+        Target::Domain(domain) => {
+            if domain.len() > u8::MAX as usize {
+                return Err(Error::new(ErrorKind::InvalidInput, format!("domain {} too long", domain)));
+            }
+            buffer.push(ATYP_DOMAIN_NAME);
+            buffer.push(domain.len() as u8);
+            buffer.extend_from_slice(domain.as_bytes());
+        }
+    }
+    buffer.extend_from_slice(&addr.port.to_be_bytes());
+    Ok(())
+}
+
+/// The SOCKS5 reply code for a failed request: `PermissionDenied` is 0x02 (not allowed by
+/// ruleset), `NetworkUnreachable` 0x03, `HostUnreachable` and `TimedOut` 0x04, `ConnectionRefused`
+/// 0x05, and anything else 0x01 (general failure).
+///
+/// ```
+/// use std::io::{Error, ErrorKind};
+/// use socks_lib::reply_code_for;
+///
+/// assert_eq!(reply_code_for(&Error::from(ErrorKind::ConnectionRefused)), 0x05);
+/// assert_eq!(reply_code_for(&Error::from(ErrorKind::HostUnreachable)), 0x04);
+/// assert_eq!(reply_code_for(&Error::from(ErrorKind::TimedOut)), 0x04);
+/// assert_eq!(reply_code_for(&Error::other("boom")), 0x01);
+/// ```
+pub fn reply_code_for(err: &Error) -> u8 {
+    match err.kind() {
+        ErrorKind::PermissionDenied => REP_NOT_ALLOWED,
+        ErrorKind::ConnectionRefused => REP_CONNECTION_REFUSED,
+        ErrorKind::HostUnreachable | ErrorKind::TimedOut => REP_HOST_UNREACHABLE,
+        ErrorKind::NetworkUnreachable => REP_NETWORK_UNREACHABLE,
+        _ => REP_GENERAL_FAILURE,
+    }
+}
+
+async fn write_reply<W: AsyncWrite + Unpin>(client_writer: &mut W, rep: ReplyType, bnd_addr: &Address) -> Result<(), Error> {
+    client_writer.write_all(&Reply::new(rep, bnd_addr.clone()).encode()?).await?;
+    client_writer.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn coalesced_reads_stop_at_the_window_or_a_full_buffer() {
+        let (mut writer, mut reader) = tokio::io::duplex(64);
+        let writing = tokio::spawn(async move {
+            for chunk in [b"ab", b"cd", b"ef"] {
+                writer.write_all(chunk).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            writer
+        });
+        let mut buffer = [0u8; 4];
+        let read_len = read_coalesced(&mut reader, &mut buffer, Some(Duration::from_millis(500))).await.unwrap();
+        assert_eq!(&buffer[..read_len], b"abcd");
+        let writer = writing.await.unwrap();
+        let read_len = read_coalesced(&mut reader, &mut buffer, Some(Duration::from_millis(20))).await.unwrap();
+        assert_eq!(&buffer[..read_len], b"ef");
+        drop(writer);
+        assert_eq!(read_coalesced(&mut reader, &mut buffer, Some(Duration::from_millis(20))).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn reply_codes_follow_the_error_kind() {
+        assert_eq!(reply_code_for(&Error::from(ErrorKind::ConnectionRefused)), REP_CONNECTION_REFUSED);
+        assert_eq!(reply_code_for(&Error::from(ErrorKind::HostUnreachable)), REP_HOST_UNREACHABLE);
+        assert_eq!(reply_code_for(&Error::from(ErrorKind::TimedOut)), REP_HOST_UNREACHABLE);
+        assert_eq!(reply_code_for(&Error::from(ErrorKind::NetworkUnreachable)), REP_NETWORK_UNREACHABLE);
+        assert_eq!(reply_code_for(&Error::from(ErrorKind::PermissionDenied)), REP_NOT_ALLOWED);
+        assert_eq!(reply_code_for(&Error::from(ErrorKind::BrokenPipe)), REP_GENERAL_FAILURE);
+        assert_eq!(reply_code_for(&Error::other("boom")), REP_GENERAL_FAILURE);
+        // Wrapped upstream failures map by the kind they carry.
+        let upstream = Error::from(SocksError::Upstream(Error::from(ErrorKind::ConnectionRefused)));
+        assert_eq!(reply_code_for(&upstream), REP_CONNECTION_REFUSED);
+    }
+
+    #[test]
+    fn address_keeps_each_target_kind() {
+        let v4 = Address::from(SocketAddr::from(([10, 0, 0, 1], 80)));
+        assert_eq!(v4.target(), &Target::Ipv4(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(v4.to_string(), "10.0.0.1:80");
+
+        let v6 = Address::from(SocketAddr::from((Ipv6Addr::LOCALHOST, 443)));
+        assert_eq!(v6.target(), &Target::Ipv6(Ipv6Addr::LOCALHOST));
+        assert_eq!(v6.to_string(), "[::1]:443");
+
+        let domain = Address::new(Target::Domain("example.com".to_string()), 8080);
+        assert_eq!(domain.host(), "example.com");
+        assert_eq!(domain.port(), 8080);
+        assert_eq!(domain.to_string(), "example.com:8080");
+    }
+
+    #[test]
+    fn builder_starts_from_defaults() {
+        let config = Config::builder().build();
+        assert_eq!((config.local_addr.as_str(), config.local_port), (DEFAULT_LOCAL_ADDR, DEFAULT_LOCAL_PORT));
+        assert_eq!(config.connect_timeout, Some(DEFAULT_CONNECT_TIMEOUT));
+        assert_eq!(config.bind_timeout, Some(DEFAULT_BIND_TIMEOUT));
+        assert_eq!(config.idle_timeout, None);
+        assert_eq!(config.max_connections, None);
+        assert_eq!(config.limit_policy, LimitPolicy::Wait);
+        assert!(config.credentials.is_empty());
+
+        let config = Config::builder().local_port(1081).idle_timeout(Some(Duration::from_secs(1))).build();
+        assert_eq!(config.local_port, 1081);
+        assert_eq!(config.idle_timeout, Some(Duration::from_secs(1)));
+        assert_eq!(config.connect_timeout, Some(DEFAULT_CONNECT_TIMEOUT));
+    }
+
+    #[tokio::test]
+    async fn accept_any_takes_turns_between_ready_listeners() {
+        let first = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let second = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let first_addr = first.local_addr().unwrap();
+        let second_addr = second.local_addr().unwrap();
+        let _clients = [
+            TcpStream::connect(first_addr).await.unwrap(),
+            TcpStream::connect(first_addr).await.unwrap(),
+            TcpStream::connect(second_addr).await.unwrap(),
+        ];
+        let server_sockets = [Listener::Tcp(first), Listener::Tcp(second)];
+        let mut next_listener = 0;
+        let mut accepted_on = Vec::new();
+        for _ in 0..3 {
+            let (client_stream, _) = accept_any(&server_sockets, &mut next_listener).await.unwrap();
+            accepted_on.push(client_stream.local_addr().unwrap());
+        }
+        assert_eq!(accepted_on, [first_addr, second_addr, first_addr]);
+    }
+
+    async fn connect_configured(config: Config) -> OwnedWriteHalf {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dst_addr = Address::from(target.local_addr().unwrap());
+        let state = Arc::new(ServerState::new(&config));
+        let (_, remote_writer) = handle_connect_tcp(&Arc::new(config), &state, UNSPECIFIED_ADDR, &dst_addr).await.unwrap();
+        remote_writer
+    }
+
+    #[tokio::test]
+    async fn keepalive_is_set_on_outbound_sockets() {
+        let remote_writer = connect_configured(Config::builder().tcp_keepalive(Some(Duration::from_secs(30))).build()).await;
+        let socket = SockRef::from(remote_writer.as_ref());
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+
+        let remote_writer = connect_configured(Config::builder().build()).await;
+        assert!(!SockRef::from(remote_writer.as_ref()).keepalive().unwrap());
+    }
+
+    #[tokio::test]
+    async fn nodelay_is_set_on_outbound_sockets() {
+        let remote_writer = connect_configured(Config::builder().nodelay(true).build()).await;
+        assert!(remote_writer.as_ref().nodelay().unwrap());
+
+        let remote_writer = connect_configured(Config::builder().build()).await;
+        assert!(!remote_writer.as_ref().nodelay().unwrap());
+    }
+
+    #[tokio::test]
+    async fn link_local_targets_take_the_configured_scope_id() {
+        let dst_addr = Address::new(Target::Ipv6("fe80::1".parse().unwrap()), 80);
+        let err = lookup_addresses(&Config::builder().build(), &dst_addr).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AddrNotAvailable);
+
+        let remote_addrs = lookup_addresses(&Config::builder().ipv6_scope_id(Some(4)).build(), &dst_addr).await.unwrap();
+        assert_eq!(remote_addrs, [SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().unwrap(), 80, 0, 4))]);
+    }
+
+    #[test]
+    fn special_use_ranges_are_recognized() {
+        for ip in ["10.1.2.3", "172.16.0.1", "192.168.1.1", "127.0.0.1", "169.254.0.1", "100.64.0.1", "0.0.0.0", "240.0.0.1", "::1", "fc00::1", "fe80::1", "::ffff:10.0.0.1"] {
+            assert!(is_special_use(ip.parse().unwrap()), "{} is special-use", ip);
+        }
+        for ip in ["8.8.8.8", "203.0.113.1", "2001:4860::8888", "::ffff:1.1.1.1"] {
+            assert!(!is_special_use(ip.parse().unwrap()), "{} is public", ip);
+        }
+    }
+
+    #[test]
+    fn dial_order_alternates_families() {
+        let addrs: Vec<SocketAddr> = ["[::1]:80", "[::2]:80", "[::3]:80", "127.0.0.1:80"].iter().map(|addr| addr.parse().unwrap()).collect();
+        let interleaved = interleave_families(addrs.clone());
+        assert_eq!(interleaved, [addrs[0], addrs[3], addrs[1], addrs[2]]);
+    }
+
+    /// Serve one client over an in-memory pipe, returning the client end and the handler's result.
+    fn serve_in_memory(config: Config) -> (tokio::io::DuplexStream, JoinHandle<Result<(), Error>>) {
+        let (client_stream, server_stream) = tokio::io::duplex(HANDSHAKE_BUFFER_LEN);
+        let (client_reader, client_writer) = tokio::io::split(server_stream);
+        let client_addr = SocketAddr::from(([192, 0, 2, 1], 50000));
+        let server_addr = SocketAddr::from(([127, 0, 0, 1], 1080));
+        let state = Arc::new(ServerState::new(&config));
+        let handler = tokio::spawn(async move { handle_connection(Arc::new(config), state, client_addr, server_addr, Instant::now(), &ConnectionToken::default(), client_reader, client_writer).await });
+        (client_stream, handler)
+    }
+
+    async fn read_vec<R: AsyncRead + Unpin>(reader: &mut R, len: usize) -> Vec<u8> {
+        let mut buffer = vec![0u8; len];
+        reader.read_exact(&mut buffer).await.unwrap();
+        buffer
+    }
+
+    #[tokio::test]
+    async fn in_memory_connect_relays_to_target() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let (mut client, handler) = serve_in_memory(Config::builder().build());
+
+        client.write_all(&[VERSION, 1, METHOD_NO_AUTH]).await.unwrap();
+        assert_eq!(read_vec(&mut client, 2).await, [VERSION, METHOD_NO_AUTH]);
+        let mut request = vec![VERSION, CMD_CONNECT, 0];
+        encode_address(&mut request, &target_addr.into()).unwrap();
+        client.write_all(&request).await.unwrap();
+        let (mut remote, _) = target.accept().await.unwrap();
+        assert_eq!(read_vec(&mut client, 10).await[..4], [VERSION, REP_SUCCEEDED, 0, ATYP_IPV4]);
+
+        client.write_all(b"ping").await.unwrap();
+        assert_eq!(read_vec(&mut remote, 4).await, b"ping");
+        remote.write_all(b"pong").await.unwrap();
+        assert_eq!(read_vec(&mut client, 4).await, b"pong");
+        drop(remote);
+        drop(client);
+        handler.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn in_memory_auth_failure_is_returned() {
+        let (mut client, handler) = serve_in_memory(Config::builder().auth("user", "password").require_auth(true).build());
+
+        client.write_all(&[VERSION, 1, METHOD_USERNAME_PASSWORD]).await.unwrap();
+        assert_eq!(read_vec(&mut client, 2).await, [VERSION, METHOD_USERNAME_PASSWORD]);
+        client.write_all(b"\x01\x04user\x05wrong").await.unwrap();
+        assert_eq!(read_vec(&mut client, 2).await, [AUTH_VERSION, AUTH_FAILED]);
+        let err = handler.await.unwrap().unwrap_err();
+        assert!(matches!(SocksError::from_io(&err), Some(SocksError::AuthFailed)));
+    }
+
+    #[tokio::test]
+    async fn in_memory_unknown_version_is_returned() {
+        let (mut client, handler) = serve_in_memory(Config::builder().build());
+
+        client.write_all(&[6, 1, METHOD_NO_AUTH]).await.unwrap();
+        let err = handler.await.unwrap().unwrap_err();
+        assert!(matches!(SocksError::from_io(&err), Some(SocksError::UnsupportedVersion(6))));
+    }
+
+    #[tokio::test]
+    async fn in_memory_unacceptable_methods_are_returned() {
+        let (mut client, handler) = serve_in_memory(Config::builder().build());
+
+        client.write_all(&[VERSION, 1, METHOD_USERNAME_PASSWORD]).await.unwrap();
+        assert_eq!(read_vec(&mut client, 2).await, [VERSION, METHOD_NO_ACCEPTABLE]);
+        let err = handler.await.unwrap().unwrap_err();
+        assert!(matches!(SocksError::from_io(&err), Some(SocksError::NoAcceptableMethods(methods)) if methods == &[METHOD_USERNAME_PASSWORD]));
+    }
+
+    #[tokio::test]
+    async fn in_memory_nonzero_rsv_is_a_protocol_violation() {
+        let (mut client, handler) = serve_in_memory(Config::builder().build());
+
+        client.write_all(&[VERSION, 1, METHOD_NO_AUTH]).await.unwrap();
+        assert_eq!(read_vec(&mut client, 2).await, [VERSION, METHOD_NO_AUTH]);
+        client.write_all(&[VERSION, CMD_CONNECT, 1, ATYP_IPV4, 127, 0, 0, 1, 0, 80]).await.unwrap();
+        let err = handler.await.unwrap().unwrap_err();
+        assert!(matches!(SocksError::from_io(&err), Some(SocksError::ProtocolViolation(_))));
+    }
+
+    #[tokio::test]
+    async fn in_memory_refused_connect_is_an_upstream_error() {
+        let target_addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (mut client, handler) = serve_in_memory(Config::builder().build());
+
+        client.write_all(&[VERSION, 1, METHOD_NO_AUTH]).await.unwrap();
+        assert_eq!(read_vec(&mut client, 2).await, [VERSION, METHOD_NO_AUTH]);
+        let mut request = vec![VERSION, CMD_CONNECT, 0];
+        encode_address(&mut request, &target_addr.into()).unwrap();
+        client.write_all(&request).await.unwrap();
+        assert_eq!(read_vec(&mut client, 10).await[..2], [VERSION, REP_CONNECTION_REFUSED]);
+        let err = handler.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+        assert!(matches!(SocksError::from_io(&err), Some(SocksError::Upstream(_))));
+    }
+
+    #[tokio::test]
+    async fn in_memory_request_survives_one_byte_writes() {
+        let target = TcpListener::bind("[::1]:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let (mut client, handler) = serve_in_memory(Config::builder().build());
+
+        client.write_all(&[VERSION, 1, METHOD_NO_AUTH]).await.unwrap();
+        assert_eq!(read_vec(&mut client, 2).await, [VERSION, METHOD_NO_AUTH]);
+        let mut request = vec![VERSION, CMD_CONNECT, 0];
+        encode_address(&mut request, &target_addr.into()).unwrap();
+        for byte in request {
+            client.write_all(&[byte]).await.unwrap();
+            client.flush().await.unwrap();
+            tokio::task::yield_now().await;
+        }
+        let (remote, _) = target.accept().await.unwrap();
+        assert_eq!(read_vec(&mut client, 4).await, [VERSION, REP_SUCCEEDED, 0, ATYP_IPV6]);
+        drop(remote);
+        drop(client);
+        handler.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn debug_omits_passwords() {
+        let config = Config::builder().auth("user", "hunter2").upstream_auth("parent", "swordfish").build();
+        let debug = format!("{:?}", config);
+        assert!(debug.contains("user") && debug.contains("parent"));
+        assert!(!debug.contains("hunter2") && !debug.contains("swordfish"));
+    }
 }
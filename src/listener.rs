@@ -0,0 +1,133 @@
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::{configure_socket, Config, UNSPECIFIED_ADDR};
+
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub(crate) fn local_addr(&self) -> Result<SocketAddr, Error> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr(),
+            #[cfg(unix)]
+            Listener::Unix(_) => Err(Error::new(ErrorKind::Unsupported, "unix listener has no socket address")),
+        }
+    }
+
+    /// Unix clients have no socket address and are reported as `0.0.0.0:0`.
+    pub(crate) fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<Result<(ClientStream, SocketAddr), Error>> {
+        match self {
+            Listener::Tcp(listener) => listener.poll_accept(cx).map_ok(|(stream, client_addr)| (ClientStream::Tcp(stream), client_addr)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.poll_accept(cx).map_ok(|(stream, _)| (ClientStream::Unix(stream), UNSPECIFIED_ADDR)),
+        }
+    }
+}
+
+pub(crate) enum ClientStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl ClientStream {
+    pub(crate) fn configure(&self, config: &Config) -> Result<(), Error> {
+        match self {
+            ClientStream::Tcp(stream) => configure_socket(config, stream),
+            #[cfg(unix)]
+            ClientStream::Unix(_) => Ok(()),
+        }
+    }
+
+    pub(crate) fn local_addr(&self) -> Result<SocketAddr, Error> {
+        match self {
+            ClientStream::Tcp(stream) => stream.local_addr(),
+            #[cfg(unix)]
+            ClientStream::Unix(_) => Ok(UNSPECIFIED_ADDR),
+        }
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<Result<(), Error>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn tcp_clients_keep_their_address() {
+        let listener = Listener::Tcp(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (client_stream, client_addr) = poll_fn(|cx| listener.poll_accept(cx)).await.unwrap();
+        assert_eq!(client_addr, client.local_addr().unwrap());
+        assert_eq!(client_stream.local_addr().unwrap(), client.peer_addr().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_clients_are_reported_as_unspecified() {
+        let path = std::env::temp_dir().join(format!("socks-lib-{}-listener.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = Listener::Unix(UnixListener::bind(&path).unwrap());
+        assert_eq!(listener.local_addr().unwrap_err().kind(), ErrorKind::Unsupported);
+
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        let (mut client_stream, client_addr) = poll_fn(|cx| listener.poll_accept(cx)).await.unwrap();
+        assert_eq!(client_addr, UNSPECIFIED_ADDR);
+        assert_eq!(client_stream.local_addr().unwrap(), UNSPECIFIED_ADDR);
+        assert!(client_stream.configure(&Config::builder().nodelay(true).build()).is_ok());
+
+        client.write_all(b"ping").await.unwrap();
+        let mut received = [0u8; 4];
+        client_stream.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,70 @@
+use std::io::ErrorKind;
+
+/// Sink for connection and throughput counters, to be bridged to whatever metrics library the application uses.
+pub trait Metrics: Send + Sync {
+    fn connection_opened(&self) {}
+
+    fn connection_closed(&self) {}
+
+    /// Client to remote bytes, reported as each chunk or datagram is relayed.
+    fn bytes_up(&self, _bytes: u64) {}
+
+    /// Remote to client bytes, reported as each chunk or datagram is relayed.
+    fn bytes_down(&self, _bytes: u64) {}
+
+    /// Called when dialing a CONNECT target fails, with the kind of the final error.
+    fn connect_failed(&self, _reason: ErrorKind) {}
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::{relay, Config};
+
+    #[derive(Default)]
+    struct Counting {
+        up: AtomicU64,
+        down: AtomicU64,
+    }
+
+    impl Metrics for Counting {
+        fn bytes_up(&self, bytes: u64) {
+            self.up.fetch_add(bytes, Ordering::Relaxed);
+        }
+
+        fn bytes_down(&self, bytes: u64) {
+            self.down.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    #[tokio::test]
+    async fn relay_reports_bytes_to_its_direction() {
+        let metrics = Arc::new(Counting::default());
+        let config = Config::builder().metrics(metrics.clone()).build();
+        let (mut client, mut reader) = tokio::io::duplex(64);
+        let (mut writer, mut target) = tokio::io::duplex(64);
+        let relayed = AtomicU64::new(0);
+        let last_activity = Mutex::new(Instant::now());
+
+        client.write_all(b"hello").await.unwrap();
+        drop(client);
+        relay(&config, &mut reader, &mut writer, &last_activity, &[&relayed], |metrics, bytes| metrics.bytes_up(bytes), &[]).await.unwrap();
+
+        let mut received = Vec::new();
+        target.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello");
+        assert_eq!(relayed.load(Ordering::Relaxed), 5);
+        assert_eq!(metrics.up.load(Ordering::Relaxed), 5);
+        assert_eq!(metrics.down.load(Ordering::Relaxed), 0);
+    }
+}
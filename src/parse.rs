@@ -0,0 +1,229 @@
+use std::io::{Error, ErrorKind};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{parse_domain, Address, CmdType, MethodType, Reply, SocksError, Target};
+use crate::{ATYP_DOMAIN_NAME, ATYP_IPV4, ATYP_IPV6, CMD_ASSOCIATE, CMD_BIND, CMD_CONNECT, REP_COMMAND_NOT_SUPPORTED, VERSION};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Connect,
+    Bind,
+    UdpAssociate,
+}
+
+impl TryFrom<u8> for Command {
+    /// The reply code for an unknown command, `command not supported`.
+    type Error = u8;
+
+    fn try_from(cmd: u8) -> Result<Self, Self::Error> {
+        match cmd {
+            CMD_CONNECT => Ok(Command::Connect),
+            CMD_BIND => Ok(Command::Bind),
+            CMD_ASSOCIATE => Ok(Command::UdpAssociate),
+            _ => Err(REP_COMMAND_NOT_SUPPORTED),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    command: CmdType,
+    address: Address,
+}
+
+impl Request {
+    /// The requested command, or the raw CMD byte if it is not one this crate knows.
+    pub fn command(&self) -> Result<Command, u8> {
+        Command::try_from(self.command).map_err(|_| self.command)
+    }
+
+    pub fn address(&self) -> &Address {
+        &self.address
+    }
+}
+
+/// Read a SOCKS5 greeting and return the offered authentication methods. A greeting offering
+/// no methods is rejected as a protocol violation.
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> std::io::Result<()> {
+/// let mut frame: &[u8] = &[5, 2, 0, 2];
+/// assert_eq!(socks_lib::parse_greeting(&mut frame).await?, vec![0, 2]);
+/// # Ok(())
+/// # }
+/// ```
+pub async fn parse_greeting<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>, Error> {
+    let ver = reader.read_u8().await?;
+    if VERSION != ver {
+        return Err(SocksError::UnsupportedVersion(ver).into());
+    }
+    parse_methods(reader).await
+}
+
+pub(crate) async fn parse_methods<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<MethodType>, Error> {
+    let n_method = reader.read_u8().await?;
+    if n_method == 0 {
+        return Err(SocksError::ProtocolViolation("greeting offered no methods".to_string()).into());
+    }
+    let mut methods = vec![0u8; n_method as usize];
+    reader.read_exact(&mut methods).await?;
+    Ok(methods)
+}
+
+/// Read a SOCKS5 request: the command and the destination address. A nonzero reserved byte is
+/// rejected as a protocol violation.
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> std::io::Result<()> {
+/// use socks_lib::{Command, Target};
+///
+/// let mut frame: &[u8] = &[5, 1, 0, 3, 11, b'e', b'x', b'a', b'm', b'p', b'l', b'e', b'.', b'o', b'r', b'g', 0, 80];
+/// let request = socks_lib::parse_request(&mut frame).await?;
+/// assert_eq!(request.command(), Ok(Command::Connect));
+/// assert_eq!(request.address().target(), &Target::Domain("example.org".to_string()));
+/// assert_eq!(request.address().port(), 80);
+/// # Ok(())
+/// # }
+/// ```
+pub async fn parse_request<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Request, Error> {
+    let ver = reader.read_u8().await?;
+    if VERSION != ver {
+        return Err(SocksError::UnsupportedVersion(ver).into());
+    }
+    let command = reader.read_u8().await?;
+    let rsv = reader.read_u8().await?;
+    if rsv != 0 {
+        return Err(SocksError::ProtocolViolation(format!("invalid nonzero rsv value {}", rsv)).into());
+    }
+    let address = parse_address(reader).await?;
+    Ok(Request {
+        command,
+        address,
+    })
+}
+
+/// Read a SOCKS5 reply, as sent by a proxy in answer to a request; see [`Reply`].
+pub async fn parse_reply<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Reply, Error> {
+    let ver = reader.read_u8().await?;
+    if VERSION != ver {
+        return Err(SocksError::UnsupportedVersion(ver).into());
+    }
+    let code = reader.read_u8().await?;
+    let _rsv = reader.read_u8().await?;
+    let bnd_addr = parse_address(reader).await?;
+    Ok(Reply::new(code, bnd_addr))
+}
+
+/// Read an ATYP-prefixed address and port, as found in requests and UDP headers.
+///
+/// Fields are read with `read_exact`, so fragmented input parses the same as a whole frame.
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> std::io::Result<()> {
+/// use std::net::Ipv6Addr;
+/// use socks_lib::Target;
+/// use tokio::io::AsyncWriteExt;
+///
+/// let (mut client, mut server) = tokio::io::duplex(1);
+/// tokio::spawn(async move {
+///     let mut frame = vec![4];
+///     frame.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+///     frame.extend_from_slice(&443u16.to_be_bytes());
+///     for byte in frame {
+///         client.write_all(&[byte]).await.unwrap();
+///     }
+/// });
+/// let address = socks_lib::parse_address(&mut server).await?;
+/// assert_eq!(address.target(), &Target::Ipv6(Ipv6Addr::LOCALHOST));
+/// assert_eq!(address.port(), 443);
+/// # Ok(())
+/// # }
+/// ```
+pub async fn parse_address<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Address, Error> {
+    let atyp = reader.read_u8().await?;
+    let target = match atyp {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            reader.read_exact(&mut octets).await?;
+            Target::Ipv4(Ipv4Addr::from(octets))
+        }
+        ATYP_DOMAIN_NAME => {
+            let dst_addr_len: u8 = reader.read_u8().await?;
+            let mut dst_addr = vec![0u8; dst_addr_len as usize];
+            reader.read_exact(&mut dst_addr).await?;
+            Target::Domain(parse_domain(dst_addr)?)
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            reader.read_exact(&mut octets).await?;
+            Target::Ipv6(Ipv6Addr::from(octets))
+        }
+        _ => {
+            return Err(Error::new(ErrorKind::Unsupported, format!("invalid atyp value {}", atyp)));
+        }
+    };
+    let dst_port = reader.read_u16().await?;
+    Ok(Address::new(target, dst_port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn parses_requests_for_each_address_type() {
+        let mut frame: &[u8] = &[5, 1, 0, 1, 10, 0, 0, 1, 0, 80];
+        let request = parse_request(&mut frame).await.unwrap();
+        assert_eq!(request.command(), Ok(Command::Connect));
+        assert_eq!(request.address(), &Address::new(Target::Ipv4(Ipv4Addr::new(10, 0, 0, 1)), 80));
+
+        let mut frame = vec![5, 3, 0, 4];
+        frame.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        frame.extend_from_slice(&[1, 187]);
+        let request = parse_request(&mut frame.as_slice()).await.unwrap();
+        assert_eq!(request.command(), Ok(Command::UdpAssociate));
+        assert_eq!(request.address(), &Address::new(Target::Ipv6(Ipv6Addr::LOCALHOST), 443));
+    }
+
+    #[tokio::test]
+    async fn unknown_commands_keep_their_byte() {
+        let mut frame: &[u8] = &[5, 9, 0, 1, 10, 0, 0, 1, 0, 80];
+        let request = parse_request(&mut frame).await.unwrap();
+        assert_eq!(request.command(), Err(9));
+        assert_eq!(request.address().port(), 80);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_frames() {
+        let err = parse_greeting(&mut &[5, 0][..]).await.unwrap_err();
+        assert!(matches!(SocksError::from_io(&err), Some(SocksError::ProtocolViolation(_))));
+        let err = parse_request(&mut &[4, 1, 0, 1, 10, 0, 0, 1, 0, 80][..]).await.unwrap_err();
+        assert!(matches!(SocksError::from_io(&err), Some(SocksError::UnsupportedVersion(4))));
+        let err = parse_request(&mut &[5, 1, 1, 1, 10, 0, 0, 1, 0, 80][..]).await.unwrap_err();
+        assert!(matches!(SocksError::from_io(&err), Some(SocksError::ProtocolViolation(_))));
+        let err = parse_address(&mut &[9, 0, 0][..]).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        let err = parse_address(&mut &[1, 10, 0][..]).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn parses_replies() {
+        let mut frame: &[u8] = &[5, 5, 0, 1, 0, 0, 0, 0, 0, 0];
+        let reply = parse_reply(&mut frame).await.unwrap();
+        assert_eq!(reply.code(), 5);
+        assert_eq!(reply.bnd_addr(), &Address::new(Target::Ipv4(Ipv4Addr::UNSPECIFIED), 0));
+    }
+
+    #[test]
+    fn maps_command_bytes() {
+        assert_eq!(Command::try_from(CMD_CONNECT), Ok(Command::Connect));
+        assert_eq!(Command::try_from(CMD_BIND), Ok(Command::Bind));
+        assert_eq!(Command::try_from(CMD_ASSOCIATE), Ok(Command::UdpAssociate));
+        assert_eq!(Command::try_from(9), Err(REP_COMMAND_NOT_SUPPORTED));
+    }
+}
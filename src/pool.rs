@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::mem::MaybeUninit;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use socket2::SockRef;
+use tokio::net::TcpStream;
+
+use crate::{dial, Address, Config, ServerState};
+
+/// Spare connections to recently used CONNECT targets, dialed ahead of time so the next CONNECT
+/// to the same target skips the connect. A spare is only ever handed to one tunnel.
+#[derive(Debug)]
+pub(crate) struct ConnectionPool {
+    max_size: usize,
+    idle_timeout: Duration,
+    targets: Mutex<HashMap<Address, Spares>>,
+}
+
+/// The spares of one target and what is known about its traffic.
+#[derive(Debug, Default)]
+struct Spares {
+    idle: Vec<(TcpStream, Instant)>,
+    pending: usize,
+    last_connect: Option<Instant>,
+}
+
+impl ConnectionPool {
+    pub(crate) fn new(max_size: usize, idle_timeout: Duration) -> Self {
+        ConnectionPool {
+            max_size,
+            idle_timeout,
+            targets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a spare to `dst_addr` for a CONNECT, dialing its replacement in the background.
+    pub(crate) fn take(config: &Arc<Config>, state: &Arc<ServerState>, dst_addr: &Address) -> Option<TcpStream> {
+        let pool = state.connection_pool.as_ref()?;
+        let (spare, refill) = pool.checkout(dst_addr);
+        if refill {
+            let config = config.clone();
+            let state = state.clone();
+            let dst_addr = dst_addr.clone();
+            tokio::spawn(async move {
+                let dialed = dial(&config, &dst_addr).await;
+                let pool = state.connection_pool.as_ref().unwrap();
+                if let Err(err) = &dialed {
+                    debug!(config, "spare connection to {} failed: {}", dst_addr, err);
+                }
+                pool.dialed(&dst_addr, dialed.ok());
+            });
+        }
+        spare
+    }
+
+    /// Pop a spare to `dst_addr` that is neither expired nor closed by the target, and decide
+    /// whether to dial one: to replace a spare that was taken, or to start the target off once two
+    /// CONNECTs to it came within `idle_timeout`. Targets tunneled to less often than that never
+    /// get spares, which would only expire unused.
+    fn checkout(&self, dst_addr: &Address) -> (Option<TcpStream>, bool) {
+        let mut targets = self.targets.lock().unwrap();
+        let now = Instant::now();
+        self.prune(&mut targets, now);
+        let spares = targets.entry(dst_addr.clone()).or_default();
+        let mut taken = None;
+        while let Some((spare, opened_at)) = spares.idle.pop() {
+            if now.duration_since(opened_at) < self.idle_timeout && is_open(&spare) {
+                taken = Some(spare);
+                break;
+            }
+        }
+        let recent = spares.last_connect.is_some_and(|last_connect| now.duration_since(last_connect) < self.idle_timeout);
+        spares.last_connect = Some(now);
+        let wanted = taken.is_some() || (recent && spares.idle.is_empty() && spares.pending == 0);
+        let refill = wanted && spares.idle.len() + spares.pending < self.max_size;
+        if refill {
+            spares.pending += 1;
+        }
+        (taken, refill)
+    }
+
+    /// Record the outcome of a dial started by `checkout`.
+    fn dialed(&self, dst_addr: &Address, spare: Option<TcpStream>) {
+        let mut targets = self.targets.lock().unwrap();
+        if let Some(spares) = targets.get_mut(dst_addr) {
+            spares.pending -= 1;
+        }
+        if let Some(spare) = spare {
+            drop(targets);
+            self.put(dst_addr.clone(), spare);
+        }
+    }
+
+    fn put(&self, dst_addr: Address, spare: TcpStream) {
+        let mut targets = self.targets.lock().unwrap();
+        let now = Instant::now();
+        self.prune(&mut targets, now);
+        let spares = targets.entry(dst_addr).or_default();
+        if spares.idle.len() < self.max_size {
+            spares.idle.push((spare, now));
+        }
+    }
+
+    /// Drop expired spares, and forget targets with nothing pooled and no recent CONNECT.
+    fn prune(&self, targets: &mut HashMap<Address, Spares>, now: Instant) {
+        targets.retain(|_, spares| {
+            spares.idle.retain(|(_, opened_at)| now.duration_since(*opened_at) < self.idle_timeout);
+            let recent = spares.last_connect.is_some_and(|last_connect| now.duration_since(last_connect) < self.idle_timeout);
+            !spares.idle.is_empty() || spares.pending != 0 || recent
+        });
+    }
+}
+
+fn is_open(stream: &TcpStream) -> bool {
+    // Data the target sent first stays queued for the tunnel; only EOF or an error marks it dead.
+    match SockRef::from(stream).peek(&mut [MaybeUninit::uninit()]) {
+        Ok(peeked) => peeked != 0,
+        Err(err) => err.kind() == ErrorKind::WouldBlock,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// A connected pair: the pooled end and the target's end.
+    async fn connection(target: &TcpListener) -> (TcpStream, TcpStream) {
+        let spare = TcpStream::connect(target.local_addr().unwrap()).await.unwrap();
+        (spare, target.accept().await.unwrap().0)
+    }
+
+    fn address(port: u16) -> Address {
+        std::net::SocketAddr::from(([127, 0, 0, 1], port)).into()
+    }
+
+    #[tokio::test]
+    async fn spares_are_taken_once_per_target() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool = ConnectionPool::new(2, Duration::from_secs(60));
+        let (spare, _remote) = connection(&target).await;
+        let spare_addr = spare.local_addr().unwrap();
+
+        pool.put(address(1), spare);
+        assert!(pool.checkout(&address(2)).0.is_none());
+        assert_eq!(pool.checkout(&address(1)).0.unwrap().local_addr().unwrap(), spare_addr);
+        assert!(pool.checkout(&address(1)).0.is_none());
+    }
+
+    #[tokio::test]
+    async fn spares_past_max_size_are_dropped() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool = ConnectionPool::new(1, Duration::from_secs(60));
+        let (first, _first_remote) = connection(&target).await;
+        let (second, _second_remote) = connection(&target).await;
+        let (other, _other_remote) = connection(&target).await;
+
+        pool.put(address(1), first);
+        pool.put(address(1), second);
+        pool.put(address(2), other);
+        assert!(pool.checkout(&address(1)).0.is_some());
+        assert!(pool.checkout(&address(1)).0.is_none());
+        assert!(pool.checkout(&address(2)).0.is_some());
+    }
+
+    #[test]
+    fn spares_are_dialed_for_taken_or_recently_used_targets() {
+        let pool = ConnectionPool::new(1, Duration::from_secs(60));
+        // A first CONNECT gives no reason to expect another.
+        assert!(!pool.checkout(&address(1)).1);
+        // A second one within the idle timeout starts the target off, once.
+        assert!(pool.checkout(&address(1)).1);
+        assert!(!pool.checkout(&address(1)).1);
+        pool.dialed(&address(1), None);
+        assert!(pool.checkout(&address(1)).1);
+
+        let pool = ConnectionPool::new(1, Duration::from_millis(20));
+        assert!(!pool.checkout(&address(1)).1);
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(!pool.checkout(&address(1)).1);
+    }
+
+    #[tokio::test]
+    async fn taken_spares_are_replaced_up_to_max_size() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool = ConnectionPool::new(2, Duration::from_secs(60));
+        let (first, _first_remote) = connection(&target).await;
+        let (second, _second_remote) = connection(&target).await;
+        pool.put(address(1), first);
+        pool.put(address(1), second);
+
+        let (taken, refill) = pool.checkout(&address(1));
+        assert!(taken.is_some() && refill);
+        // One spare left and one replacement pending fill the target's two slots.
+        let (taken, refill) = pool.checkout(&address(1));
+        assert!(taken.is_some() && refill);
+        let (taken, refill) = pool.checkout(&address(1));
+        assert!(taken.is_none() && !refill);
+    }
+
+    #[tokio::test]
+    async fn expired_and_closed_spares_are_skipped() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool = ConnectionPool::new(2, Duration::from_millis(50));
+        let (expired, _expired_remote) = connection(&target).await;
+        pool.put(address(1), expired);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(pool.checkout(&address(1)).0.is_none());
+
+        let (closed, closed_remote) = connection(&target).await;
+        pool.put(address(1), closed);
+        drop(closed_remote);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(pool.checkout(&address(1)).0.is_none());
+    }
+
+    #[tokio::test]
+    async fn spares_with_queued_data_stay_usable() {
+        use tokio::io::AsyncWriteExt;
+
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool = ConnectionPool::new(1, Duration::from_secs(60));
+        let (spare, mut remote) = connection(&target).await;
+        pool.put(address(1), spare);
+        remote.write_all(b"banner").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(pool.checkout(&address(1)).0.is_some());
+    }
+}
@@ -0,0 +1,191 @@
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::ProxyProtocol;
+
+const V2_SIGNATURE: [u8; 12] = [0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A];
+const V2_VERSION_PROXY: u8 = 0x21;
+const V2_FAMILY_TCP4: u8 = 0x11;
+const V2_FAMILY_TCP6: u8 = 0x21;
+const V2_FAMILY_UDP4: u8 = 0x12;
+const V2_FAMILY_UDP6: u8 = 0x22;
+const V2_COMMAND_LOCAL: u8 = 0x00;
+const V2_COMMAND_PROXY: u8 = 0x01;
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+
+/// Encode the header announcing a connection from `src_addr` to `dst_addr`; a mixed-family pair
+/// is announced as IPv6 since both ends of a header share one family.
+pub(crate) fn encode_header(version: ProxyProtocol, src_addr: SocketAddr, dst_addr: SocketAddr) -> Vec<u8> {
+    match (version, src_addr, dst_addr) {
+        (ProxyProtocol::V1, SocketAddr::V4(src_addr), SocketAddr::V4(dst_addr)) => {
+            format!("PROXY TCP4 {} {} {} {}\r\n", src_addr.ip(), dst_addr.ip(), src_addr.port(), dst_addr.port()).into_bytes()
+        }
+        (ProxyProtocol::V1, src_addr, dst_addr) => {
+            let (src_addr, dst_addr) = (to_ipv6(src_addr), to_ipv6(dst_addr));
+            format!("PROXY TCP6 {} {} {} {}\r\n", src_addr.ip(), dst_addr.ip(), src_addr.port(), dst_addr.port()).into_bytes()
+        }
+        (ProxyProtocol::V2, SocketAddr::V4(src_addr), SocketAddr::V4(dst_addr)) => {
+            let mut header = encode_v2_prefix(V2_FAMILY_TCP4, 12);
+            header.extend_from_slice(&src_addr.ip().octets());
+            header.extend_from_slice(&dst_addr.ip().octets());
+            header.extend_from_slice(&src_addr.port().to_be_bytes());
+            header.extend_from_slice(&dst_addr.port().to_be_bytes());
+            header
+        }
+        (ProxyProtocol::V2, src_addr, dst_addr) => {
+            let (src_addr, dst_addr) = (to_ipv6(src_addr), to_ipv6(dst_addr));
+            let mut header = encode_v2_prefix(V2_FAMILY_TCP6, 36);
+            header.extend_from_slice(&src_addr.ip().octets());
+            header.extend_from_slice(&dst_addr.ip().octets());
+            header.extend_from_slice(&src_addr.port().to_be_bytes());
+            header.extend_from_slice(&dst_addr.port().to_be_bytes());
+            header
+        }
+    }
+}
+
+/// Consume a v1 or v2 header from the start of `reader`, returning the source address it carries;
+/// `None` means the sender announced a health check or an unknown family and the peer address stands.
+pub(crate) async fn read_header<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<SocketAddr>, Error> {
+    // Never read past the header: a v1 line is at least 15 bytes and a v2 header at least 16.
+    let mut header = vec![0u8; V2_SIGNATURE.len()];
+    reader.read_exact(&mut header).await?;
+    if header == V2_SIGNATURE {
+        return read_v2_header(reader).await;
+    }
+    if !header.starts_with(V1_PREFIX) {
+        return Err(Error::new(ErrorKind::InvalidData, "missing proxy protocol header"));
+    }
+    while !header.ends_with(b"\r\n") {
+        if header.len() >= V1_MAX_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "proxy protocol v1 header too long"));
+        }
+        header.push(reader.read_u8().await?);
+    }
+    parse_v1_header(&header[V1_PREFIX.len()..header.len() - 2])
+}
+
+fn parse_v1_header(header: &[u8]) -> Result<Option<SocketAddr>, Error> {
+    let header = std::str::from_utf8(header).map_err(|_| Error::new(ErrorKind::InvalidData, "invalid non-utf8 proxy protocol v1 header"))?;
+    let invalid_header = || Error::new(ErrorKind::InvalidData, format!("invalid proxy protocol v1 header {:?}", header));
+    let parts: Vec<&str> = header.split(' ').collect();
+    let src_ip: IpAddr = match parts.as_slice() {
+        ["UNKNOWN", ..] => return Ok(None),
+        ["TCP4", src_ip, dst_ip, _, _] => {
+            dst_ip.parse::<Ipv4Addr>().map_err(|_| invalid_header())?;
+            IpAddr::V4(src_ip.parse().map_err(|_| invalid_header())?)
+        }
+        ["TCP6", src_ip, dst_ip, _, _] => {
+            dst_ip.parse::<Ipv6Addr>().map_err(|_| invalid_header())?;
+            IpAddr::V6(src_ip.parse().map_err(|_| invalid_header())?)
+        }
+        _ => return Err(invalid_header()),
+    };
+    let src_port: u16 = parts[3].parse().map_err(|_| invalid_header())?;
+    parts[4].parse::<u16>().map_err(|_| invalid_header())?;
+    Ok(Some(SocketAddr::new(src_ip, src_port)))
+}
+
+async fn read_v2_header<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<SocketAddr>, Error> {
+    let ver_cmd = reader.read_u8().await?;
+    let family = reader.read_u8().await?;
+    let mut addrs = vec![0u8; reader.read_u16().await? as usize];
+    reader.read_exact(&mut addrs).await?;
+    if ver_cmd >> 4 != V2_VERSION_PROXY >> 4 {
+        return Err(Error::new(ErrorKind::InvalidData, format!("invalid proxy protocol version {}", ver_cmd >> 4)));
+    }
+    match ver_cmd & 0x0F {
+        V2_COMMAND_LOCAL => return Ok(None),
+        V2_COMMAND_PROXY => {}
+        command => return Err(Error::new(ErrorKind::InvalidData, format!("invalid proxy protocol command {}", command))),
+    }
+    match family {
+        V2_FAMILY_TCP4 | V2_FAMILY_UDP4 if addrs.len() >= 12 => {
+            let src_ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            Ok(Some(SocketAddr::from((src_ip, u16::from_be_bytes([addrs[8], addrs[9]])))))
+        }
+        V2_FAMILY_TCP6 | V2_FAMILY_UDP6 if addrs.len() >= 36 => {
+            let mut src_ip = [0u8; 16];
+            src_ip.copy_from_slice(&addrs[..16]);
+            Ok(Some(SocketAddr::from((Ipv6Addr::from(src_ip), u16::from_be_bytes([addrs[32], addrs[33]])))))
+        }
+        V2_FAMILY_TCP4 | V2_FAMILY_UDP4 | V2_FAMILY_TCP6 | V2_FAMILY_UDP6 => {
+            Err(Error::new(ErrorKind::InvalidData, format!("proxy protocol addresses too short for family {:#04x}", family)))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn encode_v2_prefix(family: u8, addr_len: u16) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    header.push(V2_VERSION_PROXY);
+    header.push(family);
+    header.extend_from_slice(&addr_len.to_be_bytes());
+    header
+}
+
+fn to_ipv6(addr: SocketAddr) -> SocketAddrV6 {
+    match addr {
+        SocketAddr::V4(addr) => SocketAddrV6::new(addr.ip().to_ipv6_mapped(), addr.port(), 0, 0),
+        SocketAddr::V6(addr) => addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_v1_headers() {
+        let (src_addr, dst_addr) = ("192.0.2.1:50000".parse().unwrap(), "198.51.100.2:443".parse().unwrap());
+        assert_eq!(encode_header(ProxyProtocol::V1, src_addr, dst_addr), b"PROXY TCP4 192.0.2.1 198.51.100.2 50000 443\r\n");
+
+        let dst_addr = "[2001:db8::2]:443".parse().unwrap();
+        assert_eq!(encode_header(ProxyProtocol::V1, src_addr, dst_addr), b"PROXY TCP6 ::ffff:192.0.2.1 2001:db8::2 50000 443\r\n");
+    }
+
+    #[test]
+    fn encodes_v2_headers() {
+        let (src_addr, dst_addr) = ("192.0.2.1:50000".parse().unwrap(), "198.51.100.2:443".parse().unwrap());
+        let header = encode_header(ProxyProtocol::V2, src_addr, dst_addr);
+        assert_eq!(header[..12], V2_SIGNATURE);
+        assert_eq!(header[12..], [V2_VERSION_PROXY, V2_FAMILY_TCP4, 0, 12, 192, 0, 2, 1, 198, 51, 100, 2, 0xC3, 0x50, 0x01, 0xBB]);
+
+        let dst_addr = "[2001:db8::2]:443".parse().unwrap();
+        let header = encode_header(ProxyProtocol::V2, src_addr, dst_addr);
+        assert_eq!(header[13..16], [V2_FAMILY_TCP6, 0, 36]);
+        assert_eq!(header.len(), 16 + 36);
+    }
+
+    #[tokio::test]
+    async fn reads_the_source_address() {
+        let header = encode_header(ProxyProtocol::V1, "192.0.2.1:50000".parse().unwrap(), "198.51.100.2:443".parse().unwrap());
+        assert_eq!(read_header(&mut &header[..]).await.unwrap(), Some("192.0.2.1:50000".parse().unwrap()));
+
+        let header = encode_header(ProxyProtocol::V2, "[2001:db8::1]:50000".parse().unwrap(), "[2001:db8::2]:443".parse().unwrap());
+        assert_eq!(read_header(&mut &header[..]).await.unwrap(), Some("[2001:db8::1]:50000".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn leaves_the_peer_address_for_local_and_unknown_headers() {
+        assert_eq!(read_header(&mut &b"PROXY UNKNOWN\r\n"[..]).await.unwrap(), None);
+
+        let mut header = encode_v2_prefix(V2_FAMILY_TCP4, 0);
+        header[12] = V2_VERSION_PROXY & 0xF0 | V2_COMMAND_LOCAL;
+        assert_eq!(read_header(&mut &header[..]).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_headers() {
+        for header in [&b"GET / HTTP/1.1\r\n"[..], b"PROXY TCP4 192.0.2.1 ::1 1 2\r\n", b"PROXY TCP4 192.0.2.1 192.0.2.2 1 70000\r\n"] {
+            assert_eq!(read_header(&mut &header[..]).await.unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+        let long_line = format!("PROXY {}\r\n", "x".repeat(V1_MAX_LEN));
+        assert_eq!(read_header(&mut long_line.as_bytes()).await.unwrap_err().kind(), ErrorKind::InvalidData);
+
+        let header = encode_v2_prefix(V2_FAMILY_TCP4, 4);
+        assert_eq!(read_header(&mut &[&header[..], &[0; 4]].concat()[..]).await.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
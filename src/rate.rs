@@ -0,0 +1,58 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    rate: u64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(rate: u64) -> Self {
+        let rate = rate.max(1);
+        RateLimiter {
+            rate,
+            bucket: Mutex::new(Bucket {
+                tokens: rate as f64,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take `amount` tokens, sleeping off any debt once the bucket is drained.
+    pub(crate) async fn acquire(&self, amount: usize) {
+        let debt = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.refilled_at).as_secs_f64() * self.rate as f64;
+            bucket.tokens = (bucket.tokens + refill).min(self.rate as f64) - amount as f64;
+            bucket.refilled_at = now;
+            -bucket.tokens
+        };
+        if debt > 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(debt / self.rate as f64)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn burst_is_free_and_debt_is_slept_off() {
+        let rate_limiter = RateLimiter::new(1000);
+        let started = Instant::now();
+        rate_limiter.acquire(1000).await;
+        assert!(started.elapsed() < Duration::from_millis(100));
+        rate_limiter.acquire(300).await;
+        assert!(started.elapsed() >= Duration::from_millis(280));
+        assert!(started.elapsed() < Duration::from_millis(600));
+    }
+}
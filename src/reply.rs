@@ -0,0 +1,104 @@
+use std::io::Error;
+
+use crate::{encode_address, Address, ReplyType, VERSION};
+
+/// A SOCKS5 reply: the REP code and the bound address reported to the client.
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> std::io::Result<()> {
+/// use std::net::{Ipv4Addr, Ipv6Addr};
+/// use socks_lib::{Address, Reply, Target};
+///
+/// let reply = Reply::new(0, Address::new(Target::Ipv4(Ipv4Addr::new(10, 0, 0, 1)), 1080));
+/// assert_eq!(reply.encode()?, vec![5, 0, 0, 1, 10, 0, 0, 1, 4, 56]);
+///
+/// for reply in [
+///     Reply::new(0, Address::new(Target::Ipv6(Ipv6Addr::LOCALHOST), 443)),
+///     Reply::new(4, Address::new(Target::Domain("example.org".to_string()), 80)),
+///     Reply::new(5, Address::new(Target::Ipv4(Ipv4Addr::UNSPECIFIED), 0)),
+/// ] {
+///     let frame = reply.encode()?;
+///     assert_eq!(socks_lib::parse_reply(&mut frame.as_slice()).await?, reply);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    code: ReplyType,
+    bnd_addr: Address,
+}
+
+impl Reply {
+    pub fn new(code: u8, bnd_addr: Address) -> Self {
+        Reply {
+            code,
+            bnd_addr,
+        }
+    }
+
+    pub fn code(&self) -> u8 {
+        self.code
+    }
+
+    pub fn bnd_addr(&self) -> &Address {
+        &self.bnd_addr
+    }
+
+    /// Serialize into the wire format; fails only for a domain longer than 255 bytes.
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut reply = vec![VERSION, self.code, 0u8];
+        encode_address(&mut reply, &self.bnd_addr)?;
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+    use crate::{parse_reply, Target, ATYP_DOMAIN_NAME, ATYP_IPV4, ATYP_IPV6, REP_COMMAND_NOT_SUPPORTED, REP_GENERAL_FAILURE, REP_HOST_UNREACHABLE, REP_NOT_ALLOWED, REP_SUCCEEDED};
+
+    #[test]
+    fn encodes_each_address_type() {
+        let reply = Reply::new(0, Address::new(Target::Ipv4(Ipv4Addr::new(192, 0, 2, 1)), 1080));
+        assert_eq!(reply.encode().unwrap(), [VERSION, 0, 0, ATYP_IPV4, 192, 0, 2, 1, 0x04, 0x38]);
+
+        let reply = Reply::new(0, Address::new(Target::Ipv6(Ipv6Addr::LOCALHOST), 443));
+        let mut frame = vec![VERSION, 0, 0, ATYP_IPV6];
+        frame.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        frame.extend_from_slice(&[0x01, 0xBB]);
+        assert_eq!(reply.encode().unwrap(), frame);
+
+        let reply = Reply::new(0, Address::new(Target::Domain("example.org".to_string()), 80));
+        let mut frame = vec![VERSION, 0, 0, ATYP_DOMAIN_NAME, 11];
+        frame.extend_from_slice(b"example.org");
+        frame.extend_from_slice(&[0, 80]);
+        assert_eq!(reply.encode().unwrap(), frame);
+    }
+
+    #[test]
+    fn rejects_domains_too_long_to_encode() {
+        let reply = Reply::new(0, Address::new(Target::Domain("a".repeat(256)), 80));
+        assert!(reply.encode().is_err());
+    }
+
+    #[tokio::test]
+    async fn round_trips_through_the_parser() {
+        let bnd_addrs = [
+            Address::new(Target::Ipv4(Ipv4Addr::UNSPECIFIED), 0),
+            Address::new(Target::Ipv6("2001:db8::1".parse().unwrap()), 65535),
+            Address::new(Target::Domain("proxy.example".to_string()), 1080),
+        ];
+        for code in [REP_SUCCEEDED, REP_GENERAL_FAILURE, REP_NOT_ALLOWED, REP_HOST_UNREACHABLE, REP_COMMAND_NOT_SUPPORTED] {
+            for bnd_addr in &bnd_addrs {
+                let reply = Reply::new(code, bnd_addr.clone());
+                let frame = reply.encode().unwrap();
+                assert_eq!(parse_reply(&mut frame.as_slice()).await.unwrap(), reply);
+            }
+        }
+    }
+}
+
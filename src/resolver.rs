@@ -0,0 +1,30 @@
+use std::io::Error;
+use std::net::SocketAddr;
+
+use crate::BoxFuture;
+
+pub trait Resolver: Send + Sync {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> BoxFuture<'a, Result<Vec<SocketAddr>, Error>>;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> BoxFuture<'a, Result<Vec<SocketAddr>, Error>> {
+        Box::pin(async move {
+            Ok(tokio::net::lookup_host((host, port)).await?.collect())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn system_resolver_resolves_literals() {
+        let resolved = SystemResolver.resolve("127.0.0.1", 80).await.unwrap();
+        assert_eq!(resolved, [SocketAddr::from(([127, 0, 0, 1], 80))]);
+    }
+}
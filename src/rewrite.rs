@@ -0,0 +1,47 @@
+use std::io::Error;
+
+use crate::Address;
+
+/// Rewrites CONNECT targets before they are dialed; an error rejects the request with the reply
+/// code matching its kind, e.g. `PermissionDenied` for "not allowed by ruleset".
+pub trait Rewriter: Send + Sync {
+    fn rewrite(&self, target: &mut Address) -> Result<(), Error>;
+}
+
+impl<F: Fn(&mut Address) -> Result<(), Error> + Send + Sync> Rewriter for F {
+    fn rewrite(&self, target: &mut Address) -> Result<(), Error> {
+        self(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use super::*;
+    use crate::Target;
+
+    #[test]
+    fn closures_rewrite_the_target_in_place() {
+        let rewriter = |target: &mut Address| {
+            if target.port() == 80 {
+                *target = Address::new(Target::Domain("mirror.internal".to_string()), 8080);
+            }
+            Ok(())
+        };
+        let mut target = Address::new(Target::Domain("example.com".to_string()), 80);
+        rewriter.rewrite(&mut target).unwrap();
+        assert_eq!(target, Address::new(Target::Domain("mirror.internal".to_string()), 8080));
+
+        let mut untouched = Address::new(Target::Domain("example.com".to_string()), 443);
+        rewriter.rewrite(&mut untouched).unwrap();
+        assert_eq!(untouched.port(), 443);
+    }
+
+    #[test]
+    fn closure_errors_are_passed_through() {
+        let rewriter: Box<dyn Rewriter> = Box::new(|_: &mut Address| Err(Error::new(ErrorKind::PermissionDenied, "blocked")));
+        let err = rewriter.rewrite(&mut Address::new(Target::Domain("example.com".to_string()), 80)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    }
+}
@@ -0,0 +1,81 @@
+//! Helpers for driving a server from tests without assembling SOCKS5 frames by hand.
+//!
+//! ```
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() -> std::io::Result<()> {
+//! use socks_lib::{testutil, Address, Config, Target};
+//! use tokio::io::{AsyncReadExt, AsyncWriteExt};
+//!
+//! let echo = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+//! let echo_addr = echo.local_addr()?;
+//! tokio::spawn(async move {
+//!     let (mut stream, _) = echo.accept().await.unwrap();
+//!     let (mut reader, mut writer) = stream.split();
+//!     tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+//! });
+//!
+//! let proxy_addr = testutil::spawn_server(Config::builder().auth("user", "password")).await?;
+//! let target = Address::new(Target::Ipv4(std::net::Ipv4Addr::LOCALHOST), echo_addr.port());
+//!
+//! let mut stream = testutil::connect_with_auth(proxy_addr, &target, "user", "password").await?;
+//! stream.write_all(b"ping").await?;
+//! let mut pong = [0u8; 4];
+//! stream.read_exact(&mut pong).await?;
+//! assert_eq!(&pong, b"ping");
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io::Error;
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+
+use crate::{upstream, Address, ConfigBuilder, Server};
+
+/// Bind a server built from `config` to an ephemeral loopback port and run it on the current
+/// runtime, returning the address it listens on.
+pub async fn spawn_server(config: ConfigBuilder) -> Result<SocketAddr, Error> {
+    let server = Server::new(config.local_addr("127.0.0.1").local_port(0).build()).bind().await?;
+    let server_addr = server.local_addr()?;
+    tokio::spawn(server.run());
+    Ok(server_addr)
+}
+
+/// Negotiate a CONNECT to `dst_addr` through the proxy at `proxy_addr` without authentication and
+/// return the relayed stream.
+pub async fn connect(proxy_addr: SocketAddr, dst_addr: &Address) -> Result<TcpStream, Error> {
+    let mut proxy_stream = TcpStream::connect(proxy_addr).await?;
+    upstream::handshake(&mut proxy_stream, None, dst_addr).await?;
+    Ok(proxy_stream)
+}
+
+/// Like [`connect`], authenticating with a username and password.
+pub async fn connect_with_auth(proxy_addr: SocketAddr, dst_addr: &Address, username: &str, password: &str) -> Result<TcpStream, Error> {
+    let mut proxy_stream = TcpStream::connect(proxy_addr).await?;
+    upstream::handshake(&mut proxy_stream, Some((username, password)), dst_addr).await?;
+    Ok(proxy_stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::{Config, Target};
+
+    #[tokio::test]
+    async fn spawned_servers_listen_on_loopback() {
+        let server_addr = spawn_server(Config::builder().local_addr("0.0.0.0").local_port(1080)).await.unwrap();
+        assert_eq!(server_addr.ip(), Ipv4Addr::LOCALHOST);
+        assert_ne!(server_addr.port(), 1080);
+    }
+
+    #[tokio::test]
+    async fn failed_connects_keep_the_reply_kind() {
+        let closed_port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let server_addr = spawn_server(Config::builder()).await.unwrap();
+        let err = connect(server_addr, &Address::new(Target::Ipv4(Ipv4Addr::LOCALHOST), closed_port)).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+    }
+}
@@ -0,0 +1,52 @@
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+use crate::Config;
+
+pub(crate) fn acceptor(config: &Config) -> Result<Option<TlsAcceptor>, Error> {
+    let (cert_chain, key) = match &config.tls_identity {
+        Some(tls_identity) => tls_identity,
+        None => return Ok(None),
+    };
+    let server_config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(cert_chain.clone(), key.clone_key()))
+        .map_err(|err| Error::new(ErrorKind::InvalidInput, format!("invalid tls config: {}", err)))?;
+    Ok(Some(TlsAcceptor::from(Arc::new(server_config))))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+
+    use super::*;
+
+    fn self_signed() -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert = CertificateDer::from(certified.cert.der().to_vec());
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+        (cert, key)
+    }
+
+    #[test]
+    fn no_identity_means_plain_tcp() {
+        assert!(acceptor(&Config::builder().build()).unwrap().is_none());
+    }
+
+    #[test]
+    fn valid_identity_builds_an_acceptor() {
+        let (cert, key) = self_signed();
+        assert!(acceptor(&Config::builder().tls_identity(vec![cert], key).build()).unwrap().is_some());
+    }
+
+    #[test]
+    fn mismatched_key_is_invalid_input() {
+        let (cert, _) = self_signed();
+        let (_, other_key) = self_signed();
+        let err = acceptor(&Config::builder().tls_identity(vec![cert], other_key).build()).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,222 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::UdpSocket;
+
+use crate::{check_domain_len, encode_socket_addr, lookup_address, parse_domain, Address, Byte, CloseReason, Config, ConnectionToken, RelayClosed, ServerState, Target};
+use crate::{ATYP_DOMAIN_NAME, ATYP_IPV4, ATYP_IPV6, READER_BUFFER_LEN};
+
+const DATAGRAM_BUFFER_LEN: usize = 65536;
+const MAX_RESOLVED_TARGETS: usize = 1024;
+
+pub(crate) async fn handle_connect_udp(config: &Config, server_addr: SocketAddr, _dst_addr: &Address) -> Result<UdpSocket, Error> {
+    // A client reaching a dual-stack listener over IPv4 gets a plain IPv4 relay. An IPv6 relay
+    // listens dual-stack unless bound to a configured address, so it can still reach IPv4 targets.
+    let bind_ip = config.udp_bind_addr.unwrap_or(server_addr.ip().to_canonical());
+    match bind_ip {
+        IpAddr::V6(ip) if ip.is_unspecified() || config.udp_bind_addr.is_none() => bind_dual_stack(),
+        _ => UdpSocket::bind(SocketAddr::new(bind_ip, 0)).await,
+    }
+}
+
+fn bind_dual_stack() -> Result<UdpSocket, Error> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(false)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)).into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// The form of `addr` an IPv6 relay socket can send to: IPv4 peers are reached IPv4-mapped.
+fn relay_peer_addr(relay_ipv6: bool, addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) if relay_ipv6 => SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port()),
+        _ => addr,
+    }
+}
+
+pub(crate) async fn handle_udp_relay<R: AsyncRead + Unpin>(config: &Config, state: &ServerState, client_addr: SocketAddr, token: &ConnectionToken, dst_addr: &Address, relay_socket: UdpSocket, mut client_reader: R) -> Result<(), Error> {
+    // DST.ADDR and DST.PORT name the address the client will send from, where it knows it;
+    // zeros (or a domain) leave only the control connection's IP to go by, and the exact source
+    // is learned from the first datagram. Compared canonically, so a dual-stack relay still
+    // recognizes IPv4 clients whose datagrams arrive IPv4-mapped.
+    let expected_ip = match dst_addr.target() {
+        Target::Ipv4(ip) if !ip.is_unspecified() => IpAddr::V4(*ip),
+        Target::Ipv6(ip) if !ip.is_unspecified() => IpAddr::V6(*ip),
+        _ => client_addr.ip(),
+    }.to_canonical();
+    let expected_port = Some(dst_addr.port()).filter(|port| *port != 0);
+    let mut control_buffer: [u8; READER_BUFFER_LEN] = [0u8; READER_BUFFER_LEN];
+    let mut datagram_buffer = vec![0u8; DATAGRAM_BUFFER_LEN];
+    let mut client_udp_addr: Option<SocketAddr> = None;
+    let mut contacted_addrs: HashSet<SocketAddr> = HashSet::new();
+    // Domain targets are resolved once per association, so a stream of datagrams to one name does
+    // not stall the relay loop on a lookup each.
+    let mut resolved_addrs: HashMap<Address, SocketAddr> = HashMap::new();
+    let relay_ipv6 = relay_socket.local_addr()?.is_ipv6();
+    let bytes_up = AtomicU64::new(0);
+    let bytes_down = AtomicU64::new(0);
+    let mut closed = RelayClosed {
+        config,
+        client_addr,
+        dst_addr,
+        started: Instant::now(),
+        bytes_up: &bytes_up,
+        bytes_down: &bytes_down,
+        close_reason: None,
+    };
+    // The association lives exactly as long as the control connection, so its EOF tears the relay
+    // down even while a datagram is being resolved or sent.
+    let control_closed = async {
+        loop {
+            match client_reader.read(&mut control_buffer).await {
+                Ok(0) => return Ok(()),
+                Ok(_) => {}
+                Err(err) => return Err(err),
+            }
+        }
+    };
+    let relayed = async {
+        loop {
+            let (datagram_len, src_addr) = match relay_socket.recv_from(&mut datagram_buffer).await {
+                Ok((datagram_len, src_addr)) => (datagram_len, SocketAddr::new(src_addr.ip().to_canonical(), src_addr.port())),
+                Err(err) => return Err(err),
+            };
+            let from_client = match client_udp_addr {
+                Some(client_udp_addr) => client_udp_addr == src_addr,
+                None => expected_ip == src_addr.ip() && expected_port.is_none_or(|port| port == src_addr.port()),
+            };
+            if from_client {
+                client_udp_addr = Some(src_addr);
+                let header = parse_udp_header(&datagram_buffer[..datagram_len]).and_then(|header| check_domain_len(config, &header.1).map(|()| header));
+                let (frag, dst_addr, header_len) = match header {
+                    Ok(header) => header,
+                    Err(_) => continue,
+                };
+                // Fragment reassembly is optional in RFC 1928 and not implemented: any datagram with a
+                // non-zero FRAG is dropped, never forwarded as a partial payload.
+                if frag != 0 {
+                    debug!(config, "{} dropped udp fragment {} to {}", client_addr, frag, dst_addr);
+                    continue;
+                }
+                let dst_socket_addr = match resolved_addrs.get(&dst_addr) {
+                    Some(dst_socket_addr) => Ok(*dst_socket_addr),
+                    None => lookup_address(config, &dst_addr).await.inspect(|dst_socket_addr| {
+                        if matches!(dst_addr.target(), Target::Domain(_)) && resolved_addrs.len() < MAX_RESOLVED_TARGETS {
+                            resolved_addrs.insert(dst_addr.clone(), *dst_socket_addr);
+                        }
+                    }),
+                };
+                if let Ok(dst_socket_addr) = dst_socket_addr {
+                    let dst_socket_addr = SocketAddr::new(dst_socket_addr.ip().to_canonical(), dst_socket_addr.port());
+                    contacted_addrs.insert(dst_socket_addr);
+                    match relay_socket.send_to(&datagram_buffer[header_len..datagram_len], relay_peer_addr(relay_ipv6, dst_socket_addr)).await {
+                        Ok(_) => {
+                            bytes_up.fetch_add((datagram_len - header_len) as u64, Ordering::Relaxed);
+                            state.counters.total_bytes.fetch_add((datagram_len - header_len) as u64, Ordering::Relaxed);
+                            config.metrics.bytes_up((datagram_len - header_len) as u64);
+                        }
+                        Err(err) => debug!(config, "{} failed to send udp datagram to {}: {}", client_addr, dst_socket_addr, err),
+                    }
+                }
+            } else if let Some(client_udp_addr) = client_udp_addr.filter(|_| contacted_addrs.contains(&src_addr)) {
+                let mut datagram = vec![0u8, 0u8, 0u8];
+                encode_socket_addr(&mut datagram, src_addr);
+                datagram.extend_from_slice(&datagram_buffer[..datagram_len]);
+                if relay_socket.send_to(&datagram, relay_peer_addr(relay_ipv6, client_udp_addr)).await.is_ok() {
+                    bytes_down.fetch_add(datagram_len as u64, Ordering::Relaxed);
+                    state.counters.total_bytes.fetch_add(datagram_len as u64, Ordering::Relaxed);
+                    config.metrics.bytes_down(datagram_len as u64);
+                }
+            } else {
+                debug!(config, "{} dropped udp datagram from unexpected source {}", client_addr, src_addr);
+            }
+        }
+    };
+    let result = tokio::select! {
+        closed = control_closed => Some(closed),
+        err = relayed => Some(err),
+        _ = token.cancelled() => None,
+    };
+    drop(relay_socket);
+    closed.close_reason = Some(match &result {
+        Some(Ok(())) => CloseReason::Eof,
+        Some(Err(err)) => CloseReason::Error(err.kind()),
+        None => CloseReason::Cancelled,
+    });
+    result.unwrap_or(Ok(()))
+}
+
+fn parse_udp_header(datagram: &[u8]) -> Result<(Byte, Address, usize), Error> {
+    let invalid_datagram = || Error::new(ErrorKind::InvalidData, format!("invalid udp datagram of length {}", datagram.len()));
+    if datagram.len() < 4 {
+        return Err(invalid_datagram());
+    }
+    let frag = datagram[2];
+    let atyp = datagram[3];
+    let (target, addr_end) = match atyp {
+        ATYP_IPV4 => {
+            let octets: [u8; 4] = datagram.get(4..8).ok_or_else(invalid_datagram)?.try_into().unwrap();
+            (Target::Ipv4(Ipv4Addr::from(octets)), 8)
+        }
+        ATYP_DOMAIN_NAME => {
+            let dst_addr_len = *datagram.get(4).ok_or_else(invalid_datagram)? as usize;
+            let domain = datagram.get(5..5 + dst_addr_len).ok_or_else(invalid_datagram)?;
+            (Target::Domain(parse_domain(domain.to_vec())?), 5 + dst_addr_len)
+        }
+        ATYP_IPV6 => {
+            let octets: [u8; 16] = datagram.get(4..20).ok_or_else(invalid_datagram)?.try_into().unwrap();
+            (Target::Ipv6(Ipv6Addr::from(octets)), 20)
+        }
+        _ => {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("invalid atyp value {}", atyp)));
+        }
+    };
+    let port = datagram.get(addr_end..addr_end + 2).ok_or_else(invalid_datagram)?;
+    Ok((frag, Address {
+        target,
+        port: u16::from_be_bytes([port[0], port[1]]),
+    }, addr_end + 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_each_address_type() {
+        let (frag, addr, header_len) = parse_udp_header(&[0, 0, 0, ATYP_IPV4, 127, 0, 0, 1, 0, 53, b'x']).unwrap();
+        assert_eq!((frag, header_len), (0, 10));
+        assert_eq!(addr, Address::new(Target::Ipv4(Ipv4Addr::LOCALHOST), 53));
+
+        let mut datagram = vec![0, 0, 2, ATYP_IPV6];
+        datagram.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        datagram.extend_from_slice(&[0, 53]);
+        let (frag, addr, header_len) = parse_udp_header(&datagram).unwrap();
+        assert_eq!((frag, header_len), (2, 22));
+        assert_eq!(addr, Address::new(Target::Ipv6(Ipv6Addr::LOCALHOST), 53));
+
+        let (_, addr, header_len) = parse_udp_header(b"\0\0\0\x03\x0bexample.com\0\x35").unwrap();
+        assert_eq!(header_len, 18);
+        assert_eq!(addr, Address::new(Target::Domain("example.com".to_string()), 53));
+    }
+
+    #[test]
+    fn rejects_truncated_headers() {
+        assert!(parse_udp_header(&[0, 0, 0]).is_err());
+        assert!(parse_udp_header(&[0, 0, 0, ATYP_IPV4, 127, 0]).is_err());
+        assert!(parse_udp_header(b"\0\0\0\x03\x0bexample").is_err());
+        assert!(parse_udp_header(&[0, 0, 0, 9, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn maps_ipv4_peers_for_ipv6_relays() {
+        let peer = SocketAddr::from(([127, 0, 0, 1], 53));
+        assert_eq!(relay_peer_addr(false, peer), peer);
+        assert_eq!(relay_peer_addr(true, peer), "[::ffff:127.0.0.1]:53".parse().unwrap());
+    }
+}
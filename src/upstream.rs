@@ -0,0 +1,149 @@
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::{connect_remote, encode_address, is_special_use, lookup_addresses, parse_reply, Action, Address, Config, SocksError, Target};
+use crate::{AUTH_SUCCEEDED, AUTH_VERSION, CMD_CONNECT, METHOD_NO_ACCEPTABLE, METHOD_NO_AUTH, METHOD_USERNAME_PASSWORD, VERSION};
+use crate::{REP_CONNECTION_REFUSED, REP_HOST_UNREACHABLE, REP_NETWORK_UNREACHABLE, REP_NOT_ALLOWED, REP_SUCCEEDED};
+
+/// Connect to `dst_addr` through the parent SOCKS5 proxy at `upstream_proxy`.
+pub(crate) async fn connect(config: &Config, upstream_proxy: SocketAddr, dst_addr: &Address) -> Result<TcpStream, Error> {
+    match dst_addr.target() {
+        Target::Domain(domain) => {
+            if config.access_control.as_ref().is_some_and(|access_control| access_control.evaluate_domain(domain) == Action::Deny) {
+                return Err(Error::new(ErrorKind::PermissionDenied, format!("connection to {} not allowed by ruleset", dst_addr)));
+            }
+            // The parent resolves the name, so the check can only go by what it resolves to here;
+            // names that do not resolve locally are left to the parent.
+            if config.block_private_addresses {
+                if let Ok(resolved_addrs) = config.resolver.resolve(domain, dst_addr.port()).await {
+                    if !resolved_addrs.is_empty() && resolved_addrs.iter().all(|resolved_addr| is_special_use(resolved_addr.ip())) {
+                        return Err(Error::new(ErrorKind::PermissionDenied, format!("connection to {} not allowed, private address", dst_addr)));
+                    }
+                }
+            }
+        }
+        _ => {
+            lookup_addresses(config, dst_addr).await?;
+        }
+    }
+
+    let upstream_credentials = config.upstream_credentials.as_ref().map(|(username, password)| (username.as_str(), password.as_str()));
+    let connect = async {
+        let mut upstream_stream = connect_remote(config, upstream_proxy).await?;
+        handshake(&mut upstream_stream, upstream_credentials, dst_addr).await?;
+        Ok(upstream_stream)
+    };
+    connect.await.map_err(|err: Error| SocksError::Upstream(Error::new(err.kind(), format!("upstream proxy {}: {}", upstream_proxy, err))).into())
+}
+
+/// Run the client side of a SOCKS5 CONNECT for `dst_addr`, offering username/password
+/// authentication when `credentials` are given.
+pub(crate) async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(proxy_stream: &mut S, credentials: Option<(&str, &str)>, dst_addr: &Address) -> Result<(), Error> {
+    negotiate(proxy_stream, credentials).await?;
+
+    let mut request = vec![VERSION, CMD_CONNECT, 0u8];
+    encode_address(&mut request, dst_addr)?;
+    proxy_stream.write_all(&request).await?;
+
+    let reply = parse_reply(proxy_stream).await?;
+    if REP_SUCCEEDED != reply.code() {
+        return Err(Error::new(reply_error_kind(reply.code()), format!("replied {} for {}", reply.code(), dst_addr)));
+    }
+    Ok(())
+}
+
+async fn negotiate<S: AsyncRead + AsyncWrite + Unpin>(proxy_stream: &mut S, credentials: Option<(&str, &str)>) -> Result<(), Error> {
+    let greeting: &[u8] = match credentials {
+        Some(_) => &[VERSION, 2u8, METHOD_USERNAME_PASSWORD, METHOD_NO_AUTH],
+        None => &[VERSION, 1u8, METHOD_NO_AUTH],
+    };
+    proxy_stream.write_all(greeting).await?;
+
+    let mut selected = [0u8; 2];
+    proxy_stream.read_exact(&mut selected).await?;
+    if VERSION != selected[0] {
+        return Err(Error::new(ErrorKind::InvalidData, format!("invalid proxy version {}", selected[0])));
+    }
+    match (selected[1], credentials) {
+        (METHOD_NO_AUTH, _) => Ok(()),
+        (METHOD_USERNAME_PASSWORD, Some((username, password))) => {
+            if username.len() > u8::MAX as usize || password.len() > u8::MAX as usize {
+                return Err(Error::new(ErrorKind::InvalidInput, "credentials too long"));
+            }
+            let mut auth_request = vec![AUTH_VERSION, username.len() as u8];
+            auth_request.extend_from_slice(username.as_bytes());
+            auth_request.push(password.len() as u8);
+            auth_request.extend_from_slice(password.as_bytes());
+            proxy_stream.write_all(&auth_request).await?;
+
+            let mut auth_reply = [0u8; 2];
+            proxy_stream.read_exact(&mut auth_reply).await?;
+            if AUTH_SUCCEEDED != auth_reply[1] {
+                return Err(Error::new(ErrorKind::PermissionDenied, "proxy rejected credentials"));
+            }
+            Ok(())
+        }
+        (METHOD_NO_ACCEPTABLE, _) => Err(Error::new(ErrorKind::PermissionDenied, "proxy accepted no offered method")),
+        (method, _) => Err(Error::new(ErrorKind::InvalidData, format!("invalid proxy method {}", method))),
+    }
+}
+
+fn reply_error_kind(rep: u8) -> ErrorKind {
+    match rep {
+        REP_NOT_ALLOWED => ErrorKind::PermissionDenied,
+        REP_NETWORK_UNREACHABLE => ErrorKind::NetworkUnreachable,
+        REP_HOST_UNREACHABLE => ErrorKind::HostUnreachable,
+        REP_CONNECTION_REFUSED => ErrorKind::ConnectionRefused,
+        _ => ErrorKind::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[tokio::test]
+    async fn handshake_sends_credentials_and_request() {
+        let (mut proxy_stream, mut parent) = tokio::io::duplex(1024);
+        let dst_addr = Address::new(Target::Ipv4(Ipv4Addr::LOCALHOST), 80);
+        let parent = tokio::spawn(async move {
+            let mut received = [0u8; 4 + 11 + 10];
+            parent.write_all(&[VERSION, METHOD_USERNAME_PASSWORD, AUTH_VERSION, AUTH_SUCCEEDED]).await.unwrap();
+            parent.write_all(&[VERSION, REP_SUCCEEDED, 0, 1, 127, 0, 0, 1, 0, 80]).await.unwrap();
+            parent.read_exact(&mut received).await.unwrap();
+            received
+        });
+
+        handshake(&mut proxy_stream, Some(("user", "pass")), &dst_addr).await.unwrap();
+        let received = parent.await.unwrap();
+        assert_eq!(received[..4], [VERSION, 2, METHOD_USERNAME_PASSWORD, METHOD_NO_AUTH]);
+        assert_eq!(received[4..15], *b"\x01\x04user\x04pass");
+        assert_eq!(received[15..], [VERSION, CMD_CONNECT, 0, 1, 127, 0, 0, 1, 0, 80]);
+    }
+
+    #[tokio::test]
+    async fn handshake_reports_the_parent_reply_code() {
+        let (mut proxy_stream, mut parent) = tokio::io::duplex(1024);
+        let dst_addr = Address::new(Target::Ipv4(Ipv4Addr::LOCALHOST), 80);
+        parent.write_all(&[VERSION, METHOD_NO_AUTH, VERSION, REP_CONNECTION_REFUSED, 0, 1, 0, 0, 0, 0, 0, 0]).await.unwrap();
+
+        let err = handshake(&mut proxy_stream, None, &dst_addr).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn rejected_credentials_are_permission_denied() {
+        let (mut proxy_stream, mut parent) = tokio::io::duplex(1024);
+        parent.write_all(&[VERSION, METHOD_USERNAME_PASSWORD, AUTH_VERSION, 1]).await.unwrap();
+        let err = negotiate(&mut proxy_stream, Some(("user", "wrong"))).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+
+        let (mut proxy_stream, mut parent) = tokio::io::duplex(1024);
+        parent.write_all(&[VERSION, METHOD_NO_ACCEPTABLE]).await.unwrap();
+        let err = negotiate(&mut proxy_stream, None).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    }
+}
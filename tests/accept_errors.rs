@@ -0,0 +1,79 @@
+//! Runs alone in its own binary: it exhausts the process's file descriptors, which would break
+//! any test running alongside it.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::fs::File;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::*;
+use socks_lib::{Config, LogLevel, Server};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpSocket;
+
+fn nofile_limit() -> libc::rlimit {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    assert_eq!(unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) }, 0);
+    limit
+}
+
+fn set_nofile_limit(limit: libc::rlimit) {
+    assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) }, 0);
+}
+
+/// A config counting the failed accepts its server logs in `failures`.
+fn counting_accept_failures(failures: &Arc<AtomicUsize>) -> socks_lib::ConfigBuilder {
+    let failures = failures.clone();
+    let log_fn = move |_level: LogLevel, line: &str| {
+        if line.starts_with("accept failed") {
+            failures.fetch_add(1, Ordering::Relaxed);
+        }
+    };
+    Config::builder().local_addr("127.0.0.1").local_port(0).log_fn(Arc::new(log_fn))
+}
+
+#[tokio::test]
+async fn servers_survive_descriptor_exhaustion() {
+    let echo_addr = echo_server().await;
+    let failures = [Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0))];
+    let proxy_addr = spawn_proxy(counting_accept_failures(&failures[0])).await;
+    let sequential = Server::new(counting_accept_failures(&failures[1]).build()).bind().await.unwrap();
+    let sequential_addr = sequential.local_addr().unwrap();
+    tokio::spawn(sequential.run_sequential());
+    let client_sockets = [TcpSocket::new_v4().unwrap(), TcpSocket::new_v4().unwrap()];
+
+    // Leave no descriptor for the servers to accept into while the clients' connections are queued.
+    let original_limit = nofile_limit();
+    set_nofile_limit(libc::rlimit { rlim_cur: 256.min(original_limit.rlim_max), ..original_limit });
+    let mut fillers = Vec::new();
+    while let Ok(filler) = File::open("/dev/null") {
+        fillers.push(filler);
+    }
+    let mut streams = Vec::new();
+    for (client_socket, proxy_addr) in client_sockets.into_iter().zip([proxy_addr, sequential_addr]) {
+        streams.push(client_socket.connect(proxy_addr).await.unwrap());
+    }
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    drop(fillers);
+    set_nofile_limit(original_limit);
+    // Both servers backed off instead of spinning on the failing accept.
+    for failures in &failures {
+        let failures = failures.load(Ordering::Relaxed);
+        assert!((1..20).contains(&failures), "{} failed accepts", failures);
+    }
+
+    for mut stream in streams {
+        stream.write_all(&[5, 1, 0]).await.unwrap();
+        assert_eq!(read_n(&mut stream, 2).await, [5, 0]);
+        stream.write_all(&connect_request(echo_addr)).await.unwrap();
+        assert_eq!(read_reply(&mut stream).await.0, 0);
+        assert_echo(&mut stream, b"ping").await;
+    }
+
+    let mut stream = connect(proxy_addr, echo_addr).await;
+    assert_echo(&mut stream, b"pong").await;
+}
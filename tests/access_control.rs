@@ -0,0 +1,85 @@
+mod common;
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use common::*;
+use socks_lib::{AccessControl, Action, Config, Rule};
+use tokio::io::AsyncWriteExt;
+
+#[tokio::test]
+async fn allowed_domain_connects() {
+    let echo_addr = echo_server().await;
+    let access_control = AccessControl::new(Action::Deny).rule(Rule::domain("*.allowed.test", Action::Allow));
+    let resolver = FixedResolver::new([IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+    let proxy_addr = spawn_proxy(Config::builder().access_control(access_control).resolver(resolver)).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&domain_request(1, b"echo.allowed.test", echo_addr.port())).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 0);
+    assert_echo(&mut stream, b"ping").await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&domain_request(1, b"echo.other.test", echo_addr.port())).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 2);
+}
+
+#[tokio::test]
+async fn denied_cidr_is_not_allowed() {
+    let echo_addr = echo_server().await;
+    let access_control = AccessControl::new(Action::Allow).rule(Rule::cidr("127.0.0.0/8", Action::Deny).unwrap());
+    let proxy_addr = spawn_proxy(Config::builder().access_control(access_control)).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&connect_request(echo_addr)).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 2);
+    assert!(is_closed(&mut stream).await);
+
+    // The same target written IPv4-mapped is still denied.
+    let mapped_addr = SocketAddr::new(IpAddr::V6(Ipv4Addr::LOCALHOST.to_ipv6_mapped()), echo_addr.port());
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&connect_request(mapped_addr)).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 2);
+}
+
+#[tokio::test]
+async fn default_action_applies_without_a_match() {
+    let echo_addr = echo_server().await;
+    let access_control = AccessControl::new(Action::Deny).rule(Rule::cidr("10.0.0.0/8", Action::Allow).unwrap());
+    let proxy_addr = spawn_proxy(Config::builder().access_control(access_control)).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&connect_request(echo_addr)).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 2);
+
+    let access_control = AccessControl::new(Action::Allow).rule(Rule::cidr("10.0.0.0/8", Action::Deny).unwrap());
+    let proxy_addr = spawn_proxy(Config::builder().access_control(access_control)).await;
+    connect(proxy_addr, echo_addr).await;
+}
+
+#[tokio::test]
+async fn private_addresses_are_blocked_after_resolution() {
+    let echo_addr = echo_server().await;
+    let resolver = FixedResolver::new([IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+    let proxy_addr = spawn_proxy(Config::builder().block_private_addresses(true).resolver(resolver)).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&domain_request(1, b"internal.test", echo_addr.port())).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 2);
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&connect_request(echo_addr)).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 2);
+}
+
+#[tokio::test]
+async fn public_addresses_pass_the_private_check() {
+    // Nothing answers on this documentation address, so the dial times out rather than being refused.
+    let resolver = FixedResolver::new([IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1))]);
+    let config = Config::builder().block_private_addresses(true).resolver(resolver).connect_timeout(Some(Duration::from_millis(100)));
+    let proxy_addr = spawn_proxy(config).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&domain_request(1, b"public.test", 80)).await.unwrap();
+    assert_ne!(read_reply(&mut stream).await.0, 2);
+}
@@ -0,0 +1,180 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use common::*;
+use socks_lib::{AccessControl, Action, Authenticator, BoxFuture, Config, Rule};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpSocket, TcpStream};
+
+async fn login(proxy_addr: SocketAddr, username: &[u8], password: &[u8]) -> (TcpStream, u8) {
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream.write_all(&[5, 1, 2]).await.unwrap();
+    assert_eq!(read_n(&mut stream, 2).await, [5, 2]);
+    let mut auth = vec![1, username.len() as u8];
+    auth.extend_from_slice(username);
+    auth.push(password.len() as u8);
+    auth.extend_from_slice(password);
+    stream.write_all(&auth).await.unwrap();
+    let status = read_n(&mut stream, 2).await;
+    assert_eq!(status[0], 1);
+    (stream, status[1])
+}
+
+#[tokio::test]
+async fn correct_credentials_connect() {
+    let echo_addr = echo_server().await;
+    let proxy_addr = spawn_proxy(Config::builder().auth("user", "password")).await;
+
+    let (mut stream, status) = login(proxy_addr, b"user", b"password").await;
+    assert_eq!(status, 0);
+    stream.write_all(&connect_request(echo_addr)).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 0);
+    assert_echo(&mut stream, b"ping").await;
+}
+
+#[tokio::test]
+async fn wrong_credentials_fail_and_close() {
+    let proxy_addr = spawn_proxy(Config::builder().auth("user", "password")).await;
+
+    let (mut stream, status) = login(proxy_addr, b"user", b"wrong").await;
+    assert_eq!(status, 1);
+    assert!(is_closed(&mut stream).await);
+
+    let (mut stream, status) = login(proxy_addr, b"nobody", b"password").await;
+    assert_eq!(status, 1);
+    assert!(is_closed(&mut stream).await);
+}
+
+#[tokio::test]
+async fn required_auth_not_offered_is_rejected() {
+    let proxy_addr = spawn_proxy(Config::builder().auth("user", "password").require_auth(true)).await;
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    assert_eq!(read_n(&mut stream, 2).await, [5, 0xFF]);
+    assert!(is_closed(&mut stream).await);
+}
+
+#[tokio::test]
+async fn no_acceptable_method_is_rejected() {
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream.write_all(&[5, 1, 2]).await.unwrap();
+    assert_eq!(read_n(&mut stream, 2).await, [5, 0xFF]);
+    assert!(is_closed(&mut stream).await);
+}
+
+#[tokio::test]
+async fn offered_method_is_selected() {
+    let proxy_addr = spawn_proxy(Config::builder().auth("user", "password")).await;
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream.write_all(&[5, 2, 0xFE, 0]).await.unwrap();
+    assert_eq!(read_n(&mut stream, 2).await, [5, 0]);
+}
+
+/// Offer `methods` in one greeting and return the method the proxy selected.
+async fn selected_method(proxy_addr: SocketAddr, methods: &[u8]) -> u8 {
+    selected_method_from([127, 0, 0, 1], proxy_addr, methods).await
+}
+
+#[tokio::test]
+async fn method_preference_decides_between_offered_methods() {
+    let proxy_addr = spawn_proxy(Config::builder().auth("user", "password")).await;
+    assert_eq!(selected_method(proxy_addr, &[0, 2]).await, 2);
+
+    let proxy_addr = spawn_proxy(Config::builder().auth("user", "password").method_preference([0, 2])).await;
+    assert_eq!(selected_method(proxy_addr, &[2, 0]).await, 0);
+    assert_eq!(selected_method(proxy_addr, &[2]).await, 2);
+
+    // Enabled methods left out of the preference are never picked.
+    let proxy_addr = spawn_proxy(Config::builder().auth("user", "password").method_preference([2])).await;
+    assert_eq!(selected_method(proxy_addr, &[0]).await, 0xFF);
+}
+
+#[tokio::test]
+async fn gssapi_is_never_selected() {
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+    assert_eq!(selected_method(proxy_addr, &[1]).await, 0xFF);
+    assert_eq!(selected_method(proxy_addr, &[1, 0]).await, 0);
+
+    let proxy_addr = spawn_proxy(Config::builder().auth("user", "password").require_auth(true)).await;
+    assert_eq!(selected_method(proxy_addr, &[1]).await, 0xFF);
+    assert_eq!(selected_method(proxy_addr, &[1, 2]).await, 2);
+}
+
+#[tokio::test]
+async fn gssapi_only_client_gets_no_acceptable_methods_and_eof() {
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream.write_all(&[5, 1, 1]).await.unwrap();
+    assert_eq!(read_n(&mut stream, 2).await, [5, 0xFF]);
+    let mut buffer = [0u8; 16];
+    let read = tokio::time::timeout(PROMPTLY, stream.read(&mut buffer)).await.expect("connection left open");
+    assert_eq!(read.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn required_auth_accepts_valid_credentials() {
+    let echo_addr = echo_server().await;
+    let proxy_addr = spawn_proxy(Config::builder().auth("user", "password").require_auth(true)).await;
+
+    let (mut stream, status) = login(proxy_addr, b"user", b"password").await;
+    assert_eq!(status, 0);
+    stream.write_all(&connect_request(echo_addr)).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 0);
+    assert_echo(&mut stream, b"ping").await;
+
+    let (mut stream, status) = login(proxy_addr, b"user", b"wrong").await;
+    assert_eq!(status, 1);
+    assert!(is_closed(&mut stream).await);
+}
+
+/// Allows `alice` with any password, as an external backend would decide.
+struct AliceOnly;
+
+impl Authenticator for AliceOnly {
+    fn authenticate<'a>(&'a self, username: &'a [u8], _password: &'a [u8]) -> BoxFuture<'a, bool> {
+        Box::pin(async move { username == b"alice" })
+    }
+}
+
+#[tokio::test]
+async fn authenticator_decides_logins() {
+    let proxy_addr = spawn_proxy(Config::builder().authenticator(Arc::new(AliceOnly)).require_auth(true)).await;
+
+    let (_stream, status) = login(proxy_addr, b"alice", b"anything").await;
+    assert_eq!(status, 0);
+
+    let (mut stream, status) = login(proxy_addr, b"mallory", b"anything").await;
+    assert_eq!(status, 1);
+    assert!(is_closed(&mut stream).await);
+}
+
+/// Like [`selected_method`], connecting from `source`.
+async fn selected_method_from(source: [u8; 4], proxy_addr: SocketAddr, methods: &[u8]) -> u8 {
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind(SocketAddr::from((source, 0))).unwrap();
+    let mut stream = socket.connect(proxy_addr).await.unwrap();
+    let mut greeting = vec![5, methods.len() as u8];
+    greeting.extend_from_slice(methods);
+    stream.write_all(&greeting).await.unwrap();
+    let selection = read_n(&mut stream, 2).await;
+    assert_eq!(selection[0], 5);
+    selection[1]
+}
+
+#[tokio::test]
+async fn no_auth_sources_choose_the_method_per_client() {
+    let trusted = AccessControl::new(Action::Deny).rule(Rule::cidr("127.0.0.1/32", Action::Allow).unwrap());
+    let config = Config::builder().auth("user", "password").require_auth(true).no_auth_sources(trusted);
+    let proxy_addr = spawn_proxy(config).await;
+
+    assert_eq!(selected_method_from([127, 0, 0, 1], proxy_addr, &[0]).await, 0);
+    assert_eq!(selected_method_from([127, 0, 0, 2], proxy_addr, &[0]).await, 0xFF);
+    assert_eq!(selected_method_from([127, 0, 0, 2], proxy_addr, &[0, 2]).await, 2);
+}
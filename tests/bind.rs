@@ -0,0 +1,62 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use common::*;
+use socks_lib::{AccessControl, Action, Config, Rule};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpSocket, TcpStream};
+
+async fn connect_from(source_ip: [u8; 4], addr: SocketAddr) -> TcpStream {
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind(SocketAddr::from((source_ip, 0))).unwrap();
+    socket.connect(addr).await.unwrap()
+}
+
+#[tokio::test]
+async fn bind_relays_the_inbound_connection() {
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&request(2, SocketAddr::from(([0, 0, 0, 0], 0)))).await.unwrap();
+    let (rep, bind_addr) = read_reply(&mut stream).await;
+    assert_eq!(rep, 0);
+
+    let mut peer = TcpStream::connect(bind_addr).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await, (0, peer.local_addr().unwrap()));
+    assert_relayed(&mut stream, &mut peer, b"ping").await;
+    assert_relayed(&mut peer, &mut stream, b"pong").await;
+}
+
+#[tokio::test]
+async fn bind_only_accepts_the_expected_peer() {
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&request(2, SocketAddr::from(([127, 0, 0, 2], 0)))).await.unwrap();
+    let (_, bind_addr) = read_reply(&mut stream).await;
+
+    let mut stranger = connect_from([127, 0, 0, 1], bind_addr).await;
+    assert!(is_closed(&mut stranger).await);
+    let mut peer = connect_from([127, 0, 0, 2], bind_addr).await;
+    assert_eq!(read_reply(&mut stream).await, (0, peer.local_addr().unwrap()));
+    assert_relayed(&mut peer, &mut stream, b"pong").await;
+}
+
+#[tokio::test]
+async fn bind_peers_pass_access_control() {
+    let access_control = AccessControl::new(Action::Allow).rule(Rule::cidr("127.0.0.1/32", Action::Deny).unwrap());
+    let proxy_addr = spawn_proxy(Config::builder().access_control(access_control).bind_timeout(Some(Duration::from_millis(300)))).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&request(2, SocketAddr::from(([127, 0, 0, 1], 0)))).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 2);
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&request(2, SocketAddr::from(([0, 0, 0, 0], 0)))).await.unwrap();
+    let (_, bind_addr) = read_reply(&mut stream).await;
+    let mut denied = connect_from([127, 0, 0, 1], bind_addr).await;
+    assert!(is_closed(&mut denied).await);
+    assert_eq!(read_reply(&mut stream).await.0, 4);
+}
@@ -0,0 +1,89 @@
+#![cfg(feature = "cancellation")]
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use common::*;
+use socks_lib::{CancellationToken, CloseReason, Config, ConnectionSummary, EventHandler, Server, ShutdownStats};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::{TcpListener, TcpStream};
+
+fn loopback() -> socks_lib::ConfigBuilder {
+    Config::builder().local_addr("127.0.0.1").local_port(0)
+}
+
+/// Records why each relay ended.
+#[derive(Default)]
+struct CloseReasons(Mutex<Vec<CloseReason>>);
+
+impl EventHandler for CloseReasons {
+    fn on_summary(&self, summary: &ConnectionSummary) {
+        self.0.lock().unwrap().push(summary.close_reason);
+    }
+}
+
+/// Assert the peer ends the stream with FIN rather than a reset.
+async fn assert_eof<R: AsyncRead + Unpin>(reader: &mut R) {
+    let mut buffer = [0u8; 16];
+    let read = tokio::time::timeout(PROMPTLY, reader.read(&mut buffer)).await.expect("stream stayed open");
+    assert_eq!(read.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn one_token_cancels_every_server_and_connection() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let token = CancellationToken::new();
+    let close_reasons = Arc::new(CloseReasons::default());
+    let mut proxies = Vec::new();
+    for _ in 0..2 {
+        let server = Server::new(loopback().event_handler(close_reasons.clone()).build()).bind().await.unwrap();
+        let proxy_addr = server.local_addr().unwrap();
+        proxies.push((proxy_addr, tokio::spawn(server.run_with_token(token.clone()))));
+    }
+    let mut tunnels = Vec::new();
+    for (proxy_addr, _) in &proxies {
+        let stream = connect(*proxy_addr, target.local_addr().unwrap()).await;
+        let (remote, _) = target.accept().await.unwrap();
+        tunnels.push((stream, remote));
+    }
+
+    token.cancel();
+    for (proxy_addr, running) in proxies {
+        let stats = tokio::time::timeout(PROMPTLY, running).await.expect("server kept running").unwrap().unwrap();
+        assert_eq!(stats, ShutdownStats { served: 1, aborted: 0 });
+        assert!(TcpStream::connect(proxy_addr).await.is_err());
+    }
+    for (mut stream, mut remote) in tunnels {
+        assert_eof(&mut stream).await;
+        assert_eof(&mut remote).await;
+    }
+    assert_eq!(close_reasons.0.lock().unwrap()[..], [CloseReason::Cancelled, CloseReason::Cancelled]);
+}
+
+#[tokio::test]
+async fn cancellation_stops_handshakes_in_progress() {
+    let token = CancellationToken::new();
+    let server = Server::new(loopback().build()).bind().await.unwrap();
+    let proxy_addr = server.local_addr().unwrap();
+    let running = tokio::spawn(server.run_with_token(token.clone()));
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    token.cancel();
+    let started = Instant::now();
+    let stats = tokio::time::timeout(PROMPTLY, running).await.expect("server kept running").unwrap().unwrap();
+    assert!(started.elapsed() < Duration::from_secs(1));
+    assert_eq!(stats, ShutdownStats { served: 1, aborted: 0 });
+    assert!(is_closed(&mut stream).await);
+}
+
+#[tokio::test]
+async fn cancelled_token_stops_the_server_at_once() {
+    let token = CancellationToken::new();
+    token.cancel();
+    let server = Server::new(loopback().build());
+    let stats = tokio::time::timeout(PROMPTLY, server.handle_with_token(token)).await.expect("server kept running").unwrap();
+    assert_eq!(stats, ShutdownStats::default());
+}
@@ -0,0 +1,88 @@
+mod common;
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use common::*;
+use socks_lib::Config;
+use tokio::io::AsyncWriteExt;
+
+#[tokio::test]
+async fn connect_through_a_parent_proxy() {
+    let echo_addr = echo_server().await;
+    let parent_addr = spawn_proxy(Config::builder()).await;
+    let proxy_addr = spawn_proxy(Config::builder().upstream_proxy(Some(parent_addr))).await;
+
+    let mut stream = connect(proxy_addr, echo_addr).await;
+    assert_echo(&mut stream, b"ping").await;
+}
+
+#[tokio::test]
+async fn domains_are_resolved_by_the_parent() {
+    let echo_addr = echo_server().await;
+    let parent_resolver = FixedResolver::new([IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+    let parent_addr = spawn_proxy(Config::builder().resolver(parent_resolver.clone())).await;
+    let local_resolver = FixedResolver::new([]);
+    let proxy_addr = spawn_proxy(Config::builder().upstream_proxy(Some(parent_addr)).resolver(local_resolver.clone())).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&domain_request(1, b"echo.test", echo_addr.port())).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 0);
+    assert_echo(&mut stream, b"ping").await;
+    assert_eq!(parent_resolver.queries(), ["echo.test"]);
+    assert!(local_resolver.queries().is_empty());
+}
+
+#[tokio::test]
+async fn parent_failures_are_passed_on() {
+    let parent_addr = spawn_proxy(Config::builder()).await;
+    let proxy_addr = spawn_proxy(Config::builder().upstream_proxy(Some(parent_addr))).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&connect_request(SocketAddr::from(([127, 0, 0, 1], closed_port())))).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 5);
+}
+
+#[tokio::test]
+async fn private_checks_apply_before_forwarding_domains() {
+    let echo_addr = echo_server().await;
+    let parent_addr = spawn_proxy(Config::builder().resolver(FixedResolver::new([IpAddr::V4(Ipv4Addr::LOCALHOST)]))).await;
+    let local_resolver = FixedResolver::new([IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+    let proxy_addr = spawn_proxy(Config::builder().upstream_proxy(Some(parent_addr)).block_private_addresses(true).resolver(local_resolver)).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&domain_request(1, b"internal.test", echo_addr.port())).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 2);
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&connect_request(echo_addr)).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 2);
+}
+
+#[tokio::test]
+async fn unresolvable_domains_are_left_to_the_parent() {
+    let echo_addr = echo_server().await;
+    let parent_addr = spawn_proxy(Config::builder().resolver(FixedResolver::new([IpAddr::V4(Ipv4Addr::LOCALHOST)]))).await;
+    let proxy_addr = spawn_proxy(Config::builder().upstream_proxy(Some(parent_addr)).block_private_addresses(true).resolver(FixedResolver::new([]))).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&domain_request(1, b"echo.test", echo_addr.port())).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 0);
+}
+
+#[tokio::test]
+async fn parent_credentials_are_sent_when_chaining() {
+    let echo_addr = echo_server().await;
+    let parent_addr = spawn_proxy(Config::builder().auth("parent", "swordfish").require_auth(true)).await;
+
+    let proxy_addr = spawn_proxy(Config::builder().upstream_proxy(Some(parent_addr)).upstream_auth("parent", "swordfish")).await;
+    let mut stream = connect(proxy_addr, echo_addr).await;
+    assert_echo(&mut stream, b"ping").await;
+
+    for proxy_config in [Config::builder().upstream_auth("parent", "wrong"), Config::builder()] {
+        let proxy_addr = spawn_proxy(proxy_config.upstream_proxy(Some(parent_addr))).await;
+        let mut stream = greet(proxy_addr).await;
+        stream.write_all(&connect_request(echo_addr)).await.unwrap();
+        assert_eq!(read_reply(&mut stream).await.0, 2);
+        assert!(is_closed(&mut stream).await);
+    }
+}
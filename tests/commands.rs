@@ -0,0 +1,65 @@
+mod common;
+
+use std::time::Duration;
+
+use common::*;
+use socks_lib::Config;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+
+#[tokio::test]
+async fn each_command_is_dispatched() {
+    let echo_addr = echo_server().await;
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&request(1, echo_addr)).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 0);
+    assert_echo(&mut stream, b"ping").await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&request(2, "127.0.0.1:0".parse().unwrap())).await.unwrap();
+    let (rep, bind_addr) = read_reply(&mut stream).await;
+    assert_eq!(rep, 0);
+    assert_ne!(bind_addr.port(), 0);
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&request(3, "127.0.0.1:0".parse().unwrap())).await.unwrap();
+    let rep = read_reply(&mut stream).await.0;
+    assert_eq!(rep, if cfg!(feature = "udp") { 0 } else { 7 });
+}
+
+#[tokio::test]
+async fn unknown_command_is_not_supported() {
+    let echo_addr = echo_server().await;
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&request(0, echo_addr)).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 7);
+}
+
+#[tokio::test]
+async fn command_nine_gets_a_reply_before_close() {
+    let echo_addr = echo_server().await;
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&request(9, echo_addr)).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 7);
+    assert!(is_closed(&mut stream).await);
+}
+
+#[tokio::test]
+async fn nonzero_rsv_is_rejected_without_dialing() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+
+    let mut stream = greet(proxy_addr).await;
+    let mut frame = connect_request(target.local_addr().unwrap());
+    frame[2] = 1;
+    stream.write_all(&frame).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 1);
+    assert!(is_closed(&mut stream).await);
+    assert!(tokio::time::timeout(Duration::from_millis(200), target.accept()).await.is_err(), "target was dialed");
+}
@@ -0,0 +1,267 @@
+//! Helpers shared by the integration tests: loopback targets, a spawned proxy and raw SOCKS5
+//! frames, so each test spells out exactly the bytes it puts on the wire.
+
+#![allow(dead_code)]
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use socks_lib::{BoxFuture, ConfigBuilder, Resolver, Server};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+/// Upper bound for anything a test expects to happen promptly.
+pub const PROMPTLY: Duration = Duration::from_secs(5);
+
+/// Start a TCP server on `addr` that echoes every connection back to itself.
+pub async fn echo_server_on(addr: &str) -> SocketAddr {
+    let listener = TcpListener::bind(addr).await.unwrap();
+    let echo_addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    echo_addr
+}
+
+/// Start a TCP echo server on an ephemeral IPv4 loopback port.
+pub async fn echo_server() -> SocketAddr {
+    echo_server_on("127.0.0.1:0").await
+}
+
+/// Start a UDP server on `addr` that echoes every datagram back to its sender.
+pub async fn udp_echo_server_on(addr: &str) -> SocketAddr {
+    let socket = UdpSocket::bind(addr).await.unwrap();
+    let echo_addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buffer = vec![0u8; 65536];
+        loop {
+            let (n, src) = socket.recv_from(&mut buffer).await.unwrap();
+            let _ = socket.send_to(&buffer[..n], src).await;
+        }
+    });
+    echo_addr
+}
+
+/// Start a UDP echo server on an ephemeral IPv4 loopback port.
+pub async fn udp_echo_server() -> SocketAddr {
+    udp_echo_server_on("127.0.0.1:0").await
+}
+
+/// A loopback port nothing listens on.
+pub fn closed_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// Bind a server built from `config` to an ephemeral IPv4 loopback port and run it in the
+/// background.
+pub async fn spawn_proxy(config: ConfigBuilder) -> SocketAddr {
+    spawn_proxy_on("127.0.0.1", config).await
+}
+
+/// Like [`spawn_proxy`], listening on `host` instead.
+pub async fn spawn_proxy_on(host: &str, config: ConfigBuilder) -> SocketAddr {
+    let server = Server::new(config.local_addr(host).local_port(0).build()).bind().await.unwrap();
+    let proxy_addr = server.local_addr().unwrap();
+    tokio::spawn(server.run());
+    proxy_addr
+}
+
+/// Connect to the proxy and complete a no-auth greeting.
+pub async fn greet(proxy_addr: SocketAddr) -> TcpStream {
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    assert_eq!(read_n(&mut stream, 2).await, [5, 0]);
+    stream
+}
+
+/// Encode `addr` as ATYP, address and port.
+pub fn encode_addr(addr: SocketAddr) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    match addr {
+        SocketAddr::V4(v4) => {
+            buffer.push(1);
+            buffer.extend_from_slice(&v4.ip().octets());
+        }
+        SocketAddr::V6(v6) => {
+            buffer.push(4);
+            buffer.extend_from_slice(&v6.ip().octets());
+        }
+    }
+    buffer.extend_from_slice(&addr.port().to_be_bytes());
+    buffer
+}
+
+/// A SOCKS5 request for `cmd` to an IP target.
+pub fn request(cmd: u8, addr: SocketAddr) -> Vec<u8> {
+    let mut buffer = vec![5, cmd, 0];
+    buffer.extend(encode_addr(addr));
+    buffer
+}
+
+/// A SOCKS5 request for `cmd` to a domain target.
+pub fn domain_request(cmd: u8, domain: &[u8], port: u16) -> Vec<u8> {
+    let mut buffer = vec![5, cmd, 0, 3, domain.len() as u8];
+    buffer.extend_from_slice(domain);
+    buffer.extend_from_slice(&port.to_be_bytes());
+    buffer
+}
+
+/// A SOCKS5 CONNECT request to an IP target.
+pub fn connect_request(addr: SocketAddr) -> Vec<u8> {
+    request(1, addr)
+}
+
+/// Read a SOCKS5 reply with an IP bound address, returning REP and BND.ADDR.
+pub async fn read_reply<R: AsyncRead + Unpin>(reader: &mut R) -> (u8, SocketAddr) {
+    let header = read_n(reader, 4).await;
+    assert_eq!(header[0], 5);
+    let addr = match header[3] {
+        1 => {
+            let raw = read_n(reader, 6).await;
+            SocketAddr::from(([raw[0], raw[1], raw[2], raw[3]], u16::from_be_bytes([raw[4], raw[5]])))
+        }
+        4 => {
+            let raw = read_n(reader, 18).await;
+            let ip: [u8; 16] = raw[..16].try_into().unwrap();
+            SocketAddr::from((ip, u16::from_be_bytes([raw[16], raw[17]])))
+        }
+        atyp => panic!("unexpected ATYP {atyp} in reply"),
+    };
+    (header[1], addr)
+}
+
+/// Greet the proxy, CONNECT to `target` and assert the request succeeded.
+pub async fn connect(proxy_addr: SocketAddr, target: SocketAddr) -> TcpStream {
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&connect_request(target)).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 0);
+    stream
+}
+
+/// Build a SOCKS5 UDP datagram for `addr`.
+pub fn udp_datagram(frag: u8, addr: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let mut buffer = vec![0, 0, frag];
+    buffer.extend(encode_addr(addr));
+    buffer.extend_from_slice(payload);
+    buffer
+}
+
+/// Read exactly `n` bytes, failing the test if they do not arrive promptly.
+pub async fn read_n<R: AsyncRead + Unpin>(reader: &mut R, n: usize) -> Vec<u8> {
+    let mut buffer = vec![0u8; n];
+    tokio::time::timeout(PROMPTLY, reader.read_exact(&mut buffer)).await.expect("read timed out").unwrap();
+    buffer
+}
+
+/// Write `payload` and assert the same bytes come back.
+pub async fn assert_echo<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, payload: &[u8]) {
+    stream.write_all(payload).await.unwrap();
+    assert_eq!(read_n(stream, payload.len()).await, payload);
+}
+
+/// Whether the peer closes the stream (EOF or reset) within `within`.
+pub async fn closes_within<R: AsyncRead + Unpin>(reader: &mut R, within: Duration) -> bool {
+    let mut buffer = [0u8; 1024];
+    let deadline = tokio::time::Instant::now() + within;
+    loop {
+        match tokio::time::timeout_at(deadline, reader.read(&mut buffer)).await {
+            Ok(Ok(0)) | Ok(Err(_)) => return true,
+            Ok(Ok(_)) => continue,
+            Err(_) => return false,
+        }
+    }
+}
+
+/// Whether the peer closes the stream promptly.
+pub async fn is_closed<R: AsyncRead + Unpin>(reader: &mut R) -> bool {
+    closes_within(reader, PROMPTLY).await
+}
+
+/// A loopback address whose accept queue is full, so further connection attempts hang the way
+/// they do against a host that silently drops SYNs.
+pub struct Blackhole {
+    pub addr: SocketAddr,
+    _listener: TcpListener,
+    _queued: TcpStream,
+}
+
+pub async fn blackhole() -> Blackhole {
+    blackhole_on(SocketAddr::from(([127, 0, 0, 1], 0))).await
+}
+
+pub async fn blackhole_on(addr: SocketAddr) -> Blackhole {
+    let socket = tokio::net::TcpSocket::new_v4().unwrap();
+    socket.bind(addr).unwrap();
+    let listener = socket.listen(0).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let queued = TcpStream::connect(addr).await.unwrap();
+    Blackhole {
+        addr,
+        _listener: listener,
+        _queued: queued,
+    }
+}
+
+/// Write `payload` on `from` and assert it arrives on `to`.
+pub async fn assert_relayed<W: AsyncWrite + Unpin, R: AsyncRead + Unpin>(from: &mut W, to: &mut R, payload: &[u8]) {
+    from.write_all(payload).await.unwrap();
+    assert_eq!(read_n(to, payload.len()).await, payload);
+}
+
+/// Resolves every name to the same IPs, recording the names it was asked for.
+#[derive(Default)]
+pub struct FixedResolver {
+    pub ips: Vec<IpAddr>,
+    pub queries: Mutex<Vec<String>>,
+}
+
+impl FixedResolver {
+    pub fn new<I: IntoIterator<Item = IpAddr>>(ips: I) -> Arc<Self> {
+        Arc::new(FixedResolver {
+            ips: ips.into_iter().collect(),
+            queries: Default::default(),
+        })
+    }
+
+    pub fn queries(&self) -> Vec<String> {
+        self.queries.lock().unwrap().clone()
+    }
+}
+
+impl Resolver for FixedResolver {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        self.queries.lock().unwrap().push(host.to_string());
+        let addrs = self.ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect();
+        Box::pin(async move { Ok(addrs) })
+    }
+}
+
+/// A valid hostname of exactly `len` bytes, made of 63-byte labels.
+pub fn long_hostname(len: usize) -> String {
+    let mut hostname = String::new();
+    while hostname.len() < len {
+        if !hostname.is_empty() {
+            hostname.push('.');
+        }
+        let label_len = (len - hostname.len()).min(63);
+        hostname.push_str(&"a".repeat(label_len));
+    }
+    hostname
+}
+
+/// Poll `condition` until it holds, failing the test if it does not become true promptly.
+pub async fn wait_until<F: FnMut() -> bool>(mut condition: F) {
+    let deadline = tokio::time::Instant::now() + PROMPTLY;
+    while !condition() {
+        assert!(tokio::time::Instant::now() < deadline, "condition not reached in time");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
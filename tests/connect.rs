@@ -0,0 +1,140 @@
+mod common;
+
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use common::*;
+use socks_lib::{Address, BoxFuture, Config, EventHandler, Resolver, Server};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+
+#[tokio::test]
+async fn connect_ipv4_target() {
+    let echo_addr = echo_server().await;
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+
+    let mut stream = connect(proxy_addr, echo_addr).await;
+    assert_echo(&mut stream, b"ping").await;
+}
+
+#[tokio::test]
+async fn connect_ipv6_target() {
+    let echo_addr = echo_server_on("[::1]:0").await;
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+
+    let mut stream = connect(proxy_addr, echo_addr).await;
+    assert_echo(&mut stream, b"ping").await;
+}
+
+#[tokio::test]
+async fn refused_target_gets_connection_refused_reply() {
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&connect_request(SocketAddr::from(([127, 0, 0, 1], closed_port())))).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 5);
+    assert!(is_closed(&mut stream).await);
+}
+
+#[tokio::test]
+async fn reply_carries_outbound_local_address() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&connect_request(target_addr)).await.unwrap();
+    let (_, outbound_addr) = target.accept().await.unwrap();
+    assert_eq!(read_reply(&mut stream).await, (0, outbound_addr));
+}
+
+#[tokio::test]
+async fn blackholed_target_times_out() {
+    let blackhole = blackhole().await;
+    let proxy_addr = spawn_proxy(Config::builder().connect_timeout(Some(Duration::from_millis(200)))).await;
+
+    let mut stream = greet(proxy_addr).await;
+    let started = Instant::now();
+    stream.write_all(&connect_request(blackhole.addr)).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 4);
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    assert!(started.elapsed() < Duration::from_secs(2));
+}
+
+#[tokio::test]
+async fn outbound_connections_use_the_configured_source() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let proxy_addr = spawn_proxy(Config::builder().outbound_bind(Some(IpAddr::from([127, 0, 0, 2])))).await;
+
+    let _stream = connect(proxy_addr, target_addr).await;
+    let (_, outbound_addr) = target.accept().await.unwrap();
+    assert_eq!(outbound_addr.ip(), IpAddr::from([127, 0, 0, 2]));
+
+    // A source of the other family cannot reach the target.
+    let proxy_addr = spawn_proxy(Config::builder().outbound_bind(Some(IpAddr::from(Ipv6Addr::LOCALHOST)))).await;
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&connect_request(target_addr)).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 1);
+}
+
+#[tokio::test]
+async fn link_local_target_without_scope_id_fails_cleanly() {
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&connect_request("[fe80::1]:80".parse().unwrap())).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 1);
+    assert!(is_closed(&mut stream).await);
+}
+
+/// Resolves every name to `addr` after a pause.
+struct SlowResolver(SocketAddr);
+
+impl Resolver for SlowResolver {
+    fn resolve<'a>(&'a self, _host: &'a str, _port: u16) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        Box::pin(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            Ok(vec![self.0])
+        })
+    }
+}
+
+/// Counts established tunnels.
+#[derive(Default)]
+struct Connects(AtomicUsize);
+
+impl EventHandler for Connects {
+    fn on_connect(&self, _client: SocketAddr, _target: &Address) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[tokio::test]
+async fn client_gone_before_the_reply_releases_the_upstream() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let connects = Arc::new(Connects::default());
+    let config = Config::builder()
+        .local_addr("127.0.0.1")
+        .local_port(0)
+        .resolver(Arc::new(SlowResolver(target.local_addr().unwrap())))
+        .event_handler(connects.clone());
+    let server = Server::new(config.build());
+    let bound = server.bind().await.unwrap();
+    let proxy_addr = bound.local_addr().unwrap();
+    tokio::spawn(bound.run());
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&domain_request(1, b"slow.test", 80)).await.unwrap();
+    // Reset rather than close, so the reply write fails instead of landing in a buffer.
+    stream.set_linger(Some(Duration::ZERO)).unwrap();
+    drop(stream);
+
+    let (mut remote, _) = tokio::time::timeout(PROMPTLY, target.accept()).await.unwrap().unwrap();
+    assert!(is_closed(&mut remote).await);
+    wait_until(|| server.stats().active_connections == 0).await;
+    assert_eq!(connects.0.load(Ordering::SeqCst), 0);
+}
@@ -0,0 +1,97 @@
+mod common;
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use common::*;
+use socks_lib::{BoxFuture, Config, Resolver};
+use tokio::io::AsyncWriteExt;
+
+#[tokio::test]
+async fn dead_address_falls_through_to_a_live_one() {
+    let blackhole = blackhole().await;
+    let echo_addr = echo_server_on(&format!("127.0.0.2:{}", blackhole.addr.port())).await;
+    let resolver = FixedResolver::new([blackhole.addr.ip(), echo_addr.ip()]);
+    let proxy_addr = spawn_proxy(Config::builder().resolver(resolver)).await;
+
+    let mut stream = greet(proxy_addr).await;
+    let started = Instant::now();
+    stream.write_all(&domain_request(1, b"dual.test", echo_addr.port())).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 0);
+    assert!(started.elapsed() < Duration::from_secs(2), "waited {:?} on the dead address", started.elapsed());
+    assert_echo(&mut stream, b"ping").await;
+}
+
+#[tokio::test]
+async fn refused_address_falls_through_at_once() {
+    let port = closed_port();
+    let echo_addr = echo_server_on(&format!("127.0.0.2:{}", port)).await;
+    let resolver = FixedResolver::new([IpAddr::from([127, 0, 0, 1]), echo_addr.ip()]);
+    let proxy_addr = spawn_proxy(Config::builder().resolver(resolver)).await;
+
+    let mut stream = greet(proxy_addr).await;
+    let started = Instant::now();
+    stream.write_all(&domain_request(1, b"dual.test", port)).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 0);
+    assert!(started.elapsed() < Duration::from_millis(250), "waited {:?} on the refused address", started.elapsed());
+    assert_echo(&mut stream, b"ping").await;
+}
+
+/// Resolves to a refused port for the first `failures` queries and to `live` after that.
+struct FlakyResolver {
+    refused: SocketAddr,
+    live: SocketAddr,
+    failures: usize,
+    queries: AtomicUsize,
+}
+
+impl FlakyResolver {
+    fn new(live: SocketAddr, failures: usize) -> Arc<Self> {
+        Arc::new(FlakyResolver {
+            refused: SocketAddr::from(([127, 0, 0, 1], closed_port())),
+            live,
+            failures,
+            queries: AtomicUsize::new(0),
+        })
+    }
+}
+
+impl Resolver for FlakyResolver {
+    fn resolve<'a>(&'a self, _host: &'a str, _port: u16) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        let query = self.queries.fetch_add(1, Ordering::SeqCst);
+        let addr = if query < self.failures { self.refused } else { self.live };
+        Box::pin(async move { Ok(vec![addr]) })
+    }
+}
+
+#[tokio::test]
+async fn refused_connects_are_retried_until_one_succeeds() {
+    let echo_addr = echo_server().await;
+    let resolver = FlakyResolver::new(echo_addr, 2);
+    let config = Config::builder().resolver(resolver.clone()).connect_retries(2).retry_backoff(Duration::from_millis(50));
+    let proxy_addr = spawn_proxy(config).await;
+
+    let mut stream = greet(proxy_addr).await;
+    let started = Instant::now();
+    stream.write_all(&domain_request(1, b"flaky.test", echo_addr.port())).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 0);
+    assert!(started.elapsed() >= Duration::from_millis(100), "retried without backing off");
+    assert_eq!(resolver.queries.load(Ordering::SeqCst), 3);
+    assert_echo(&mut stream, b"ping").await;
+}
+
+#[tokio::test]
+async fn connect_fails_once_retries_run_out() {
+    let echo_addr = echo_server().await;
+    let resolver = FlakyResolver::new(echo_addr, 2);
+    let config = Config::builder().resolver(resolver.clone()).connect_retries(1).retry_backoff(Duration::from_millis(10));
+    let proxy_addr = spawn_proxy(config).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&domain_request(1, b"flaky.test", echo_addr.port())).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 5);
+    assert_eq!(resolver.queries.load(Ordering::SeqCst), 2);
+}
@@ -0,0 +1,164 @@
+mod common;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
+use common::*;
+use socks_lib::{Config, IpFamily};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+
+#[tokio::test]
+async fn long_domain_read_in_pieces() {
+    let echo_addr = echo_server().await;
+    let resolver = FixedResolver::new([IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+    let proxy_addr = spawn_proxy(Config::builder().resolver(resolver.clone())).await;
+    let domain = long_hostname(253);
+
+    let mut stream = greet(proxy_addr).await;
+    for piece in domain_request(1, domain.as_bytes(), echo_addr.port()).chunks(100) {
+        stream.write_all(piece).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(read_reply(&mut stream).await.0, 0);
+    assert_echo(&mut stream, b"ping").await;
+    assert_eq!(resolver.queries(), [domain]);
+}
+
+#[tokio::test]
+async fn empty_domain_is_rejected() {
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&domain_request(1, b"", 80)).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 1);
+    assert!(is_closed(&mut stream).await);
+}
+
+#[tokio::test]
+async fn longest_domains_are_read_whole() {
+    let echo_addr = echo_server().await;
+    let resolver = FixedResolver::new([IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+    let proxy_addr = spawn_proxy(Config::builder().resolver(resolver.clone())).await;
+
+    // The longest hostname, with its root label, still fits the length byte.
+    let domain = long_hostname(253) + ".";
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&domain_request(1, domain.as_bytes(), echo_addr.port())).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 0);
+    assert_echo(&mut stream, b"ping").await;
+
+    // SOCKS allows 255 bytes, but that is past RFC 1035's 253: the name is still read whole and
+    // then refused with a general failure rather than resolved.
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&domain_request(1, long_hostname(255).as_bytes(), echo_addr.port())).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 1);
+    assert!(is_closed(&mut stream).await);
+    assert_eq!(resolver.queries(), [domain]);
+}
+
+#[tokio::test]
+async fn domains_go_through_the_configured_resolver() {
+    let echo_addr = echo_server().await;
+    let resolver = FixedResolver::new([IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+    let proxy_addr = spawn_proxy(Config::builder().resolver(resolver.clone())).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&domain_request(1, b"echo.test", echo_addr.port())).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 0);
+    assert_echo(&mut stream, b"ping").await;
+    assert_eq!(resolver.queries(), ["echo.test"]);
+}
+
+#[tokio::test]
+async fn unresolvable_domain_gets_host_unreachable() {
+    let proxy_addr = spawn_proxy(Config::builder().resolver(FixedResolver::new([]))).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&domain_request(1, b"nowhere.test", 80)).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 4);
+}
+
+/// Listeners on `127.0.0.1` and `[::1]` sharing one port.
+async fn dual_stack_listeners() -> (TcpListener, TcpListener) {
+    loop {
+        let v4 = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        if let Ok(v6) = TcpListener::bind(("::1", v4.local_addr().unwrap().port())).await {
+            return (v4, v6);
+        }
+    }
+}
+
+#[tokio::test]
+async fn dns_family_picks_addresses_of_one_family() {
+    let (v4, v6) = dual_stack_listeners().await;
+    let port = v4.local_addr().unwrap().port();
+    let resolver = FixedResolver::new([IpAddr::V6(Ipv6Addr::LOCALHOST), IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+
+    for (dns_family, listener) in [(IpFamily::V4Only, &v4), (IpFamily::V6Only, &v6)] {
+        let proxy_addr = spawn_proxy(Config::builder().resolver(resolver.clone()).dns_family(dns_family)).await;
+        let mut stream = greet(proxy_addr).await;
+        stream.write_all(&domain_request(1, b"mixed.test", port)).await.unwrap();
+        assert_eq!(read_reply(&mut stream).await.0, 0);
+        let (mut remote, _) = tokio::time::timeout(PROMPTLY, listener.accept()).await.expect("wrong family dialed").unwrap();
+        assert_relayed(&mut stream, &mut remote, b"ping").await;
+    }
+}
+
+#[tokio::test]
+async fn dns_family_without_matching_addresses_is_unreachable() {
+    let echo_addr = echo_server().await;
+    let resolver = FixedResolver::new([IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+    let proxy_addr = spawn_proxy(Config::builder().resolver(resolver).dns_family(IpFamily::V6Only)).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&domain_request(1, b"v4only.test", echo_addr.port())).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 4);
+
+    // IP literals bypass the family filter.
+    let proxy_addr = spawn_proxy(Config::builder().dns_family(IpFamily::V6Only)).await;
+    connect(proxy_addr, echo_addr).await;
+}
+
+#[tokio::test]
+async fn invalid_hostnames_never_reach_the_resolver() {
+    let resolver = FixedResolver::new([IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+    let proxy_addr = spawn_proxy(Config::builder().resolver(resolver.clone())).await;
+
+    for domain in [&b"bad\0name.test"[..], b"-bad.test", b"bad name.test", b"\xff\xfe.test"] {
+        let mut stream = greet(proxy_addr).await;
+        stream.write_all(&domain_request(1, domain, 80)).await.unwrap();
+        assert_eq!(read_reply(&mut stream).await.0, 1);
+    }
+    assert!(resolver.queries().is_empty());
+}
+
+#[tokio::test]
+async fn internationalized_domains_are_resolved_as_punycode() {
+    let echo_addr = echo_server().await;
+    let resolver = FixedResolver::new([echo_addr.ip()]);
+    let proxy_addr = spawn_proxy(Config::builder().resolver(resolver.clone())).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&domain_request(1, "bücher.test".as_bytes(), echo_addr.port())).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 0);
+    assert_eq!(resolver.queries(), ["xn--bcher-kva.test"]);
+}
+
+#[tokio::test]
+async fn domains_over_max_domain_len_are_refused_unresolved() {
+    let echo_addr = echo_server().await;
+    let resolver = FixedResolver::new([echo_addr.ip()]);
+    let proxy_addr = spawn_proxy(Config::builder().resolver(resolver.clone()).max_domain_len(20)).await;
+
+    let longest = long_hostname(20);
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&domain_request(1, longest.as_bytes(), echo_addr.port())).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 0);
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&domain_request(1, long_hostname(21).as_bytes(), echo_addr.port())).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 1);
+    assert!(is_closed(&mut stream).await);
+    assert_eq!(resolver.queries(), [longest]);
+}
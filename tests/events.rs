@@ -0,0 +1,205 @@
+mod common;
+
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use common::*;
+use socks_lib::{Address, BoxFuture, CloseReason, Config, ConfigBuilder, ConnectionSummary, EventHandler, Resolver, Server, Target};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+
+#[derive(Default)]
+struct RecordingEvents {
+    connected: Mutex<Vec<(SocketAddr, Address)>>,
+    resolved: Mutex<Vec<(Address, SocketAddr)>>,
+    closed: Mutex<Vec<(SocketAddr, u64, u64)>>,
+    errors: Mutex<Vec<(SocketAddr, ErrorKind, String)>>,
+    summaries: Mutex<Vec<ConnectionSummary>>,
+}
+
+impl EventHandler for RecordingEvents {
+    fn on_connect(&self, client: SocketAddr, target: &Address) {
+        self.connected.lock().unwrap().push((client, target.clone()));
+    }
+
+    fn on_resolve(&self, _client: SocketAddr, target: &Address, remote: SocketAddr) {
+        self.resolved.lock().unwrap().push((target.clone(), remote));
+    }
+
+    fn on_close(&self, client: SocketAddr, bytes_up: u64, bytes_down: u64) {
+        self.closed.lock().unwrap().push((client, bytes_up, bytes_down));
+    }
+
+    fn on_error(&self, client: SocketAddr, err: &Error) {
+        self.errors.lock().unwrap().push((client, err.kind(), err.to_string()));
+    }
+
+    fn on_summary(&self, summary: &ConnectionSummary) {
+        self.summaries.lock().unwrap().push(summary.clone());
+    }
+}
+
+async fn spawn_recorded(config: ConfigBuilder) -> (SocketAddr, Arc<RecordingEvents>) {
+    let events = Arc::new(RecordingEvents::default());
+    (spawn_proxy(config.event_handler(events.clone())).await, events)
+}
+
+/// A target that reads `up` bytes, answers with `down` bytes and closes.
+async fn answering_target(up: usize, down: usize) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        read_n(&mut stream, up).await;
+        stream.write_all(&vec![b'd'; down]).await.unwrap();
+    });
+    target_addr
+}
+
+#[tokio::test]
+async fn relayed_bytes_are_counted_per_direction() {
+    let target_addr = answering_target(1000, 300).await;
+    let events = Arc::new(RecordingEvents::default());
+    let server = Server::new(Config::builder().local_port(0).event_handler(events.clone()).build());
+    let bound = server.bind().await.unwrap();
+    let proxy_addr = bound.local_addr().unwrap();
+    tokio::spawn(bound.run());
+
+    let mut stream = connect(proxy_addr, target_addr).await;
+    let client_addr = stream.local_addr().unwrap();
+    stream.write_all(&[b'u'; 1000]).await.unwrap();
+    assert_eq!(read_n(&mut stream, 300).await, [b'd'; 300]);
+    assert!(is_closed(&mut stream).await);
+    drop(stream);
+
+    wait_until(|| !events.closed.lock().unwrap().is_empty()).await;
+    assert_eq!(events.closed.lock().unwrap()[..], [(client_addr, 1000, 300)]);
+    assert_eq!(server.stats().total_bytes, 1300);
+}
+
+#[tokio::test]
+async fn hooks_see_connect_close_and_error() {
+    let echo_addr = echo_server().await;
+    let (proxy_addr, events) = spawn_recorded(Config::builder()).await;
+
+    let mut stream = connect(proxy_addr, echo_addr).await;
+    let client_addr = stream.local_addr().unwrap();
+    assert_echo(&mut stream, b"ping").await;
+    drop(stream);
+    wait_until(|| !events.closed.lock().unwrap().is_empty()).await;
+    assert_eq!(events.connected.lock().unwrap()[..], [(client_addr, Address::from(echo_addr))]);
+    assert_eq!(events.closed.lock().unwrap()[..], [(client_addr, 4, 4)]);
+
+    let mut stream = greet(proxy_addr).await;
+    let client_addr = stream.local_addr().unwrap();
+    stream.write_all(&connect_request(SocketAddr::from(([127, 0, 0, 1], closed_port())))).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 5);
+    wait_until(|| !events.errors.lock().unwrap().is_empty()).await;
+    let errors = events.errors.lock().unwrap();
+    assert_eq!((errors[0].0, errors[0].1), (client_addr, ErrorKind::ConnectionRefused));
+}
+
+struct PanickingResolver;
+
+impl Resolver for PanickingResolver {
+    fn resolve<'a>(&'a self, _host: &'a str, _port: u16) -> BoxFuture<'a, std::io::Result<Vec<SocketAddr>>> {
+        panic!("resolver exploded")
+    }
+}
+
+#[tokio::test]
+async fn handler_errors_reach_the_event_hook() {
+    let echo_addr = echo_server().await;
+    let (proxy_addr, events) = spawn_recorded(Config::builder().resolver(Arc::new(PanickingResolver))).await;
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream.write_all(&[6, 1, 0]).await.unwrap();
+    assert!(is_closed(&mut stream).await);
+    wait_until(|| events.errors.lock().unwrap().len() == 1).await;
+    assert_eq!(events.errors.lock().unwrap()[0].2, "invalid socks version 6");
+
+    // A panicking handler is reported as an error and leaves the server running.
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&domain_request(1, b"echo.test", echo_addr.port())).await.unwrap();
+    assert!(is_closed(&mut stream).await);
+    wait_until(|| events.errors.lock().unwrap().len() == 2).await;
+    assert!(events.errors.lock().unwrap()[1].2.contains("resolver exploded"));
+    connect(proxy_addr, echo_addr).await;
+}
+
+#[tokio::test]
+async fn resolved_address_is_reported_for_domains() {
+    let echo_addr = echo_server().await;
+    let resolver = FixedResolver::new([echo_addr.ip()]);
+    let (proxy_addr, events) = spawn_recorded(Config::builder().resolver(resolver)).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&domain_request(1, b"echo.test", echo_addr.port())).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 0);
+    let target = Address::new(Target::Domain("echo.test".to_string()), echo_addr.port());
+    wait_until(|| !events.resolved.lock().unwrap().is_empty()).await;
+    assert_eq!(events.resolved.lock().unwrap()[..], [(target, echo_addr)]);
+}
+
+impl RecordingEvents {
+    /// Wait for the first summary and return it.
+    async fn summary(&self) -> ConnectionSummary {
+        wait_until(|| !self.summaries.lock().unwrap().is_empty()).await;
+        self.summaries.lock().unwrap()[0].clone()
+    }
+}
+
+#[tokio::test]
+async fn summaries_total_a_finished_relay() {
+    let target_addr = answering_target(5, 7).await;
+    let (proxy_addr, events) = spawn_recorded(Config::builder()).await;
+
+    let mut stream = connect(proxy_addr, target_addr).await;
+    let client_addr = stream.local_addr().unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(read_n(&mut stream, 7).await, b"ddddddd");
+    drop(stream);
+
+    let summary = events.summary().await;
+    assert_eq!(summary.client, client_addr);
+    assert_eq!(summary.target, Address::from(target_addr));
+    assert_eq!((summary.bytes_up, summary.bytes_down), (5, 7));
+    assert!(summary.duration >= Duration::from_millis(100) && summary.duration < PROMPTLY, "{:?}", summary.duration);
+    assert_eq!(summary.close_reason, CloseReason::Eof);
+}
+
+#[tokio::test]
+async fn summaries_tell_timeouts_apart() {
+    let echo_addr = echo_server().await;
+    let (proxy_addr, events) = spawn_recorded(Config::builder().idle_timeout(Some(Duration::from_millis(100)))).await;
+    let _stream = connect(proxy_addr, echo_addr).await;
+    assert_eq!(events.summary().await.close_reason, CloseReason::IdleTimeout);
+
+    let (proxy_addr, events) = spawn_recorded(Config::builder().max_lifetime(Some(Duration::from_millis(100)))).await;
+    let _stream = connect(proxy_addr, echo_addr).await;
+    assert_eq!(events.summary().await.close_reason, CloseReason::MaxLifetime);
+}
+
+#[tokio::test]
+async fn summaries_report_errors_and_kills() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let (proxy_addr, events) = spawn_recorded(Config::builder()).await;
+    let _stream = connect(proxy_addr, target.local_addr().unwrap()).await;
+    let (remote, _) = target.accept().await.unwrap();
+    remote.set_linger(Some(Duration::ZERO)).unwrap();
+    drop(remote);
+    assert_eq!(events.summary().await.close_reason, CloseReason::Error(ErrorKind::ConnectionReset));
+
+    let events = Arc::new(RecordingEvents::default());
+    let server = Server::new(Config::builder().local_addr("127.0.0.1").local_port(0).event_handler(events.clone()).build());
+    let bound = server.bind().await.unwrap();
+    let proxy_addr = bound.local_addr().unwrap();
+    tokio::spawn(bound.run());
+    let _stream = connect(proxy_addr, target.local_addr().unwrap()).await;
+    let _remote = target.accept().await.unwrap();
+    assert!(server.kill(server.connections()[0].id));
+    assert_eq!(events.summary().await.close_reason, CloseReason::Cancelled);
+}
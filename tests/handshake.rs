@@ -0,0 +1,128 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use common::*;
+use socks_lib::{AccessControl, Action, Config, LogLevel, Rule};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+#[tokio::test]
+async fn silent_clients_are_closed_after_the_handshake_timeout() {
+    let proxy_addr = spawn_proxy(Config::builder().handshake_timeout(Some(Duration::from_millis(200)))).await;
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    let started = Instant::now();
+    assert!(is_closed(&mut stream).await);
+    assert!(started.elapsed() >= Duration::from_millis(150));
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    // A partial greeting does not extend the deadline.
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream.write_all(&[5]).await.unwrap();
+    assert!(closes_within(&mut stream, Duration::from_secs(2)).await);
+}
+
+#[tokio::test]
+async fn handshake_timeout_covers_every_phase_together() {
+    let trusted = AccessControl::new(Action::Deny).rule(Rule::cidr("127.0.0.1/32", Action::Allow).unwrap());
+    let config = Config::builder().accept_proxy_protocol(true).proxy_protocol_trusted(trusted);
+    let proxy_addr = spawn_proxy(config.handshake_timeout(Some(Duration::from_millis(400)))).await;
+
+    // The header and the greeting each take 250ms, under the limit alone but over it together.
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream.write_all(b"PROXY TCP4 203.0.113.7 ").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    stream.write_all(b"127.0.0.1 40000 1080\r\n").await.unwrap();
+    stream.write_all(&[5]).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    let _ = stream.write_all(&[1, 0]).await;
+    let mut reply = [0u8; 2];
+    assert!(!matches!(tokio::time::timeout(PROMPTLY, stream.read(&mut reply)).await.unwrap(), Ok(n) if n > 0));
+}
+
+#[tokio::test]
+async fn greeting_split_across_writes() {
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream.write_all(&[5, 2]).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    stream.write_all(&[2, 0]).await.unwrap();
+    assert_eq!(read_n(&mut stream, 2).await, [5, 0]);
+}
+
+#[tokio::test]
+async fn greeting_with_every_method_is_read_whole() {
+    let echo_addr = echo_server().await;
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+
+    let mut greeting = vec![5, 255];
+    greeting.extend((0..255u8).rev());
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream.write_all(&greeting).await.unwrap();
+    assert_eq!(read_n(&mut stream, 2).await, [5, 0]);
+    stream.write_all(&connect_request(echo_addr)).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 0);
+}
+
+#[tokio::test]
+async fn disconnects_during_the_handshake_are_quiet() {
+    let lines: Arc<Mutex<Vec<(LogLevel, String)>>> = Default::default();
+    let log_lines = lines.clone();
+    let log_fn = move |level: LogLevel, line: &str| log_lines.lock().unwrap().push((level, line.to_string()));
+    let proxy_addr = spawn_proxy(Config::builder().log_fn(Arc::new(log_fn))).await;
+
+    drop(TcpStream::connect(proxy_addr).await.unwrap());
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream.write_all(&[5]).await.unwrap();
+    drop(stream);
+
+    let disconnects = || lines.lock().unwrap().iter().filter(|(_, line)| line.contains("disconnected during handshake")).count();
+    wait_until(|| disconnects() == 2).await;
+    wait_until(|| lines.lock().unwrap().iter().filter(|(_, line)| line.ends_with(" closed")).count() == 2).await;
+    assert!(lines.lock().unwrap().iter().all(|(level, _)| *level != LogLevel::Warn), "{:?}", lines.lock().unwrap());
+    connect(proxy_addr, echo_server().await).await;
+}
+
+#[tokio::test]
+async fn greeting_without_methods_is_rejected() {
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream.write_all(&[5, 0]).await.unwrap();
+    assert_eq!(read_n(&mut stream, 2).await, [5, 0xFF]);
+    assert!(is_closed(&mut stream).await);
+}
+
+#[tokio::test]
+async fn greeting_and_request_in_one_write() {
+    let echo_addr = echo_server().await;
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    let mut pipelined = vec![5, 1, 0];
+    pipelined.extend(connect_request(echo_addr));
+    pipelined.extend_from_slice(b"early data");
+    stream.write_all(&pipelined).await.unwrap();
+    assert_eq!(read_n(&mut stream, 2).await, [5, 0]);
+    assert_eq!(read_reply(&mut stream).await.0, 0);
+    assert_eq!(read_n(&mut stream, 10).await, b"early data");
+}
+
+#[tokio::test]
+async fn greeting_login_and_request_in_one_write() {
+    let echo_addr = echo_server().await;
+    let proxy_addr = spawn_proxy(Config::builder().auth("user", "password")).await;
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    let mut pipelined = vec![5, 1, 2];
+    pipelined.extend_from_slice(b"\x01\x04user\x08password");
+    pipelined.extend(connect_request(echo_addr));
+    stream.write_all(&pipelined).await.unwrap();
+    assert_eq!(read_n(&mut stream, 2).await, [5, 2]);
+    assert_eq!(read_n(&mut stream, 2).await, [1, 0]);
+    assert_eq!(read_reply(&mut stream).await.0, 0);
+    assert_echo(&mut stream, b"ping").await;
+}
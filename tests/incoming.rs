@@ -0,0 +1,63 @@
+mod common;
+
+use common::*;
+use socks_lib::{Config, Server};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+fn loopback() -> socks_lib::ConfigBuilder {
+    Config::builder().local_addr("127.0.0.1").local_port(0)
+}
+
+#[tokio::test]
+async fn accepted_connections_can_be_served_by_the_caller() {
+    let echo_addr = echo_server().await;
+    let server = Server::new(loopback().build()).bind().await.unwrap();
+    let proxy_addr = server.local_addr().unwrap();
+    let mut incoming = server.incoming();
+
+    let client = tokio::spawn(async move {
+        let mut stream = connect(proxy_addr, echo_addr).await;
+        assert_echo(&mut stream, b"ping").await;
+        stream.local_addr().unwrap()
+    });
+    let connection = incoming.accept().await.unwrap();
+    let client_addr = connection.client_addr();
+    tokio::spawn(connection.serve());
+    assert_eq!(client.await.unwrap(), client_addr);
+}
+
+#[tokio::test]
+async fn accepted_connections_can_be_handled_raw() {
+    let server = Server::new(loopback().build()).bind().await.unwrap();
+    let proxy_addr = server.local_addr().unwrap();
+    let mut incoming = server.incoming();
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream.write_all(&[5, 2, 0, 2]).await.unwrap();
+    let mut connection = incoming.accept().await.unwrap();
+    assert_eq!(socks_lib::parse_greeting(&mut connection).await.unwrap(), [0, 2]);
+    connection.write_all(&[5, 0xFF]).await.unwrap();
+    drop(connection);
+    assert_eq!(read_n(&mut stream, 2).await, [5, 0xFF]);
+    assert!(is_closed(&mut stream).await);
+}
+
+#[tokio::test]
+async fn serving_past_the_per_client_cap_fails() {
+    let echo_addr = echo_server().await;
+    let server = Server::new(loopback().max_per_client(Some(1)).build());
+    let bound = server.bind().await.unwrap();
+    let proxy_addr = bound.local_addr().unwrap();
+    let mut incoming = bound.incoming();
+
+    let first = tokio::spawn(async move { connect(proxy_addr, echo_addr).await });
+    tokio::spawn(incoming.accept().await.unwrap().serve());
+    let mut first = first.await.unwrap();
+    let mut second = TcpStream::connect(proxy_addr).await.unwrap();
+    let err = incoming.accept().await.unwrap().serve().await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+    assert!(is_closed(&mut second).await);
+    assert_echo(&mut first, b"ping").await;
+    assert_eq!(server.stats().active_connections, 1);
+}
@@ -0,0 +1,90 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use common::*;
+use socks_lib::{Config, LimitPolicy, Server};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpSocket, TcpStream};
+
+#[tokio::test]
+async fn connections_over_the_cap_wait_for_a_slot() {
+    let echo_addr = echo_server().await;
+    let proxy_addr = spawn_proxy(Config::builder().max_connections(Some(1))).await;
+
+    let first = connect(proxy_addr, echo_addr).await;
+    let mut second = TcpStream::connect(proxy_addr).await.unwrap();
+    second.write_all(&[5, 1, 0]).await.unwrap();
+    assert!(tokio::time::timeout(Duration::from_millis(200), read_n(&mut second, 2)).await.is_err());
+
+    drop(first);
+    assert_eq!(read_n(&mut second, 2).await, [5, 0]);
+}
+
+#[tokio::test]
+async fn connections_over_the_cap_are_rejected() {
+    let echo_addr = echo_server().await;
+    let proxy_addr = spawn_proxy(Config::builder().max_connections(Some(1)).limit_policy(LimitPolicy::Reject)).await;
+
+    let mut first = connect(proxy_addr, echo_addr).await;
+    let mut second = TcpStream::connect(proxy_addr).await.unwrap();
+    assert!(is_closed(&mut second).await);
+    assert_echo(&mut first, b"ping").await;
+}
+
+#[tokio::test]
+async fn connections_over_the_per_client_cap_are_rejected() {
+    let echo_addr = echo_server().await;
+    let server = Server::new(Config::builder().local_addr("127.0.0.1").local_port(0).max_per_client(Some(2)).build());
+    let bound = server.bind().await.unwrap();
+    let proxy_addr = bound.local_addr().unwrap();
+    tokio::spawn(bound.run());
+
+    let mut first = connect(proxy_addr, echo_addr).await;
+    let _second = connect(proxy_addr, echo_addr).await;
+    let mut third = TcpStream::connect(proxy_addr).await.unwrap();
+    assert!(is_closed(&mut third).await);
+    assert_echo(&mut first, b"ping").await;
+
+    // Other sources have budgets of their own.
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind(SocketAddr::from(([127, 0, 0, 2], 0))).unwrap();
+    let mut other = socket.connect(proxy_addr).await.unwrap();
+    other.write_all(&[5, 1, 0]).await.unwrap();
+    assert_eq!(read_n(&mut other, 2).await, [5, 0]);
+
+    // Closing a connection frees its slot.
+    drop(first);
+    wait_until(|| server.stats().active_connections == 2).await;
+    connect(proxy_addr, echo_addr).await;
+}
+
+/// Fill an idle proxy's accept queue and count how many of `attempts` connects complete.
+#[cfg(target_os = "linux")]
+async fn queued_connects(listen_backlog: u32, attempts: usize) -> usize {
+    let echo_addr = echo_server().await;
+    // With its only slot taken the server stops accepting, leaving later clients in the queue.
+    let proxy_addr = spawn_proxy(Config::builder().max_connections(Some(1)).listen_backlog(listen_backlog)).await;
+    let _first = connect(proxy_addr, echo_addr).await;
+
+    let mut connects = Vec::new();
+    for _ in 0..attempts {
+        connects.push(tokio::spawn(tokio::time::timeout(Duration::from_millis(500), TcpStream::connect(proxy_addr))));
+    }
+    let mut queued = Vec::new();
+    for connect in connects {
+        if let Ok(Ok(stream)) = connect.await.unwrap() {
+            queued.push(stream);
+        }
+    }
+    queued.len()
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn listen_backlog_bounds_the_accept_queue() {
+    let queued = queued_connects(1, 8).await;
+    assert!(queued <= 3, "{} connects queued with a backlog of 1", queued);
+    assert_eq!(queued_connects(64, 8).await, 8);
+}
@@ -0,0 +1,42 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use common::*;
+use socks_lib::{Config, LogLevel};
+use tokio::io::AsyncWriteExt;
+
+#[tokio::test]
+async fn log_fn_receives_every_line_with_its_level() {
+    let lines: Arc<Mutex<Vec<(LogLevel, String)>>> = Default::default();
+    let log_lines = lines.clone();
+    let log_fn = move |level: LogLevel, line: &str| log_lines.lock().unwrap().push((level, line.to_string()));
+    let echo_addr = echo_server().await;
+    let proxy_addr = spawn_proxy(Config::builder().log_fn(Arc::new(log_fn))).await;
+
+    let mut stream = connect(proxy_addr, echo_addr).await;
+    let client_addr = stream.local_addr().unwrap();
+    assert_echo(&mut stream, b"ping").await;
+    drop(stream);
+
+    wait_until(|| lines.lock().unwrap().iter().any(|(_, line)| line == &format!("{} closed", client_addr))).await;
+    let lines = lines.lock().unwrap();
+    assert!(lines.iter().any(|(level, line)| *level == LogLevel::Debug && line == &format!("{} accepted", client_addr)), "{:?}", lines);
+    assert!(lines.iter().any(|(level, line)| *level == LogLevel::Info && line.contains(&format!("connected to {}", echo_addr))), "{:?}", lines);
+}
+
+#[tokio::test]
+async fn failed_connects_are_logged_as_warnings() {
+    let lines: Arc<Mutex<Vec<(LogLevel, String)>>> = Default::default();
+    let log_lines = lines.clone();
+    let log_fn = move |level: LogLevel, line: &str| log_lines.lock().unwrap().push((level, line.to_string()));
+    let proxy_addr = spawn_proxy(Config::builder().log_fn(Arc::new(log_fn))).await;
+
+    let target = SocketAddr::from(([127, 0, 0, 1], closed_port()));
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&connect_request(target)).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 5);
+    let failed = format!("connect to {} failed", target);
+    wait_until(|| lines.lock().unwrap().iter().any(|(level, line)| *level == LogLevel::Warn && line.contains(&failed))).await;
+}
@@ -0,0 +1,40 @@
+#![cfg(feature = "log")]
+
+mod common;
+
+use std::sync::Mutex;
+
+use common::*;
+use socks_lib::Config;
+
+/// Records every message, since a process has one global logger.
+struct RecordingLogger(Mutex<Vec<(log::Level, String)>>);
+
+impl log::Log for RecordingLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.0.lock().unwrap().push((record.level(), record.args().to_string()));
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: RecordingLogger = RecordingLogger(Mutex::new(Vec::new()));
+
+#[tokio::test]
+async fn connections_are_logged_through_the_log_facade() {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(log::LevelFilter::Debug);
+    let echo_addr = echo_server().await;
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+
+    let mut stream = connect(proxy_addr, echo_addr).await;
+    assert_echo(&mut stream, b"ping").await;
+
+    let records = LOGGER.0.lock().unwrap();
+    assert!(records.iter().any(|(level, message)| *level == log::Level::Info && message.contains(&format!("connected to {}", echo_addr))));
+    assert!(records.iter().any(|(level, message)| *level == log::Level::Debug && message.contains("accepted")));
+}
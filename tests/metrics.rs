@@ -0,0 +1,74 @@
+mod common;
+
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use common::*;
+use socks_lib::{Config, Metrics};
+use tokio::io::AsyncWriteExt;
+
+#[derive(Default)]
+struct Counting {
+    opened: AtomicU64,
+    closed: AtomicU64,
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+    connect_failures: Mutex<Vec<ErrorKind>>,
+}
+
+impl Metrics for Counting {
+    fn connection_opened(&self) {
+        self.opened.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn connection_closed(&self) {
+        self.closed.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn bytes_up(&self, bytes: u64) {
+        self.bytes_up.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    fn bytes_down(&self, bytes: u64) {
+        self.bytes_down.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    fn connect_failed(&self, reason: ErrorKind) {
+        self.connect_failures.lock().unwrap().push(reason);
+    }
+}
+
+#[tokio::test]
+async fn counters_follow_the_connection_lifecycle() {
+    let echo_addr = echo_server().await;
+    let metrics = Arc::new(Counting::default());
+    let proxy_addr = spawn_proxy(Config::builder().metrics(metrics.clone())).await;
+
+    let mut stream = connect(proxy_addr, echo_addr).await;
+    assert_eq!(metrics.opened.load(Ordering::SeqCst), 1);
+    assert_echo(&mut stream, b"ping").await;
+    assert_echo(&mut stream, b"pong!").await;
+    // Chunks are counted once written, which may be just after the echo arrives.
+    wait_until(|| metrics.bytes_up.load(Ordering::SeqCst) == 9 && metrics.bytes_down.load(Ordering::SeqCst) == 9).await;
+    assert_eq!(metrics.closed.load(Ordering::SeqCst), 0);
+
+    drop(stream);
+    wait_until(|| metrics.closed.load(Ordering::SeqCst) == 1).await;
+    assert!(metrics.connect_failures.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn connect_failures_are_counted_by_reason() {
+    let metrics = Arc::new(Counting::default());
+    let proxy_addr = spawn_proxy(Config::builder().metrics(metrics.clone())).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&connect_request(SocketAddr::from(([127, 0, 0, 1], closed_port())))).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 5);
+    assert_eq!(*metrics.connect_failures.lock().unwrap(), [ErrorKind::ConnectionRefused]);
+    drop(stream);
+    wait_until(|| metrics.closed.load(Ordering::SeqCst) == 1).await;
+    assert_eq!(metrics.opened.load(Ordering::SeqCst), 1);
+}
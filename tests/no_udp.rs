@@ -0,0 +1,32 @@
+#![cfg(not(feature = "udp"))]
+
+mod common;
+
+use std::net::SocketAddr;
+
+use common::*;
+use socks_lib::Config;
+use tokio::io::AsyncWriteExt;
+
+#[tokio::test]
+async fn associate_is_not_supported_without_the_udp_feature() {
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&request(3, SocketAddr::from(([0, 0, 0, 0], 0)))).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 7);
+    assert!(is_closed(&mut stream).await);
+}
+
+#[tokio::test]
+async fn tcp_commands_work_without_the_udp_feature() {
+    let echo_addr = echo_server().await;
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+
+    let mut stream = connect(proxy_addr, echo_addr).await;
+    assert_echo(&mut stream, b"ping").await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&request(2, SocketAddr::from(([127, 0, 0, 1], 0)))).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 0);
+}
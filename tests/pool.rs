@@ -0,0 +1,113 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::*;
+use socks_lib::Config;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+
+async fn accept(target: &TcpListener) -> TcpStream {
+    tokio::time::timeout(PROMPTLY, target.accept()).await.expect("target not dialed").unwrap().0
+}
+
+/// Accept `count` connections, of which one carries `client`'s tunnel and the others are spares
+/// dialed alongside it in no particular order. Returns the tunnel's end first.
+async fn accept_tunnel(target: &TcpListener, client: &mut TcpStream, count: usize) -> (TcpStream, Vec<TcpStream>) {
+    let mut remotes = Vec::new();
+    for _ in 0..count {
+        remotes.push(accept(target).await);
+    }
+    client.write_all(b"probe").await.unwrap();
+    let deadline = Instant::now() + PROMPTLY;
+    while Instant::now() < deadline {
+        for i in 0..remotes.len() {
+            let mut buffer = [0u8; 5];
+            if let Ok(Ok(5)) = tokio::time::timeout(Duration::from_millis(10), remotes[i].peek(&mut buffer)).await {
+                let mut tunnel = remotes.swap_remove(i);
+                assert_eq!(read_n(&mut tunnel, 5).await, b"probe");
+                return (tunnel, remotes);
+            }
+        }
+    }
+    panic!("no connection carried the tunnel");
+}
+
+/// Assert the target is dialed no more within a while.
+async fn assert_no_more_dials(target: &TcpListener) {
+    assert!(tokio::time::timeout(Duration::from_millis(300), target.accept()).await.is_err());
+}
+
+#[tokio::test]
+async fn back_to_back_connects_reuse_a_spare() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let proxy_addr = spawn_proxy(Config::builder().connection_pool(1, PROMPTLY)).await;
+
+    // The first CONNECT to a target gets no spare; the second one starts it off.
+    let mut first = connect(proxy_addr, target_addr).await;
+    let (_first_remote, _) = accept_tunnel(&target, &mut first, 1).await;
+    let mut second = connect(proxy_addr, target_addr).await;
+    let (mut second_remote, mut spares) = accept_tunnel(&target, &mut second, 2).await;
+    assert_relayed(&mut second, &mut second_remote, b"two").await;
+
+    let mut third = connect(proxy_addr, target_addr).await;
+    let mut spare = spares.pop().unwrap();
+    assert_relayed(&mut third, &mut spare, b"three").await;
+    assert_relayed(&mut spare, &mut third, b"back").await;
+    // Taking the spare dials exactly one replacement for the next CONNECT.
+    let _replacement = accept(&target).await;
+    assert_no_more_dials(&target).await;
+}
+
+#[tokio::test]
+async fn spares_are_dialed_only_to_replace_taken_ones() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let proxy_addr = spawn_proxy(Config::builder().connection_pool(1, Duration::from_millis(100))).await;
+
+    // CONNECTs further apart than the idle timeout would never use a spare, so none is dialed.
+    for _ in 0..3 {
+        let mut client = connect(proxy_addr, target_addr).await;
+        let (_remote, spares) = accept_tunnel(&target, &mut client, 1).await;
+        assert!(spares.is_empty());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    assert_no_more_dials(&target).await;
+}
+
+#[tokio::test]
+async fn expired_spares_are_not_reused() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let proxy_addr = spawn_proxy(Config::builder().connection_pool(1, Duration::from_millis(100))).await;
+
+    let mut first = connect(proxy_addr, target_addr).await;
+    let _first_remote = accept_tunnel(&target, &mut first, 1).await;
+    let mut second = connect(proxy_addr, target_addr).await;
+    let _second_remotes = accept_tunnel(&target, &mut second, 2).await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let mut third = connect(proxy_addr, target_addr).await;
+    let (mut third_remote, _) = accept_tunnel(&target, &mut third, 1).await;
+    assert_relayed(&mut third, &mut third_remote, b"fresh").await;
+}
+
+#[tokio::test]
+async fn spares_closed_by_the_target_are_not_reused() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let proxy_addr = spawn_proxy(Config::builder().connection_pool(1, PROMPTLY)).await;
+
+    let mut first = connect(proxy_addr, target_addr).await;
+    let _first_remote = accept_tunnel(&target, &mut first, 1).await;
+    let mut second = connect(proxy_addr, target_addr).await;
+    let (_second_remote, spares) = accept_tunnel(&target, &mut second, 2).await;
+    drop(spares);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // The closed spare is skipped and, the target still being busy, a new one is dialed with it.
+    let mut third = connect(proxy_addr, target_addr).await;
+    let (mut third_remote, _spare) = accept_tunnel(&target, &mut third, 2).await;
+    assert_relayed(&mut third, &mut third_remote, b"fresh").await;
+}
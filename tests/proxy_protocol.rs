@@ -0,0 +1,143 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use common::*;
+use socks_lib::{AccessControl, Action, Address, Config, ConfigBuilder, EventHandler, ProxyProtocol, Rule, Server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+#[tokio::test]
+async fn v1_header_names_the_client_and_target() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let proxy_addr = spawn_proxy(Config::builder().proxy_protocol(Some(ProxyProtocol::V1))).await;
+
+    let mut stream = connect(proxy_addr, target_addr).await;
+    let client_addr = stream.local_addr().unwrap();
+    let (mut remote, _) = target.accept().await.unwrap();
+    let expected = format!("PROXY TCP4 {} {} {} {}\r\n", client_addr.ip(), target_addr.ip(), client_addr.port(), target_addr.port());
+    assert_eq!(read_n(&mut remote, expected.len()).await, expected.as_bytes());
+    assert_relayed(&mut stream, &mut remote, b"ping").await;
+}
+
+#[tokio::test]
+async fn v2_header_names_the_client_and_target() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let proxy_addr = spawn_proxy(Config::builder().proxy_protocol(Some(ProxyProtocol::V2))).await;
+
+    let mut stream = connect(proxy_addr, target_addr).await;
+    let client_addr = stream.local_addr().unwrap();
+    let (mut remote, _) = target.accept().await.unwrap();
+    let mut expected = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+    expected.extend_from_slice(&[127, 0, 0, 1, 127, 0, 0, 1]);
+    expected.extend_from_slice(&client_addr.port().to_be_bytes());
+    expected.extend_from_slice(&target_addr.port().to_be_bytes());
+    assert_eq!(read_n(&mut remote, expected.len()).await, expected);
+    assert_relayed(&mut stream, &mut remote, b"ping").await;
+}
+
+#[tokio::test]
+async fn header_cannot_be_sent_through_a_parent_proxy() {
+    let config = Config::builder()
+        .local_addr("127.0.0.1")
+        .local_port(0)
+        .proxy_protocol(Some(ProxyProtocol::V1))
+        .upstream_proxy(Some("127.0.0.1:1080".parse().unwrap()))
+        .build();
+    let err = Server::new(config).bind().await.err().expect("bound despite the conflict");
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+fn trusting(cidr: &str) -> ConfigBuilder {
+    Config::builder().accept_proxy_protocol(true).proxy_protocol_trusted(AccessControl::new(Action::Deny).rule(Rule::cidr(cidr, Action::Allow).unwrap()))
+}
+
+fn trusting_loopback() -> ConfigBuilder {
+    trusting("127.0.0.1/32")
+}
+
+/// A v2 header claiming the connection comes from 203.0.113.7:40000.
+fn v2_header(proxy_addr: SocketAddr) -> Vec<u8> {
+    let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+    header.extend_from_slice(&[203, 0, 113, 7, 127, 0, 0, 1]);
+    header.extend_from_slice(&40000u16.to_be_bytes());
+    header.extend_from_slice(&proxy_addr.port().to_be_bytes());
+    header
+}
+
+/// Records the client address each CONNECT is attributed to.
+#[derive(Default)]
+struct Clients(Mutex<Vec<SocketAddr>>);
+
+impl EventHandler for Clients {
+    fn on_connect(&self, client: SocketAddr, _target: &Address) {
+        self.0.lock().unwrap().push(client);
+    }
+}
+
+#[tokio::test]
+async fn inbound_v2_header_names_the_client() {
+    let echo_addr = echo_server().await;
+    let clients = Arc::new(Clients::default());
+    let proxy_addr = spawn_proxy(trusting_loopback().event_handler(clients.clone())).await;
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    let mut header = v2_header(proxy_addr);
+    header.extend_from_slice(&[5, 1, 0]);
+    stream.write_all(&header).await.unwrap();
+    assert_eq!(read_n(&mut stream, 2).await, [5, 0]);
+    stream.write_all(&connect_request(echo_addr)).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 0);
+    assert_echo(&mut stream, b"ping").await;
+    assert_eq!(clients.0.lock().unwrap()[..], [SocketAddr::from(([203, 0, 113, 7], 40000))]);
+}
+
+#[tokio::test]
+async fn malformed_inbound_header_is_rejected() {
+    let proxy_addr = spawn_proxy(trusting_loopback()).await;
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream.write_all(b"PROXY TCP4 not-an-ip 127.0.0.1 1 2\r\n\x05\x01\x00").await.unwrap();
+    assert!(is_closed(&mut stream).await);
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream.write_all(&[5, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).await.unwrap();
+    assert!(is_closed(&mut stream).await);
+}
+
+#[tokio::test]
+async fn untrusted_headers_cannot_unlock_no_auth_sources() {
+    let no_auth_sources = AccessControl::new(Action::Deny).rule(Rule::cidr("203.0.113.0/24", Action::Allow).unwrap());
+    let auth = |config: ConfigBuilder| config.auth("user", "password").require_auth(true).no_auth_sources(no_auth_sources.clone());
+
+    let proxy_addr = spawn_proxy(auth(trusting("192.0.2.1/32"))).await;
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    let mut header = v2_header(proxy_addr);
+    header.extend_from_slice(&[5, 1, 0]);
+    stream.write_all(&header).await.unwrap();
+    let mut reply = Vec::new();
+    let _ = tokio::time::timeout(PROMPTLY, stream.read_to_end(&mut reply)).await.unwrap();
+    assert!(reply.is_empty());
+
+    // The same header from a trusted peer is believed.
+    let proxy_addr = spawn_proxy(auth(trusting_loopback())).await;
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    let mut header = v2_header(proxy_addr);
+    header.extend_from_slice(&[5, 1, 0]);
+    stream.write_all(&header).await.unwrap();
+    assert_eq!(read_n(&mut stream, 2).await, [5, 0]);
+}
+
+#[tokio::test]
+async fn untrusted_peers_are_served_directly() {
+    let echo_addr = echo_server().await;
+    let clients = Arc::new(Clients::default());
+    let proxy_addr = spawn_proxy(trusting("192.0.2.1/32").event_handler(clients.clone())).await;
+
+    let mut stream = connect(proxy_addr, echo_addr).await;
+    assert_echo(&mut stream, b"ping").await;
+    assert_eq!(clients.0.lock().unwrap()[..], [stream.local_addr().unwrap()]);
+}
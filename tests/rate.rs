@@ -0,0 +1,77 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use common::*;
+use socks_lib::Config;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+
+#[tokio::test]
+async fn per_connection_rate_limit_paces_the_transfer() {
+    let echo_addr = echo_server().await;
+    let proxy_addr = spawn_proxy(Config::builder().rate_limit(Some(100_000))).await;
+
+    let stream = connect(proxy_addr, echo_addr).await;
+    let (mut reader, mut writer) = stream.into_split();
+    let started = Instant::now();
+    // A full bucket lets the first second's worth through at once; the rest takes 1.5s.
+    let writing = tokio::spawn(async move { writer.write_all(&[0u8; 250_000]).await.unwrap() });
+    read_n(&mut reader, 250_000).await;
+    writing.await.unwrap();
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(1300), "finished in {:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(4), "finished in {:?}", elapsed);
+}
+
+/// A target that discards what it reads, reporting on `done` once `total` bytes arrived across
+/// all its connections.
+async fn counting_sink(total: usize) -> (SocketAddr, oneshot::Receiver<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let sink_addr = listener.local_addr().unwrap();
+    let (done_tx, done_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let received = Arc::new(AtomicUsize::new(0));
+        let done_tx = Arc::new(Mutex::new(Some(done_tx)));
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (received, done_tx) = (received.clone(), done_tx.clone());
+            tokio::spawn(async move {
+                let mut buffer = [0u8; 8192];
+                while let Ok(n @ 1..) = stream.read(&mut buffer).await {
+                    if received.fetch_add(n, Ordering::Relaxed) + n >= total {
+                        if let Some(done_tx) = done_tx.lock().unwrap().take() {
+                            let _ = done_tx.send(());
+                        }
+                    }
+                }
+            });
+        }
+    });
+    (sink_addr, done_rx)
+}
+
+#[tokio::test]
+async fn global_rate_limit_is_shared_by_all_connections() {
+    let (sink_addr, done) = counting_sink(200_000).await;
+    let proxy_addr = spawn_proxy(Config::builder().global_rate_limit(Some(100_000))).await;
+
+    let mut streams = Vec::new();
+    for _ in 0..2 {
+        streams.push(connect(proxy_addr, sink_addr).await);
+    }
+    let started = Instant::now();
+    for mut stream in streams {
+        tokio::spawn(async move {
+            stream.write_all(&[0u8; 100_000]).await.unwrap();
+            std::future::pending::<()>().await;
+        });
+    }
+    tokio::time::timeout(PROMPTLY, done).await.unwrap().unwrap();
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(800), "finished in {:?}", elapsed);
+}
@@ -0,0 +1,251 @@
+mod common;
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use common::*;
+use socks_lib::{Config, EventHandler, Metrics};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+#[tokio::test]
+async fn idle_timeout_closes_both_ends() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let proxy_addr = spawn_proxy(Config::builder().idle_timeout(Some(Duration::from_millis(300)))).await;
+
+    let mut stream = connect(proxy_addr, target_addr).await;
+    let (mut remote, _) = target.accept().await.unwrap();
+    // Activity keeps the relay open past the timeout.
+    for _ in 0..3 {
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_relayed(&mut stream, &mut remote, b"x").await;
+    }
+
+    let idle_since = Instant::now();
+    assert!(is_closed(&mut stream).await);
+    assert!(is_closed(&mut remote).await);
+    assert!(idle_since.elapsed() >= Duration::from_millis(250));
+}
+
+#[tokio::test]
+async fn relay_buffer_size_does_not_change_the_stream() {
+    let echo_addr = echo_server().await;
+    let payload: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    for relay_buffer_size in [16, 64 * 1024] {
+        let proxy_addr = spawn_proxy(Config::builder().relay_buffer_size(relay_buffer_size)).await;
+        let stream = connect(proxy_addr, echo_addr).await;
+        let (mut reader, mut writer) = stream.into_split();
+        let sent = payload.clone();
+        let writing = tokio::spawn(async move { writer.write_all(&sent).await.unwrap() });
+        assert_eq!(read_n(&mut reader, payload.len()).await, payload);
+        writing.await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn client_half_close_still_gets_the_response() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+
+    let mut stream = connect(proxy_addr, target_addr).await;
+    let (mut remote, _) = target.accept().await.unwrap();
+    assert_relayed(&mut stream, &mut remote, b"request").await;
+    stream.shutdown().await.unwrap();
+    assert!(is_closed(&mut remote).await);
+
+    assert_relayed(&mut remote, &mut stream, b"response").await;
+    drop(remote);
+    assert!(is_closed(&mut stream).await);
+}
+
+#[tokio::test]
+async fn large_response_survives_an_early_client_close() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+    let payload: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+
+    let mut stream = connect(proxy_addr, target_addr).await;
+    let (mut remote, _) = target.accept().await.unwrap();
+    stream.shutdown().await.unwrap();
+    assert!(is_closed(&mut remote).await);
+    let sent = payload.clone();
+    tokio::spawn(async move { remote.write_all(&sent).await.unwrap() });
+    assert_eq!(read_n(&mut stream, payload.len()).await, payload);
+    assert!(is_closed(&mut stream).await);
+}
+
+#[tokio::test]
+async fn reset_target_closes_the_client() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+
+    let mut stream = connect(proxy_addr, target_addr).await;
+    let (remote, _) = target.accept().await.unwrap();
+    remote.set_linger(Some(Duration::ZERO)).unwrap();
+    drop(remote);
+    assert!(is_closed(&mut stream).await);
+}
+
+#[tokio::test]
+async fn max_lifetime_counts_from_accept() {
+    let echo_addr = echo_server().await;
+    let proxy_addr = spawn_proxy(Config::builder().max_lifetime(Some(Duration::from_millis(600)))).await;
+
+    let accepted = Instant::now();
+    let mut stream = greet(proxy_addr).await;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    stream.write_all(&connect_request(echo_addr)).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 0);
+    // Keep the relay busy, so only the deadline can close it.
+    let echoing = async {
+        let mut byte = [0u8; 1];
+        while stream.write_all(b"x").await.is_ok() && matches!(stream.read(&mut byte).await, Ok(1)) {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    };
+    tokio::time::timeout(PROMPTLY, echoing).await.expect("relay outlived its deadline");
+    assert!(accepted.elapsed() >= Duration::from_millis(550));
+    assert!(accepted.elapsed() < Duration::from_millis(850));
+}
+
+#[tokio::test]
+async fn max_lifetime_closes_without_draining() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_addr = spawn_proxy(Config::builder().max_lifetime(Some(Duration::from_millis(300)))).await;
+
+    let accepted = Instant::now();
+    let stream = connect(proxy_addr, target.local_addr().unwrap()).await;
+    let (remote, _) = target.accept().await.unwrap();
+    // Both peers keep sending past the deadline; a proxy draining them would keep accepting it.
+    let sending = |mut peer: TcpStream| async move {
+        while peer.write_all(b"x").await.is_ok() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        accepted.elapsed()
+    };
+    let (client_closed, remote_closed) = tokio::time::timeout(PROMPTLY, async { tokio::join!(sending(stream), sending(remote)) }).await.expect("relay outlived its deadline");
+    assert!(client_closed < Duration::from_millis(600), "client closed after {:?}", client_closed);
+    assert!(remote_closed < Duration::from_millis(600), "target closed after {:?}", remote_closed);
+}
+
+/// Records the message of every connection error.
+#[derive(Default)]
+struct Errors(Mutex<Vec<String>>);
+
+impl EventHandler for Errors {
+    fn on_error(&self, _client: SocketAddr, err: &io::Error) {
+        self.0.lock().unwrap().push(err.to_string());
+    }
+}
+
+#[tokio::test]
+async fn io_timeout_aborts_a_write_the_target_never_reads() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let errors = Arc::new(Errors::default());
+    let config = Config::builder().io_timeout(Some(Duration::from_millis(300))).event_handler(errors.clone());
+    let proxy_addr = spawn_proxy(config).await;
+
+    let stream = connect(proxy_addr, target_addr).await;
+    let (mut remote, _) = target.accept().await.unwrap();
+    // Keep the downstream busy, so only the blocked upstream write can time out.
+    tokio::spawn(async move {
+        while remote.write_all(b"x").await.is_ok() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    });
+    let (mut reader, mut writer) = stream.into_split();
+    tokio::spawn(async move {
+        let chunk = vec![0u8; 64 * 1024];
+        while writer.write_all(&chunk).await.is_ok() {}
+    });
+
+    assert!(is_closed(&mut reader).await);
+    wait_until(|| !errors.0.lock().unwrap().is_empty()).await;
+    assert!(errors.0.lock().unwrap()[0].starts_with("relay write blocked"), "{:?}", errors.0.lock().unwrap());
+}
+
+/// Counts the chunks relayed client to remote, one per write, and their bytes.
+#[derive(Default)]
+struct UpstreamWrites {
+    writes: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+impl Metrics for UpstreamWrites {
+    fn bytes_up(&self, bytes: u64) {
+        self.writes.fetch_add(1, Ordering::SeqCst);
+        self.bytes.fetch_add(bytes as usize, Ordering::SeqCst);
+    }
+}
+
+/// Send ten small chunks 20ms apart and return how many writes relayed them.
+async fn upstream_writes_for_a_burst(config: socks_lib::ConfigBuilder) -> usize {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let writes = Arc::new(UpstreamWrites::default());
+    let proxy_addr = spawn_proxy(config.metrics(writes.clone())).await;
+
+    let mut stream = connect(proxy_addr, target.local_addr().unwrap()).await;
+    let (mut remote, _) = target.accept().await.unwrap();
+    // Keep Nagle from batching the chunks before they reach the proxy.
+    stream.set_nodelay(true).unwrap();
+    for chunk in 0..10u8 {
+        stream.write_all(&[chunk]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(read_n(&mut remote, 10).await, (0..10).collect::<Vec<u8>>());
+    wait_until(|| writes.bytes.load(Ordering::SeqCst) == 10).await;
+    writes.writes.load(Ordering::SeqCst)
+}
+
+#[tokio::test]
+async fn coalesce_window_batches_small_chunks() {
+    let writes = upstream_writes_for_a_burst(Config::builder()).await;
+    assert!(writes >= 5, "{} writes without a window", writes);
+    let writes = upstream_writes_for_a_burst(Config::builder().coalesce_window(Some(Duration::from_millis(500)))).await;
+    assert!(writes <= 2, "{} writes with a window", writes);
+}
+
+#[tokio::test]
+async fn closing_with_unread_client_data_sends_fin_not_rst() {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_addr = spawn_proxy(Config::builder().idle_timeout(Some(Duration::from_millis(100)))).await;
+
+    let mut stream = connect(proxy_addr, target.local_addr().unwrap()).await;
+    let (mut remote, _) = target.accept().await.unwrap();
+    // More than the sockets can buffer, so the proxy still has some queued for the client at the end.
+    tokio::spawn(async move {
+        let _ = remote.write_all(&vec![b'd'; 8 * 1024 * 1024]).await;
+        remote
+    });
+    // The target never reads either, leaving the proxy holding unread client data.
+    let chunk = vec![0u8; 64 * 1024];
+    let deadline = Instant::now() + Duration::from_millis(200);
+    while Instant::now() < deadline {
+        if stream.try_write(&chunk).is_err() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    // Only read once the relay has gone idle and closed; a reset would discard what the proxy had
+    // queued and end the stream with an error instead of EOF.
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut received = 0;
+    let end = loop {
+        match tokio::time::timeout(PROMPTLY, stream.read(&mut buffer)).await.expect("connection left open") {
+            Ok(0) => break Ok(()),
+            Ok(read_len) => received += read_len,
+            Err(err) => break Err(err),
+        }
+    };
+    assert!(received > 0);
+    end.unwrap();
+}
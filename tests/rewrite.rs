@@ -0,0 +1,35 @@
+mod common;
+
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+
+use common::*;
+use socks_lib::{Address, Config, Target};
+use tokio::io::AsyncWriteExt;
+
+#[tokio::test]
+async fn rewriter_redirects_a_domain() {
+    let echo_addr = echo_server().await;
+    let rewriter = move |target: &mut Address| {
+        if matches!(target.target(), Target::Domain(domain) if domain == "example.com") {
+            *target = Address::from(echo_addr);
+        }
+        Ok(())
+    };
+    let proxy_addr = spawn_proxy(Config::builder().rewriter(Arc::new(rewriter))).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&domain_request(1, b"example.com", 80)).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 0);
+    assert_echo(&mut stream, b"ping").await;
+}
+
+#[tokio::test]
+async fn rewriter_error_rejects_the_request() {
+    let rewriter = |_: &mut Address| Err(Error::new(ErrorKind::PermissionDenied, "blocked"));
+    let proxy_addr = spawn_proxy(Config::builder().rewriter(Arc::new(rewriter))).await;
+
+    let mut stream = greet(proxy_addr).await;
+    stream.write_all(&domain_request(1, b"example.com", 80)).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 2);
+}
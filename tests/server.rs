@@ -0,0 +1,254 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use common::*;
+use socks_lib::{Address, Config, EventHandler, Metrics, Server, ShutdownStats};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::oneshot;
+
+fn loopback() -> socks_lib::ConfigBuilder {
+    Config::builder().local_addr("127.0.0.1").local_port(0)
+}
+
+#[tokio::test]
+async fn shutdown_stops_the_server() {
+    let echo_addr = echo_server().await;
+    let server = Server::new(loopback().build()).bind().await.unwrap();
+    let proxy_addr = server.local_addr().unwrap();
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    let running = tokio::spawn(server.run_with_shutdown(async { let _ = shutdown_rx.await; }, None));
+
+    let mut stream = connect(proxy_addr, echo_addr).await;
+    assert_echo(&mut stream, b"ping").await;
+
+    shutdown_tx.send(()).unwrap();
+    let stats = tokio::time::timeout(PROMPTLY, running).await.expect("server kept running").unwrap().unwrap();
+    assert_eq!(stats, ShutdownStats { served: 0, aborted: 1 });
+    assert!(is_closed(&mut stream).await);
+    assert!(tokio::net::TcpStream::connect(proxy_addr).await.is_err());
+}
+
+#[tokio::test]
+async fn shutdown_drains_open_connections() {
+    let echo_addr = echo_server().await;
+    let server = Server::new(loopback().build()).bind().await.unwrap();
+    let proxy_addr = server.local_addr().unwrap();
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    let running = tokio::spawn(server.run_with_shutdown(async { let _ = shutdown_rx.await; }, Some(Duration::from_secs(5))));
+
+    let stream = connect(proxy_addr, echo_addr).await;
+    shutdown_tx.send(()).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    drop(stream);
+    let stats = tokio::time::timeout(PROMPTLY, running).await.expect("server kept running").unwrap().unwrap();
+    assert_eq!(stats, ShutdownStats { served: 1, aborted: 0 });
+}
+
+#[tokio::test]
+async fn binding_port_zero_reports_the_assigned_port() {
+    let echo_addr = echo_server().await;
+    let server = Server::new(loopback().build()).bind().await.unwrap();
+    let proxy_addr = server.local_addr().unwrap();
+    assert_ne!(proxy_addr.port(), 0);
+    tokio::spawn(server.run());
+
+    let mut stream = connect(proxy_addr, echo_addr).await;
+    assert_echo(&mut stream, b"ping").await;
+}
+
+#[tokio::test]
+async fn every_listen_address_accepts() {
+    let echo_addr = echo_server().await;
+    let listen_addrs = ["127.0.0.1:0".parse().unwrap(), "[::1]:0".parse().unwrap()];
+    let server = Server::new(loopback().listen_addrs(listen_addrs).build()).bind().await.unwrap();
+    let proxy_addrs = server.local_addrs().unwrap();
+    assert_eq!(proxy_addrs.len(), 2);
+    tokio::spawn(server.run());
+
+    for proxy_addr in proxy_addrs {
+        let mut stream = connect(proxy_addr, echo_addr).await;
+        assert_echo(&mut stream, b"ping").await;
+    }
+}
+
+#[tokio::test]
+async fn stats_count_open_connections() {
+    let echo_addr = echo_server().await;
+    let server = Server::new(loopback().build());
+    let bound = server.bind().await.unwrap();
+    let proxy_addr = bound.local_addr().unwrap();
+    tokio::spawn(bound.run());
+
+    let mut streams = Vec::new();
+    for _ in 0..3 {
+        streams.push(connect(proxy_addr, echo_addr).await);
+    }
+    wait_until(|| server.stats().active_connections == 3).await;
+    assert_eq!(server.stats().total_connections, 3);
+
+    streams.truncate(1);
+    wait_until(|| server.stats().active_connections == 1).await;
+    assert_eq!(server.stats().total_connections, 3);
+}
+
+/// Panics as soon as any bytes are relayed, counting the connections it saw closed.
+#[derive(Default)]
+struct PanickingMetrics {
+    closed: AtomicUsize,
+}
+
+impl Metrics for PanickingMetrics {
+    fn connection_closed(&self) {
+        self.closed.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn bytes_up(&self, _bytes: u64) {
+        panic!("metrics exploded")
+    }
+}
+
+#[tokio::test]
+async fn panic_mid_relay_releases_the_connection() {
+    let echo_addr = echo_server().await;
+    let metrics = Arc::new(PanickingMetrics::default());
+    let server = Server::new(loopback().metrics(metrics.clone()).build());
+    let bound = server.bind().await.unwrap();
+    let proxy_addr = bound.local_addr().unwrap();
+    tokio::spawn(bound.run());
+
+    let mut stream = connect(proxy_addr, echo_addr).await;
+    assert_eq!(server.stats().active_connections, 1);
+    stream.write_all(b"ping").await.unwrap();
+    assert!(is_closed(&mut stream).await);
+    wait_until(|| server.stats().active_connections == 0).await;
+    assert_eq!(metrics.closed.load(Ordering::SeqCst), 1);
+
+    // The server keeps accepting after the panic.
+    connect(proxy_addr, echo_addr).await;
+}
+
+/// Records the name of the thread each connection is served on.
+#[derive(Default)]
+struct ThreadNames(Mutex<Vec<Option<String>>>);
+
+impl EventHandler for ThreadNames {
+    fn on_connect(&self, _client: SocketAddr, _target: &Address) {
+        self.0.lock().unwrap().push(std::thread::current().name().map(str::to_string));
+    }
+}
+
+#[tokio::test]
+async fn connections_run_on_the_given_runtime() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .thread_name("proxy-worker")
+        .enable_all()
+        .build()
+        .unwrap();
+    let echo_addr = echo_server().await;
+    let thread_names = Arc::new(ThreadNames::default());
+    let server = Server::new(loopback().event_handler(thread_names.clone()).build()).bind().await.unwrap();
+    let proxy_addr = server.local_addr().unwrap();
+    tokio::spawn(server.runtime(runtime.handle().clone()).run());
+
+    let mut stream = connect(proxy_addr, echo_addr).await;
+    assert_echo(&mut stream, b"ping").await;
+    assert_eq!(*thread_names.0.lock().unwrap(), [Some("proxy-worker".to_string())]);
+    runtime.shutdown_background();
+}
+
+#[tokio::test]
+async fn serves_on_a_pre_bound_listener() {
+    let echo_addr = echo_server().await;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_addr = listener.local_addr().unwrap();
+    // The configured address is ignored in favor of the listener.
+    let server = Server::new(Config::builder().local_addr("192.0.2.1").local_port(1).build());
+    tokio::spawn(server.bind_listener(listener).unwrap().run());
+
+    let mut stream = connect(proxy_addr, echo_addr).await;
+    assert_echo(&mut stream, b"ping").await;
+}
+
+#[tokio::test]
+async fn serve_runs_with_the_defaults() {
+    let echo_addr = echo_server().await;
+    let proxy_addr = SocketAddr::from(([127, 0, 0, 1], closed_port()));
+    tokio::spawn(socks_lib::serve(proxy_addr));
+
+    wait_until(|| std::net::TcpStream::connect(proxy_addr).is_ok()).await;
+    let mut stream = connect(proxy_addr, echo_addr).await;
+    assert_echo(&mut stream, b"ping").await;
+}
+
+#[tokio::test]
+async fn serve_reports_bind_failures() {
+    let taken = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let err = socks_lib::serve(taken.local_addr().unwrap()).await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn sequential_server_serves_one_client_at_a_time() {
+    let echo_addr = echo_server().await;
+    let server = Server::new(loopback().build()).bind().await.unwrap();
+    let proxy_addr = server.local_addr().unwrap();
+    tokio::spawn(server.run_sequential());
+
+    let mut first = connect(proxy_addr, echo_addr).await;
+    assert_echo(&mut first, b"ping").await;
+    let mut second = tokio::net::TcpStream::connect(proxy_addr).await.unwrap();
+    second.write_all(&[5, 1, 0]).await.unwrap();
+    assert!(tokio::time::timeout(Duration::from_millis(200), read_n(&mut second, 2)).await.is_err());
+
+    drop(first);
+    assert_eq!(read_n(&mut second, 2).await, [5, 0]);
+    second.write_all(&connect_request(echo_addr)).await.unwrap();
+    assert_eq!(read_reply(&mut second).await.0, 0);
+    assert_echo(&mut second, b"pong").await;
+}
+
+#[tokio::test]
+async fn killed_connections_close_both_ends() {
+    let target = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = Server::new(loopback().build());
+    let bound = server.bind().await.unwrap();
+    let proxy_addr = bound.local_addr().unwrap();
+    tokio::spawn(bound.run());
+
+    let mut stream = connect(proxy_addr, target.local_addr().unwrap()).await;
+    let (mut remote, _) = target.accept().await.unwrap();
+    let connections = server.connections();
+    assert_eq!(connections.len(), 1);
+    assert_eq!(connections[0].client_addr, stream.local_addr().unwrap());
+
+    assert!(server.kill(connections[0].id));
+    assert!(is_closed(&mut stream).await);
+    assert!(is_closed(&mut remote).await);
+    wait_until(|| server.connections().is_empty()).await;
+    assert!(!server.kill(connections[0].id));
+}
+
+#[tokio::test]
+async fn ipv6_only_controls_ipv4_mapped_clients() {
+    let echo_addr = echo_server().await;
+    let dual_stack = Server::new(Config::builder().local_addr("::").local_port(0).ipv6_only(Some(false)).build()).bind().await.unwrap();
+    let port = dual_stack.local_addr().unwrap().port();
+    tokio::spawn(dual_stack.run());
+    let mut stream = connect(SocketAddr::from(([127, 0, 0, 1], port)), echo_addr).await;
+    assert_echo(&mut stream, b"ping").await;
+
+    let v6_only = Server::new(Config::builder().local_addr("::").local_port(0).ipv6_only(Some(true)).build()).bind().await.unwrap();
+    let port = v6_only.local_addr().unwrap().port();
+    tokio::spawn(v6_only.run());
+    let refused = tokio::net::TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], port))).await.unwrap_err();
+    assert_eq!(refused.kind(), std::io::ErrorKind::ConnectionRefused);
+    let mut stream = connect(SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, port)), echo_addr).await;
+    assert_echo(&mut stream, b"pong").await;
+    // The IPv4 side of the port stays free for a separate listener.
+    std::net::TcpListener::bind(("0.0.0.0", port)).unwrap();
+}
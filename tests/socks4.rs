@@ -0,0 +1,55 @@
+mod common;
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use common::*;
+use socks_lib::Config;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+fn socks4_request(port: u16, ip: [u8; 4], domain: Option<&str>) -> Vec<u8> {
+    let mut request = vec![4, 1];
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&ip);
+    request.extend_from_slice(b"user\0");
+    if let Some(domain) = domain {
+        request.extend_from_slice(domain.as_bytes());
+        request.push(0);
+    }
+    request
+}
+
+#[tokio::test]
+async fn socks4_connect() {
+    let echo_addr = echo_server().await;
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream.write_all(&socks4_request(echo_addr.port(), [127, 0, 0, 1], None)).await.unwrap();
+    assert_eq!(read_n(&mut stream, 8).await[..2], [0, 90]);
+    assert_echo(&mut stream, b"ping").await;
+}
+
+#[tokio::test]
+async fn socks4a_connect_to_domain() {
+    let echo_addr = echo_server().await;
+    let resolver = FixedResolver::new([IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+    let proxy_addr = spawn_proxy(Config::builder().resolver(resolver.clone())).await;
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream.write_all(&socks4_request(echo_addr.port(), [0, 0, 0, 1], Some("echo.test"))).await.unwrap();
+    assert_eq!(read_n(&mut stream, 8).await[..2], [0, 90]);
+    assert_echo(&mut stream, b"ping").await;
+    assert_eq!(resolver.queries(), ["echo.test"]);
+}
+
+#[tokio::test]
+async fn socks4_failure_is_rejected() {
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+    let dead_addr = SocketAddr::from(([127, 0, 0, 1], closed_port()));
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream.write_all(&socks4_request(dead_addr.port(), [127, 0, 0, 1], None)).await.unwrap();
+    assert_eq!(read_n(&mut stream, 8).await[..2], [0, 91]);
+    assert!(is_closed(&mut stream).await);
+}
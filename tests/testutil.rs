@@ -0,0 +1,34 @@
+#![cfg(feature = "testutil")]
+
+mod common;
+
+use common::*;
+use socks_lib::{testutil, Address, Config};
+
+#[tokio::test]
+async fn helpers_negotiate_a_connect() {
+    let echo_addr = echo_server().await;
+    let proxy_addr = testutil::spawn_server(Config::builder()).await.unwrap();
+
+    let mut stream = testutil::connect(proxy_addr, &Address::from(echo_addr)).await.unwrap();
+    assert_echo(&mut stream, b"ping").await;
+}
+
+#[tokio::test]
+async fn helpers_authenticate() {
+    let echo_addr = echo_server().await;
+    let proxy_addr = testutil::spawn_server(Config::builder().auth("user", "password").require_auth(true)).await.unwrap();
+
+    let mut stream = testutil::connect_with_auth(proxy_addr, &Address::from(echo_addr), "user", "password").await.unwrap();
+    assert_echo(&mut stream, b"ping").await;
+    assert!(testutil::connect_with_auth(proxy_addr, &Address::from(echo_addr), "user", "wrong").await.is_err());
+    assert!(testutil::connect(proxy_addr, &Address::from(echo_addr)).await.is_err());
+}
+
+#[tokio::test]
+async fn helpers_surface_connect_failures() {
+    let proxy_addr = testutil::spawn_server(Config::builder()).await.unwrap();
+
+    let refused = Address::from(std::net::SocketAddr::from(([127, 0, 0, 1], closed_port())));
+    assert!(testutil::connect(proxy_addr, &refused).await.is_err());
+}
@@ -0,0 +1,53 @@
+#![cfg(feature = "tls")]
+
+mod common;
+
+use std::sync::Arc;
+
+use common::*;
+use socks_lib::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+use socks_lib::rustls::{self, ClientConfig, RootCertStore};
+use socks_lib::Config;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+fn self_signed() -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let cert = CertificateDer::from(certified.cert.der().to_vec());
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+    (cert, key)
+}
+
+#[tokio::test]
+async fn socks_over_tls_with_a_self_signed_certificate() {
+    let (cert, key) = self_signed();
+    let echo_addr = echo_server().await;
+    let proxy_addr = spawn_proxy(Config::builder().tls_identity(vec![cert.clone()], key)).await;
+
+    let mut roots = RootCertStore::empty();
+    roots.add(cert).unwrap();
+    let client_config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let tcp_stream = TcpStream::connect(proxy_addr).await.unwrap();
+    let mut stream = TlsConnector::from(Arc::new(client_config)).connect(ServerName::try_from("localhost").unwrap(), tcp_stream).await.unwrap();
+
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    assert_eq!(read_n(&mut stream, 2).await, [5, 0]);
+    stream.write_all(&connect_request(echo_addr)).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 0);
+    assert_echo(&mut stream, b"ping").await;
+}
+
+#[tokio::test]
+async fn plaintext_clients_are_refused() {
+    let (cert, key) = self_signed();
+    let proxy_addr = spawn_proxy(Config::builder().tls_identity(vec![cert], key)).await;
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    assert!(is_closed(&mut stream).await);
+}
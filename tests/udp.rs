@@ -0,0 +1,216 @@
+#![cfg(feature = "udp")]
+
+mod common;
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use common::*;
+use socks_lib::Config;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket};
+
+/// Open an association from a control connection to `proxy_addr`, returning it with the relay
+/// address the proxy reported.
+async fn associate(proxy_addr: SocketAddr, client_udp_addr: SocketAddr) -> (TcpStream, SocketAddr) {
+    let mut control = greet(proxy_addr).await;
+    control.write_all(&request(3, client_udp_addr)).await.unwrap();
+    let (rep, relay_addr) = read_reply(&mut control).await;
+    assert_eq!(rep, 0);
+    (control, relay_addr)
+}
+
+/// Send `payload` to `target` through the relay and return the datagram that comes back.
+async fn round_trip(socket: &UdpSocket, relay_addr: SocketAddr, target: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    socket.send_to(&udp_datagram(0, target, payload), relay_addr).await.unwrap();
+    let mut buffer = vec![0u8; 2048];
+    let (n, src) = tokio::time::timeout(PROMPTLY, socket.recv_from(&mut buffer)).await.expect("no datagram relayed").unwrap();
+    assert_eq!(src, relay_addr);
+    buffer.truncate(n);
+    buffer
+}
+
+#[tokio::test]
+async fn udp_associate_relays_to_target() {
+    let echo_addr = udp_echo_server().await;
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    let (_control, relay_addr) = associate(proxy_addr, socket.local_addr().unwrap()).await;
+    let datagram = round_trip(&socket, relay_addr, echo_addr, b"ping").await;
+    assert_eq!(datagram, udp_datagram(0, echo_addr, b"ping"));
+}
+
+#[tokio::test]
+async fn domain_targets_are_resolved_once_per_association() {
+    let echo_addr = udp_echo_server().await;
+    let resolver = FixedResolver::new([IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+    let proxy_addr = spawn_proxy(Config::builder().resolver(resolver.clone())).await;
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    let (_control, relay_addr) = associate(proxy_addr, socket.local_addr().unwrap()).await;
+    let mut datagram = vec![0, 0, 0, 3, 11];
+    datagram.extend_from_slice(b"example.org");
+    datagram.extend_from_slice(&echo_addr.port().to_be_bytes());
+    datagram.extend_from_slice(b"ping");
+    let mut buffer = vec![0u8; 2048];
+    for _ in 0..3 {
+        socket.send_to(&datagram, relay_addr).await.unwrap();
+        tokio::time::timeout(PROMPTLY, socket.recv_from(&mut buffer)).await.expect("no datagram relayed").unwrap();
+    }
+    assert_eq!(resolver.queries(), ["example.org"]);
+}
+
+#[tokio::test]
+async fn ipv6_association_reaches_ipv4_target() {
+    let echo_addr = udp_echo_server().await;
+    let proxy_addr = spawn_proxy_on("::1", Config::builder()).await;
+    let socket = UdpSocket::bind("[::1]:0").await.unwrap();
+
+    let (_control, relay_addr) = associate(proxy_addr, socket.local_addr().unwrap()).await;
+    assert_eq!(relay_addr.ip(), proxy_addr.ip());
+    let datagram = round_trip(&socket, relay_addr, echo_addr, b"ping").await;
+    assert_eq!(datagram, udp_datagram(0, echo_addr, b"ping"));
+}
+
+#[tokio::test]
+async fn relay_binds_the_server_interface_by_default() {
+    let proxy_addr = spawn_proxy_on("127.0.0.2", Config::builder()).await;
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    let (_control, relay_addr) = associate(proxy_addr, socket.local_addr().unwrap()).await;
+    assert_eq!(relay_addr.ip(), proxy_addr.ip());
+}
+
+#[tokio::test]
+async fn relay_binds_the_configured_address() {
+    let echo_addr = udp_echo_server().await;
+    let udp_bind_addr = IpAddr::from([127, 0, 0, 3]);
+    let proxy_addr = spawn_proxy(Config::builder().udp_bind_addr(Some(udp_bind_addr))).await;
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    let (_control, relay_addr) = associate(proxy_addr, socket.local_addr().unwrap()).await;
+    assert_eq!(relay_addr.ip(), udp_bind_addr);
+    let datagram = round_trip(&socket, relay_addr, echo_addr, b"ping").await;
+    assert_eq!(datagram, udp_datagram(0, echo_addr, b"ping"));
+}
+
+#[tokio::test]
+async fn fragments_are_dropped() {
+    let echo_addr = udp_echo_server().await;
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    let (_control, relay_addr) = associate(proxy_addr, socket.local_addr().unwrap()).await;
+    socket.send_to(&udp_datagram(1, echo_addr, b"fragment"), relay_addr).await.unwrap();
+    let datagram = round_trip(&socket, relay_addr, echo_addr, b"whole").await;
+    assert_eq!(datagram, udp_datagram(0, echo_addr, b"whole"));
+}
+
+#[tokio::test]
+async fn datagrams_from_other_sources_are_ignored() {
+    let echo_addr = udp_echo_server().await;
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let intruder = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    let (_control, relay_addr) = associate(proxy_addr, socket.local_addr().unwrap()).await;
+    intruder.send_to(&udp_datagram(0, echo_addr, b"spoofed"), relay_addr).await.unwrap();
+    let datagram = round_trip(&socket, relay_addr, echo_addr, b"ping").await;
+    assert_eq!(datagram, udp_datagram(0, echo_addr, b"ping"));
+    intruder.send_to(&udp_datagram(0, echo_addr, b"spoofed"), relay_addr).await.unwrap();
+
+    let mut buffer = [0u8; 2048];
+    let received = tokio::time::timeout(Duration::from_millis(300), intruder.recv_from(&mut buffer)).await;
+    assert!(received.is_err(), "relay answered an unexpected source");
+}
+
+#[tokio::test]
+async fn reported_endpoint_is_the_bound_relay() {
+    let echo_addr = udp_echo_server().await;
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    let (_control, relay_addr) = associate(proxy_addr, socket.local_addr().unwrap()).await;
+    assert_eq!(relay_addr.ip(), proxy_addr.ip());
+    assert_ne!(relay_addr.port(), 0);
+    assert_ne!(relay_addr.port(), proxy_addr.port());
+    let datagram = round_trip(&socket, relay_addr, echo_addr, b"ping").await;
+    assert_eq!(datagram, udp_datagram(0, echo_addr, b"ping"));
+}
+
+#[tokio::test]
+async fn declared_client_port_is_enforced() {
+    let echo_addr = udp_echo_server().await;
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let other_port = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    let (_control, relay_addr) = associate(proxy_addr, socket.local_addr().unwrap()).await;
+    other_port.send_to(&udp_datagram(0, echo_addr, b"wrong port"), relay_addr).await.unwrap();
+    let mut buffer = [0u8; 2048];
+    let received = tokio::time::timeout(Duration::from_millis(300), other_port.recv_from(&mut buffer)).await;
+    assert!(received.is_err(), "relay served an undeclared port");
+    let datagram = round_trip(&socket, relay_addr, echo_addr, b"ping").await;
+    assert_eq!(datagram, udp_datagram(0, echo_addr, b"ping"));
+}
+
+#[tokio::test]
+async fn declared_ip_without_a_port_accepts_any_port() {
+    let echo_addr = udp_echo_server().await;
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    let (_control, relay_addr) = associate(proxy_addr, SocketAddr::from(([127, 0, 0, 1], 0))).await;
+    let datagram = round_trip(&socket, relay_addr, echo_addr, b"ping").await;
+    assert_eq!(datagram, udp_datagram(0, echo_addr, b"ping"));
+}
+
+#[tokio::test]
+async fn closing_the_control_connection_ends_the_association() {
+    let echo_addr = udp_echo_server().await;
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    let (control, relay_addr) = associate(proxy_addr, socket.local_addr().unwrap()).await;
+    round_trip(&socket, relay_addr, echo_addr, b"ping").await;
+    drop(control);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    socket.send_to(&udp_datagram(0, echo_addr, b"late"), relay_addr).await.unwrap();
+    let mut buffer = [0u8; 2048];
+    let received = tokio::time::timeout(Duration::from_millis(300), socket.recv_from(&mut buffer)).await;
+    assert!(!matches!(received, Ok(Ok(_))), "relay outlived its control connection");
+    // The relay port was released with the association.
+    UdpSocket::bind(relay_addr).await.unwrap();
+}
+
+#[tokio::test]
+async fn zeroed_association_learns_the_client_from_its_first_datagram() {
+    let echo_addr = udp_echo_server().await;
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let latecomer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    let (_control, relay_addr) = associate(proxy_addr, SocketAddr::from(([0, 0, 0, 0], 0))).await;
+    let datagram = round_trip(&socket, relay_addr, echo_addr, b"first").await;
+    assert_eq!(datagram, udp_datagram(0, echo_addr, b"first"));
+
+    latecomer.send_to(&udp_datagram(0, echo_addr, b"second"), relay_addr).await.unwrap();
+    let mut buffer = [0u8; 2048];
+    let received = tokio::time::timeout(Duration::from_millis(300), latecomer.recv_from(&mut buffer)).await;
+    assert!(received.is_err(), "relay served a second client");
+    let datagram = round_trip(&socket, relay_addr, echo_addr, b"again").await;
+    assert_eq!(datagram, udp_datagram(0, echo_addr, b"again"));
+}
+
+#[tokio::test]
+async fn zeroed_ipv6_association_is_accepted() {
+    let echo_addr = udp_echo_server().await;
+    let proxy_addr = spawn_proxy(Config::builder()).await;
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    let (_control, relay_addr) = associate(proxy_addr, SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, 0))).await;
+    let datagram = round_trip(&socket, relay_addr, echo_addr, b"ping").await;
+    assert_eq!(datagram, udp_datagram(0, echo_addr, b"ping"));
+}
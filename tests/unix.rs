@@ -0,0 +1,73 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use common::*;
+use socks_lib::{Config, Server};
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixStream;
+
+/// A socket path unique to this test, removed if a previous run left it behind.
+fn socket_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("socks-lib-{}-{}.sock", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+#[tokio::test]
+async fn unix_clients_get_the_same_handshake_and_relay() {
+    let echo_addr = echo_server().await;
+    let path = socket_path("relay");
+    let server = Server::new(Config::builder().build()).bind_unix(&path).unwrap();
+    tokio::spawn(server.run());
+
+    let mut stream = UnixStream::connect(&path).await.unwrap();
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    assert_eq!(read_n(&mut stream, 2).await, [5, 0]);
+    stream.write_all(&connect_request(echo_addr)).await.unwrap();
+    assert_eq!(read_reply(&mut stream).await.0, 0);
+    assert_echo(&mut stream, b"ping").await;
+    std::fs::remove_file(&path).unwrap();
+}
+
+async fn unix_request(path: &Path, request: &[u8]) -> u8 {
+    let mut stream = UnixStream::connect(path).await.unwrap();
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    assert_eq!(read_n(&mut stream, 2).await, [5, 0]);
+    stream.write_all(request).await.unwrap();
+    let rep = read_reply(&mut stream).await.0;
+    assert!(is_closed(&mut stream).await);
+    rep
+}
+
+#[tokio::test]
+async fn bind_is_not_supported_on_unix_sockets() {
+    let path = socket_path("bind");
+    let server = Server::new(Config::builder().build()).bind_unix(&path).unwrap();
+    tokio::spawn(server.run());
+
+    assert_eq!(unix_request(&path, &request(2, "0.0.0.0:0".parse().unwrap())).await, 7);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(feature = "udp")]
+#[tokio::test]
+async fn udp_associate_is_not_supported_on_unix_sockets() {
+    let path = socket_path("associate");
+    let server = Server::new(Config::builder().build()).bind_unix(&path).unwrap();
+    tokio::spawn(server.run());
+
+    assert_eq!(unix_request(&path, &request(3, "0.0.0.0:0".parse().unwrap())).await, 7);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn existing_socket_paths_are_not_replaced() {
+    let path = socket_path("taken");
+    std::fs::write(&path, b"").unwrap();
+    let err = Server::new(Config::builder().build()).bind_unix(&path).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+    std::fs::remove_file(&path).unwrap();
+}